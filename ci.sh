@@ -24,7 +24,22 @@ run pre-commit run --all-files
 
 # run the tests
 run cargo test --verbose
+run cargo test --verbose -p nixpkgs-fmt-ffi
 
-# generate the webassembly page
+# check that the C header is up to date
+run cbindgen --config ffi/cbindgen.toml --output ffi/include/nixpkgs_fmt.h ffi
+run git diff --exit-code ffi/include/nixpkgs_fmt.h
+
+# test the Python bindings
+run cargo test --verbose -p nixpkgs-fmt-python
+run python3 -m venv "${TMPDIR}/venv"
+run "${TMPDIR}/venv/bin/pip" wheel ./python -w "${TMPDIR}/dist"
+run "${TMPDIR}/venv/bin/pip" install "${TMPDIR}"/dist/nixpkgs_fmt-*.whl
+run "${TMPDIR}/venv/bin/python" -m unittest discover -s python/tests
+
+# generate the webassembly page and the npm package
 run ./wasm/build.sh
 
+# test the npm package
+run node --test wasm/npm/test
+
@@ -60,8 +60,11 @@ rec {
       nixpkgs.gitAndTools.git-extras
       nixpkgs.gitAndTools.pre-commit
       nixpkgs.mdsh
+      nixpkgs.nodejs
       nixpkgs.openssl
       nixpkgs.pkgconfig
+      nixpkgs.python3
+      nixpkgs.rust-cbindgen
       nixpkgs.stdenv.cc
       nixpkgs.wasm-pack
       rustToolchain
@@ -30,7 +30,7 @@ fi
 
 run git clone --depth=1 --branch=gh-pages "$origin_url" "$workdir/repo"
 
-run rsync -rl --exclude .git --delete wasm/ "$workdir/repo"
+run rsync -rl --exclude .git --exclude npm --delete wasm/ "$workdir/repo"
 
 run rm -f "$workdir/repo/pkg/.gitignore"
 
@@ -0,0 +1,69 @@
+#ifndef NIXPKGS_FMT_H
+#define NIXPKGS_FMT_H
+
+/* Generated with cbindgen from src/lib.rs, do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// A replacement of the bytes `[start; end)` of the input with `insert`. All
+// the edits refer to the input, so that they can be applied at once.
+typedef struct NixpkgsFmtEdit {
+  uintptr_t start;
+  uintptr_t end;
+  // A NUL-terminated UTF-8 string of `insert_len` bytes.
+  const char *insert;
+  uintptr_t insert_len;
+} NixpkgsFmtEdit;
+
+// The result of `nixpkgs_fmt_reformat`, `nixpkgs_fmt_explain` or
+// `nixpkgs_fmt_edits`.
+typedef struct NixpkgsFmtResult {
+  // The formatted or explained text, as a NUL-terminated UTF-8 string of
+  // `output_len` bytes. Null for `nixpkgs_fmt_edits` and on errors.
+  const char *output;
+  uintptr_t output_len;
+  // The edits which format the text, sorted by their ranges. Null,
+  // unless returned by `nixpkgs_fmt_edits` without errors.
+  const struct NixpkgsFmtEdit *edits;
+  uintptr_t edits_len;
+  // The error message, as a NUL-terminated UTF-8 string, or null if
+  // formatting succeeded.
+  const char *error;
+} NixpkgsFmtResult;
+
+// Formats the UTF-8 `text` of `len` bytes.
+//
+// # Safety
+//
+// `text` must point to `len` readable bytes, and the result must be
+// released with `nixpkgs_fmt_free`.
+struct NixpkgsFmtResult *nixpkgs_fmt_reformat(const uint8_t *text, uintptr_t len);
+
+// Annotates each line of the UTF-8 `text` of `len` bytes with the rules
+// which would change it, like `nixpkgs-fmt --explain`.
+//
+// # Safety
+//
+// `text` must point to `len` readable bytes, and the result must be
+// released with `nixpkgs_fmt_free`.
+struct NixpkgsFmtResult *nixpkgs_fmt_explain(const uint8_t *text, uintptr_t len);
+
+// Computes the edits which format the UTF-8 `text` of `len` bytes, so that
+// only the changed parts of a buffer need to be replaced.
+//
+// # Safety
+//
+// `text` must point to `len` readable bytes, and the result must be
+// released with `nixpkgs_fmt_free`.
+struct NixpkgsFmtResult *nixpkgs_fmt_edits(const uint8_t *text, uintptr_t len);
+
+// Releases a result returned by this library. Does nothing if `result` is
+// null.
+//
+// # Safety
+//
+// `result` must be null or returned by this library, and not released yet.
+void nixpkgs_fmt_free(struct NixpkgsFmtResult *result);
+
+#endif  /* NIXPKGS_FMT_H */
@@ -0,0 +1,250 @@
+//! C bindings to nixpkgs-fmt, see `include/nixpkgs_fmt.h`.
+//!
+//! All the functions return a `NixpkgsFmtResult`, which is owned by the
+//! library and must be released with `nixpkgs_fmt_free`.
+use std::{
+    os::raw::c_char,
+    panic::{self, AssertUnwindSafe},
+    ptr, slice, str,
+};
+
+/// The result of `nixpkgs_fmt_reformat`, `nixpkgs_fmt_explain` or
+/// `nixpkgs_fmt_edits`.
+#[repr(C)]
+pub struct NixpkgsFmtResult {
+    /// The formatted or explained text, as a NUL-terminated UTF-8 string of
+    /// `output_len` bytes. Null for `nixpkgs_fmt_edits` and on errors.
+    pub output: *const c_char,
+    pub output_len: usize,
+    /// The edits which format the text, sorted by their ranges. Null,
+    /// unless returned by `nixpkgs_fmt_edits` without errors.
+    pub edits: *const NixpkgsFmtEdit,
+    pub edits_len: usize,
+    /// The error message, as a NUL-terminated UTF-8 string, or null if
+    /// formatting succeeded.
+    pub error: *const c_char,
+}
+
+/// A replacement of the bytes `[start; end)` of the input with `insert`. All
+/// the edits refer to the input, so that they can be applied at once.
+#[repr(C)]
+pub struct NixpkgsFmtEdit {
+    pub start: usize,
+    pub end: usize,
+    /// A NUL-terminated UTF-8 string of `insert_len` bytes.
+    pub insert: *const c_char,
+    pub insert_len: usize,
+}
+
+/// `NixpkgsFmtResult` together with the buffers it points to. The result
+/// comes first, so that pointers to it are pointers to the whole.
+#[repr(C)]
+struct OwnedResult {
+    result: NixpkgsFmtResult,
+    output: Option<Box<[u8]>>,
+    edits: Vec<NixpkgsFmtEdit>,
+    inserts: Vec<Box<[u8]>>,
+    error: Option<Box<[u8]>>,
+}
+
+/// Formats the UTF-8 `text` of `len` bytes.
+///
+/// # Safety
+///
+/// `text` must point to `len` readable bytes, and the result must be
+/// released with `nixpkgs_fmt_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nixpkgs_fmt_reformat(
+    text: *const u8,
+    len: usize,
+) -> *mut NixpkgsFmtResult {
+    run(text, len, |text, owned| {
+        owned.output = Some(nul_terminated(&nixpkgs_fmt::reformat_string(text)));
+    })
+}
+
+/// Annotates each line of the UTF-8 `text` of `len` bytes with the rules
+/// which would change it, like `nixpkgs-fmt --explain`.
+///
+/// # Safety
+///
+/// `text` must point to `len` readable bytes, and the result must be
+/// released with `nixpkgs_fmt_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nixpkgs_fmt_explain(text: *const u8, len: usize) -> *mut NixpkgsFmtResult {
+    run(text, len, |text, owned| {
+        owned.output = Some(nul_terminated(&nixpkgs_fmt::explain(text)));
+    })
+}
+
+/// Computes the edits which format the UTF-8 `text` of `len` bytes, so that
+/// only the changed parts of a buffer need to be replaced.
+///
+/// # Safety
+///
+/// `text` must point to `len` readable bytes, and the result must be
+/// released with `nixpkgs_fmt_free`.
+#[no_mangle]
+pub unsafe extern "C" fn nixpkgs_fmt_edits(text: *const u8, len: usize) -> *mut NixpkgsFmtResult {
+    run(text, len, |text, owned| {
+        let mut edits = nixpkgs_fmt::text_edits(text, &Default::default());
+        edits.sort_by_key(|edit| edit.delete.start());
+        for edit in edits {
+            let insert = nul_terminated(&edit.insert);
+            owned.edits.push(NixpkgsFmtEdit {
+                start: edit.delete.start().into(),
+                end: edit.delete.end().into(),
+                insert: insert.as_ptr() as *const c_char,
+                insert_len: insert.len() - 1,
+            });
+            owned.inserts.push(insert);
+        }
+    })
+}
+
+/// Releases a result returned by this library. Does nothing if `result` is
+/// null.
+///
+/// # Safety
+///
+/// `result` must be null or returned by this library, and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn nixpkgs_fmt_free(result: *mut NixpkgsFmtResult) {
+    if !result.is_null() {
+        drop(Box::from_raw(result as *mut OwnedResult));
+    }
+}
+
+/// Runs `f` on the input, turning invalid UTF-8 and panics into errors, so
+/// that they don't cross the FFI boundary.
+unsafe fn run(
+    text: *const u8,
+    len: usize,
+    f: impl FnOnce(&str, &mut OwnedResult),
+) -> *mut NixpkgsFmtResult {
+    let mut owned = OwnedResult {
+        result: NixpkgsFmtResult {
+            output: ptr::null(),
+            output_len: 0,
+            edits: ptr::null(),
+            edits_len: 0,
+            error: ptr::null(),
+        },
+        output: None,
+        edits: Vec::new(),
+        inserts: Vec::new(),
+        error: None,
+    };
+
+    let bytes = if text.is_null() { &[][..] } else { slice::from_raw_parts(text, len) };
+    let error = match str::from_utf8(bytes) {
+        Ok(text) => match panic::catch_unwind(AssertUnwindSafe(|| f(text, &mut owned))) {
+            Ok(()) => None,
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|it| it.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                Some(format!("nixpkgs-fmt panicked: {}", message))
+            }
+        },
+        Err(err) => Some(format!("invalid UTF-8: {}", err)),
+    };
+    if let Some(error) = error {
+        owned.output = None;
+        owned.edits.clear();
+        owned.inserts.clear();
+        owned.error = Some(nul_terminated(&error));
+    }
+
+    if let Some(output) = &owned.output {
+        owned.result.output = output.as_ptr() as *const c_char;
+        owned.result.output_len = output.len() - 1;
+    }
+    if let Some(error) = &owned.error {
+        owned.result.error = error.as_ptr() as *const c_char;
+    }
+    if owned.error.is_none() && owned.output.is_none() {
+        owned.result.edits = owned.edits.as_ptr();
+        owned.result.edits_len = owned.edits.len();
+    }
+    Box::into_raw(Box::new(owned)) as *mut NixpkgsFmtResult
+}
+
+fn nul_terminated(text: &str) -> Box<[u8]> {
+    let mut buf = Vec::with_capacity(text.len() + 1);
+    buf.extend_from_slice(text.as_bytes());
+    buf.push(0);
+    buf.into_boxed_slice()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use super::*;
+
+    unsafe fn c_str(ptr: *const c_char) -> &'static str {
+        CStr::from_ptr(ptr).to_str().unwrap()
+    }
+
+    #[test]
+    fn reformats() {
+        let text = "{a=1;}";
+        unsafe {
+            let result = nixpkgs_fmt_reformat(text.as_ptr(), text.len());
+            assert!((*result).error.is_null());
+            assert!((*result).edits.is_null());
+            assert_eq!(c_str((*result).output), "{ a = 1; }\n");
+            assert_eq!((*result).output_len, "{ a = 1; }\n".len());
+            nixpkgs_fmt_free(result);
+        }
+    }
+
+    #[test]
+    fn explains() {
+        let text = "{a=1;}\n";
+        unsafe {
+            let result = nixpkgs_fmt_explain(text.as_ptr(), text.len());
+            assert!(c_str((*result).output).starts_with("{a=1;}  # [1; 1): Space between bindings"));
+            nixpkgs_fmt_free(result);
+        }
+    }
+
+    #[test]
+    fn returns_edits() {
+        let text = "{a=1;}";
+        unsafe {
+            let result = nixpkgs_fmt_edits(text.as_ptr(), text.len());
+            assert!((*result).error.is_null());
+            assert!((*result).output.is_null());
+            let edits = slice::from_raw_parts((*result).edits, (*result).edits_len);
+            let mut actual = String::new();
+            let mut prev = 0;
+            for edit in edits {
+                actual.push_str(&text[prev..edit.start]);
+                actual.push_str(c_str(edit.insert));
+                prev = edit.end;
+            }
+            actual.push_str(&text[prev..]);
+            assert_eq!(actual, "{ a = 1; }\n");
+            nixpkgs_fmt_free(result);
+        }
+    }
+
+    #[test]
+    fn reports_invalid_utf8() {
+        let text = b"{ a = \"\xff\"; }";
+        unsafe {
+            let result = nixpkgs_fmt_reformat(text.as_ptr(), text.len());
+            assert!((*result).output.is_null());
+            assert_eq!(
+                c_str((*result).error),
+                "invalid UTF-8: invalid utf-8 sequence of 1 bytes from index 7"
+            );
+            nixpkgs_fmt_free(result);
+            nixpkgs_fmt_free(ptr::null_mut());
+        }
+    }
+}
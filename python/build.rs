@@ -0,0 +1,5 @@
+fn main() {
+    // Extension modules leave the symbols of Python to be resolved when they
+    // are loaded, which needs an extra flag on macOS.
+    pyo3_build_config::add_extension_module_link_args();
+}
@@ -0,0 +1,34 @@
+"""Python bindings to nixpkgs-fmt, the Nix code formatter for nixpkgs.
+
+The formatter runs in-process, in a native extension module built from the
+Rust crate next to this package, instead of running `nixpkgs-fmt` for each
+file.
+"""
+
+from typing import List, NamedTuple
+
+from ._native import NixpkgsFmtError, explain, reformat_string
+from ._native import reformat_edits as _reformat_edits
+
+__all__ = ["Edit", "NixpkgsFmtError", "explain", "reformat_edits", "reformat_string"]
+
+
+class Edit(NamedTuple):
+    """A replacement of `text[start:end]` with `insert`.
+
+    The offsets are indices of the `str` passed to `reformat_edits`, not
+    byte offsets.
+    """
+
+    start: int
+    end: int
+    insert: str
+
+
+def reformat_edits(text: str) -> List[Edit]:
+    """Returns the edits which format `text`, sorted by their offsets.
+
+    All the edits refer to `text`, so they should be applied at once, or from
+    the last to the first.
+    """
+    return [Edit(*it) for it in _reformat_edits(text)]
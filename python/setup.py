@@ -0,0 +1,42 @@
+"""Builds the native extension module of the `python` crate with cargo and
+ships it in the package, which is why the wheel is platform specific."""
+
+import os
+import shutil
+import subprocess
+import sys
+
+from setuptools import setup
+from setuptools.command.build_py import build_py
+from setuptools.dist import Distribution
+
+ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+
+# The name of the library built by cargo, and the name Python imports it by.
+# The module uses the stable ABI, so the same build works for Python 3.7+.
+LIBRARY, MODULE = {
+    "darwin": ("libnixpkgs_fmt_python.dylib", "_native.abi3.so"),
+    "win32": ("nixpkgs_fmt_python.dll", "_native.pyd"),
+}.get(sys.platform, ("libnixpkgs_fmt_python.so", "_native.abi3.so"))
+
+
+class BuildPyWithExtension(build_py):
+    def run(self):
+        subprocess.check_call(["cargo", "build", "--release", "-p", "nixpkgs-fmt-python"], cwd=ROOT)
+        super().run()
+        shutil.copy(
+            os.path.join(ROOT, "target", "release", LIBRARY),
+            os.path.join(self.build_lib, "nixpkgs_fmt", MODULE),
+        )
+
+
+class BinaryDistribution(Distribution):
+    def has_ext_modules(self):
+        return True
+
+
+setup(
+    cmdclass={"build_py": BuildPyWithExtension},
+    distclass=BinaryDistribution,
+    options={"bdist_wheel": {"py_limited_api": "cp37"}},
+)
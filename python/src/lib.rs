@@ -0,0 +1,89 @@
+//! The native part of the `nixpkgs_fmt` Python package, see
+//! `nixpkgs_fmt/__init__.py`.
+use std::panic::{self, AssertUnwindSafe};
+
+use pyo3::{create_exception, exceptions::PyValueError, prelude::*, types::PyString};
+
+create_exception!(_native, NixpkgsFmtError, PyValueError);
+
+/// Formats `text` in the nixpkgs style.
+#[pyfunction]
+fn reformat_string(text: &PyString) -> PyResult<String> {
+    run(text, nixpkgs_fmt::reformat_string)
+}
+
+/// Annotates each line of `text` with the rules which would change it, like
+/// `nixpkgs-fmt --explain`.
+#[pyfunction]
+fn explain(text: &PyString) -> PyResult<String> {
+    run(text, nixpkgs_fmt::explain)
+}
+
+/// Returns the edits which format `text`, as `(start, end, insert)` tuples
+/// sorted by their offsets, which are indices of the Python string.
+#[pyfunction]
+fn reformat_edits(text: &PyString) -> PyResult<Vec<(usize, usize, String)>> {
+    run(text, |text| {
+        let mut edits = nixpkgs_fmt::text_edits(text, &Default::default());
+        edits.sort_by_key(|edit| edit.delete.start());
+        let offsets = edits.iter().flat_map(|edit| vec![edit.delete.start(), edit.delete.end()]);
+        let mut indices = char_indices(text, offsets.map(usize::from)).into_iter();
+        edits
+            .into_iter()
+            .map(|edit| (indices.next().unwrap(), indices.next().unwrap(), edit.insert.to_string()))
+            .collect()
+    })
+}
+
+/// Runs `f` on the text of a Python string, turning strings which aren't
+/// valid Unicode and panics into `NixpkgsFmtError`.
+fn run<T>(text: &PyString, f: impl FnOnce(&str) -> T) -> PyResult<T> {
+    let text = text.to_str().map_err(|err| NixpkgsFmtError::new_err(err.to_string()))?;
+    panic::catch_unwind(AssertUnwindSafe(|| f(text))).map_err(|payload| {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic".to_string()
+        };
+        NixpkgsFmtError::new_err(format!("nixpkgs-fmt panicked: {}", message))
+    })
+}
+
+/// Converts byte offsets of `text` to indices of code points, which is how
+/// Python strings are indexed. The offsets must be sorted, so that the text is
+/// only scanned once.
+fn char_indices(text: &str, offsets: impl Iterator<Item = usize>) -> Vec<usize> {
+    let (mut prev_offset, mut index) = (0, 0);
+    offsets
+        .map(|offset| {
+            index += text[prev_offset..offset].chars().count();
+            prev_offset = offset;
+            index
+        })
+        .collect()
+}
+
+#[pymodule]
+fn _native(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("NixpkgsFmtError", py.get_type::<NixpkgsFmtError>())?;
+    m.add_function(wrap_pyfunction!(reformat_string, m)?)?;
+    m.add_function(wrap_pyfunction!(explain, m)?)?;
+    m.add_function(wrap_pyfunction!(reformat_edits, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::char_indices;
+
+    #[test]
+    fn converts_byte_offsets_to_char_indices() {
+        let text = "a = \"ö€\"; b = 1;";
+        assert_eq!(
+            char_indices(text, vec![0, 4, 5, 10, 11, 19].into_iter()),
+            vec![0, 4, 5, 7, 8, 16]
+        );
+    }
+}
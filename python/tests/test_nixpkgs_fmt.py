@@ -0,0 +1,44 @@
+import os
+import unittest
+
+import nixpkgs_fmt
+
+
+class TestNixpkgsFmt(unittest.TestCase):
+    def test_reformat_string(self):
+        self.assertEqual(nixpkgs_fmt.reformat_string("{a=1;}"), "{ a = 1; }\n")
+
+    def test_explain(self):
+        self.assertTrue(nixpkgs_fmt.explain("{a=1;}\n").startswith("{a=1;}  # [1; 1): "))
+
+    def test_reformat_edits(self):
+        text = '{ a = "ö";b=1; }\n'
+        edits = nixpkgs_fmt.reformat_edits(text)
+        self.assertIn(nixpkgs_fmt.Edit(10, 10, " "), edits)
+        self.assertEqual(apply(text, edits), '{ a = "ö"; b = 1; }\n')
+
+    def test_reformat_edits_of_a_large_file(self):
+        text = "{\n" + "".join('a%d="€";\n' % i for i in range(2000)) + "}\n"
+        edits = nixpkgs_fmt.reformat_edits(text)
+        self.assertEqual(len(edits), 6000)
+        self.assertEqual(apply(text, edits), nixpkgs_fmt.reformat_string(text))
+
+    def test_invalid_unicode(self):
+        with self.assertRaises(nixpkgs_fmt.NixpkgsFmtError):
+            nixpkgs_fmt.reformat_string("\udcff")
+
+    def test_native_module(self):
+        # The package is tested as installed from the wheel, with the module
+        # built by cargo.
+        native = nixpkgs_fmt._native.__file__
+        self.assertEqual(os.path.dirname(native), os.path.dirname(nixpkgs_fmt.__file__))
+
+
+def apply(text, edits):
+    for edit in reversed(edits):
+        text = text[: edit.start] + edit.insert + text[edit.end :]
+    return text
+
+
+if __name__ == "__main__":
+    unittest.main()
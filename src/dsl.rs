@@ -1,19 +1,14 @@
 //! This module contains a definition of pattern-based formatting DSL.
-use std::fmt;
+use std::{borrow::Cow, fmt};
 
-use rnix::SyntaxElement;
-
-use crate::{
-    pattern::Pattern,
-    tree_utils::{next_non_whitespace_sibling, prev_non_whitespace_sibling},
-};
+use crate::pattern::{Cond, Pattern};
 
 /// `SpacingRule` describes whitespace requirements between `SyntaxElement` Note
 /// that it doesn't handle indentation (first whitespace on a line), there's
 /// `IndentRule` for that!
 #[derive(Debug)]
 pub(crate) struct SpacingRule {
-    pub(crate) name: Option<RuleName>,
+    pub(crate) name: RuleName,
     /// An element to which this spacing rule applies
     pub(crate) pattern: Pattern,
     /// How much space to add/remove at the start or end of the element.
@@ -35,7 +30,7 @@ pub(crate) struct Space {
     pub(crate) loc: SpaceLoc,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum SpaceValue {
     /// Single whitespace char, like ` `
     Single,
@@ -43,6 +38,8 @@ pub(crate) enum SpaceValue {
     SingleOptionalNewline,
     /// A single newline (`\n`) char
     Newline,
+    /// Exactly one blank line, that is two newline chars.
+    BlankLine,
     /// No whitespace at all.
     None,
     /// No space, but preserve existing line break.
@@ -69,10 +66,16 @@ pub(crate) enum SpaceLoc {
 
 /// A builder to conveniently specify a set of `SpacingRule`s
 #[derive(Debug, Default)]
-pub(crate) struct SpacingDsl {
+pub struct SpacingDsl {
     pub(crate) rules: Vec<SpacingRule>,
-    #[cfg(test)]
-    pub(crate) tests: Vec<(&'static str, &'static str)>,
+    /// Examples of the rules, as `(rule, before, after)` triples.
+    pub(crate) tests: Vec<(RuleName, Example)>,
+    /// Examples which are waiting for the next rule.
+    pending_tests: Vec<Example>,
+    /// The rule started by the latest `rule` call.
+    current_rule: Option<RuleName>,
+    /// The first misuse of the builder, reported by `check`.
+    error: Option<String>,
 }
 
 impl SpacingDsl {
@@ -81,25 +84,41 @@ impl SpacingDsl {
     /// This is a low-level method for special cases, common cases are handled
     /// by a more convenient `SpacingRuleBuilder`.
     pub(crate) fn add_rule(&mut self, rule: SpacingRule) -> &mut SpacingDsl {
+        let tests = self.pending_tests.drain(..).map(|example| (rule.name.clone(), example));
+        self.tests.extend(tests.collect::<Vec<_>>());
         self.rules.push(rule);
         self
     }
-    /// Add a new rule with the given `name`.
-    pub(crate) fn rule(&mut self, name: &'static str) -> SpacingRuleBuilder<'_> {
+    /// Add a new rule with the given stable `id` and `description`.
+    pub fn rule(
+        &mut self,
+        id: impl Into<Cow<'static, str>>,
+        description: impl Into<Cow<'static, str>>,
+    ) -> SpacingRuleBuilder<'_> {
+        let rule_name = RuleName { id: id.into(), description: description.into() };
+        self.current_rule = Some(rule_name.clone());
         SpacingRuleBuilder {
             dsl: self,
-            rule_name: Some(name),
+            rule_name,
             parent: None,
             child: None,
             between: None,
             loc: None,
         }
     }
-    /// Specify an anonymous spacing rule for an element which is a child of `parent`.
-    pub(crate) fn inside(&mut self, parent: impl Into<Pattern>) -> SpacingRuleBuilder<'_> {
+    /// Specify one more spacing rule for an element which is a child of
+    /// `parent`, with the same name as the latest `rule`.
+    pub fn inside(&mut self, parent: impl Into<Pattern>) -> SpacingRuleBuilder<'_> {
+        let rule_name = match &self.current_rule {
+            Some(it) => it.clone(),
+            None => {
+                self.error.get_or_insert_with(|| "`inside` should follow `rule`".to_string());
+                RuleName::new("unnamed", "Unnamed rule")
+            }
+        };
         SpacingRuleBuilder {
             dsl: self,
-            rule_name: None,
+            rule_name,
             parent: None,
             child: None,
             between: None,
@@ -107,20 +126,31 @@ impl SpacingDsl {
         }
         .inside(parent)
     }
-    pub(crate) fn test(&mut self, before: &'static str, after: &'static str) -> &mut SpacingDsl {
-        #[cfg(test)]
-        {
-            self.tests.push((before, after));
-        }
-        let _ = (before, after);
+    /// Add an example for the rule which follows.
+    pub fn test(
+        &mut self,
+        before: impl Into<Cow<'static, str>>,
+        after: impl Into<Cow<'static, str>>,
+    ) -> &mut SpacingDsl {
+        self.pending_tests.push((before.into(), after.into()));
         self
     }
+    /// Fails if the builder was misused: if `inside` was called before the
+    /// first `rule`, or if the last examples are not followed by a rule.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        check_dsl(&self.error, &self.pending_tests)
+    }
+    /// Add the rules from `other` after the rules of `self`.
+    pub(crate) fn extend(&mut self, other: SpacingDsl) {
+        self.rules.extend(other.rules);
+        self.tests.extend(other.tests);
+    }
 }
 
 /// A builder to conveniently specify a single rule.
-pub(crate) struct SpacingRuleBuilder<'a> {
+pub struct SpacingRuleBuilder<'a> {
     dsl: &'a mut SpacingDsl,
-    rule_name: Option<&'static str>,
+    rule_name: RuleName,
     parent: Option<Pattern>,
     child: Option<Pattern>,
     between: Option<(Pattern, Pattern)>,
@@ -129,30 +159,30 @@ pub(crate) struct SpacingRuleBuilder<'a> {
 
 impl<'a> SpacingRuleBuilder<'a> {
     /// The rule applies to direct children of the `parent` element.
-    pub(crate) fn inside(mut self, parent: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+    pub fn inside(mut self, parent: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         self.parent = Some(parent.into());
         self
     }
     /// The rule applies to both sides of the element `child`.
-    pub(crate) fn around(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+    pub fn around(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         self.child = Some(child.into());
         self.loc = Some(SpaceLoc::Around);
         self
     }
     /// The rule applies to the leading whitespace before `child`.
-    pub(crate) fn before(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+    pub fn before(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         self.child = Some(child.into());
         self.loc = Some(SpaceLoc::Before);
         self
     }
     /// The rule applies to the trailing whitespace after `child`.
-    pub(crate) fn after(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
+    pub fn after(mut self, child: impl Into<Pattern>) -> SpacingRuleBuilder<'a> {
         self.child = Some(child.into());
         self.loc = Some(SpaceLoc::After);
         self
     }
     /// The rule applies to the whitespace between the two nodes.
-    pub(crate) fn between(
+    pub fn between(
         mut self,
         left: impl Into<Pattern>,
         right: impl Into<Pattern>,
@@ -161,70 +191,65 @@ impl<'a> SpacingRuleBuilder<'a> {
         self.loc = Some(SpaceLoc::After);
         self
     }
-    /// The rule applies if the `cond` is true.
-    pub(crate) fn when(mut self, cond: fn(&SyntaxElement) -> bool) -> SpacingRuleBuilder<'a> {
-        let pred = cond.into();
-        let prev = self.child.take().unwrap();
-        self.child = Some(prev & pred);
+    /// The rule applies if the `cond` is true for the element.
+    pub fn when(mut self, cond: Cond) -> SpacingRuleBuilder<'a> {
+        let prev = self.child.take().expect("`when` should follow `before`, `after` or `around`");
+        self.child = Some(prev & Pattern::from(cond));
         self
     }
     /// Enforce single whitespace character.
-    pub(crate) fn single_space(self) -> &'a mut SpacingDsl {
+    pub fn single_space(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::Single)
     }
-    pub(crate) fn single_space_or_optional_newline(self) -> &'a mut SpacingDsl {
+    pub fn single_space_or_optional_newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::SingleOptionalNewline)
     }
-    pub(crate) fn no_space_or_optional_newline(self) -> &'a mut SpacingDsl {
+    pub fn no_space_or_optional_newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::NoneOptionalNewline)
     }
     /// Enforce the absence of any space.
-    pub(crate) fn no_space(self) -> &'a mut SpacingDsl {
+    pub fn no_space(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::None)
     }
     /// Enforce a single whitespace or newline character.
-    pub(crate) fn single_space_or_newline(self) -> &'a mut SpacingDsl {
+    pub fn single_space_or_newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::SingleOrNewline)
     }
     /// Enforce a absence of whitespace or a newline character.
-    pub(crate) fn no_space_or_newline(self) -> &'a mut SpacingDsl {
+    pub fn no_space_or_newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::NoneOrNewline)
     }
     /// Enforce a newline
-    pub(crate) fn newline(self) -> &'a mut SpacingDsl {
+    pub fn newline(self) -> &'a mut SpacingDsl {
         self.finish(SpaceValue::Newline)
     }
-    fn finish(self, value: SpaceValue) -> &'a mut SpacingDsl {
+    /// Enforce a single blank line
+    pub fn blank_line(self) -> &'a mut SpacingDsl {
+        self.finish(SpaceValue::BlankLine)
+    }
+    /// Enforce the given `value`.
+    pub(crate) fn finish(self, value: SpaceValue) -> &'a mut SpacingDsl {
         assert!(self.between.is_some() ^ self.child.is_some());
         let parent = self.parent.expect("parent must be set for each rule");
         if let Some((left, right)) = self.between {
-            let child = {
-                let left = left.clone();
-                let right = right.clone();
-                left & Pattern::from(move |it: &SyntaxElement| {
-                    next_non_whitespace_sibling(it).map(|it| right.matches(&it)) == Some(true)
-                })
-            };
+            let child = left.clone().with_next(right.clone());
             let rule = SpacingRule {
-                name: self.rule_name.map(RuleName),
+                name: self.rule_name.clone(),
                 pattern: child.with_parent(parent.clone()),
                 space: Space { value, loc: SpaceLoc::After },
             };
             self.dsl.add_rule(rule);
 
-            let child = right
-                & Pattern::from(move |it: &SyntaxElement| {
-                    prev_non_whitespace_sibling(it).map(|it| left.matches(&it)) == Some(true)
-                });
+            let child = right.with_prev(left);
             let rule = SpacingRule {
-                name: self.rule_name.map(RuleName),
+                name: self.rule_name,
                 pattern: child.with_parent(parent),
                 space: Space { value, loc: SpaceLoc::Before },
             };
             self.dsl.add_rule(rule);
         } else {
             let rule = SpacingRule {
-                name: self.rule_name.map(RuleName),
+                name: self.rule_name,
                 pattern: self.child.unwrap().with_parent(parent),
                 space: Space { value, loc: self.loc.unwrap() },
             };
@@ -240,25 +265,39 @@ pub(crate) enum Modality {
     Negative,
 }
 
+/// How to indent an element relative to its anchor.
+#[non_exhaustive]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum IndentValue {
+pub enum IndentValue {
     Indent,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) struct RuleName(&'static str);
+/// Identifies a rule, for `explain` and for the public rule registry.
+///
+/// The `id` is stable and kebab-cased, like `space-before-eq`, while the
+/// `description` is a short capitalized sentence without a trailing `.`.
+/// Both are checked by the tests of `crate::rule_registry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RuleName {
+    pub(crate) id: Cow<'static, str>,
+    pub(crate) description: Cow<'static, str>,
+}
+
+/// An example of a rule, as a `(before, after)` pair.
+pub(crate) type Example = (Cow<'static, str>, Cow<'static, str>);
 
 impl fmt::Display for RuleName {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt(&self.0, f)
+        fmt::Display::fmt(&self.description, f)
     }
 }
 
 impl RuleName {
-    pub(crate) fn new(name: &'static str) -> RuleName {
-        assert!(name.chars().next().unwrap().is_uppercase(), "rule names should be capitalized");
-        assert!(!name.ends_with('.'), "rule names should not end in `.`");
-        RuleName(name)
+    pub(crate) const fn new(id: &'static str, description: &'static str) -> RuleName {
+        RuleName { id: Cow::Borrowed(id), description: Cow::Borrowed(description) }
+    }
+    pub(crate) fn id(&self) -> &str {
+        &self.id
     }
 }
 
@@ -301,12 +340,14 @@ pub(crate) struct IndentRule {
 }
 
 /// A builder to conveniently specify a set of `IndentRule`s.
-#[derive(Default)]
-pub(crate) struct IndentDsl {
+#[derive(Debug, Default)]
+pub struct IndentDsl {
     pub(crate) rules: Vec<IndentRule>,
     pub(crate) anchors: Vec<Pattern>,
-    #[cfg(test)]
-    pub(crate) tests: Vec<(&'static str, &'static str)>,
+    /// Examples of the rules, as `(rule, before, after)` triples.
+    pub(crate) tests: Vec<(RuleName, Example)>,
+    /// Examples which are waiting for the next rule.
+    pending_tests: Vec<Example>,
 }
 
 impl IndentDsl {
@@ -325,28 +366,43 @@ impl IndentDsl {
     ///
     /// we want to indent `y = z;` relative to `baz ? ...`, although it doesn't
     /// start on the first line.
-    pub(crate) fn anchor(&mut self, pattern: impl Into<Pattern>) -> &mut IndentDsl {
+    pub fn anchor(&mut self, pattern: impl Into<Pattern>) -> &mut IndentDsl {
         self.anchors.push(pattern.into());
         self
     }
-    /// Adds a new indent rule with the given name
-    pub(crate) fn rule<'a>(&'a mut self, rule_name: &'static str) -> IndentRuleBuilder<'a> {
-        IndentRuleBuilder::new(self, rule_name)
+    /// Adds a new indent rule with the given stable `id` and `description`.
+    pub fn rule<'a>(
+        &'a mut self,
+        id: impl Into<Cow<'static, str>>,
+        description: impl Into<Cow<'static, str>>,
+    ) -> IndentRuleBuilder<'a> {
+        IndentRuleBuilder::new(self, RuleName { id: id.into(), description: description.into() })
     }
-    pub(crate) fn test(&mut self, before: &'static str, after: &'static str) -> &mut IndentDsl {
-        #[cfg(test)]
-        {
-            self.tests.push((before, after));
-        }
-        let _ = (before, after);
+    /// Add an example for the rule which follows.
+    pub fn test(
+        &mut self,
+        before: impl Into<Cow<'static, str>>,
+        after: impl Into<Cow<'static, str>>,
+    ) -> &mut IndentDsl {
+        self.pending_tests.push((before.into(), after.into()));
         self
     }
+    /// Fails if the last examples are not followed by a rule.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        check_dsl(&None, &self.pending_tests)
+    }
+    /// Add the rules and anchors from `other` to `self`.
+    pub(crate) fn extend(&mut self, other: IndentDsl) {
+        self.rules.extend(other.rules);
+        self.anchors.extend(other.anchors);
+        self.tests.extend(other.tests);
+    }
 }
 
 /// A builder to conveniently specify a single `IndentRule`.
-pub(crate) struct IndentRuleBuilder<'a> {
+pub struct IndentRuleBuilder<'a> {
     dsl: &'a mut IndentDsl,
-    rule_name: &'static str,
+    rule_name: RuleName,
     parent: Option<Pattern>,
     child: Option<Pattern>,
     child_modality: Modality,
@@ -354,7 +410,7 @@ pub(crate) struct IndentRuleBuilder<'a> {
 }
 
 impl<'a> IndentRuleBuilder<'a> {
-    fn new(dsl: &'a mut IndentDsl, rule_name: &'static str) -> IndentRuleBuilder<'a> {
+    fn new(dsl: &'a mut IndentDsl, rule_name: RuleName) -> IndentRuleBuilder<'a> {
         IndentRuleBuilder {
             dsl,
             rule_name,
@@ -366,14 +422,14 @@ impl<'a> IndentRuleBuilder<'a> {
     }
 
     /// Rule applies if element's parent matches.
-    pub(crate) fn inside(mut self, parent: impl Into<Pattern>) -> Self {
+    pub fn inside(mut self, parent: impl Into<Pattern>) -> Self {
         let prev = self.parent.replace(parent.into());
         assert!(prev.is_none());
         self
     }
 
     /// Rule applies if element itself does *not* match.
-    pub(crate) fn not_matching(self, child: impl Into<Pattern>) -> Self {
+    pub fn not_matching(self, child: impl Into<Pattern>) -> Self {
         self.matching_modality(child.into(), Modality::Negative)
     }
 
@@ -385,25 +441,38 @@ impl<'a> IndentRuleBuilder<'a> {
     }
 
     /// Which indent does the rule applies?
-    pub(crate) fn set(self, indent_value: IndentValue) -> &'a mut IndentDsl {
+    pub fn set(self, indent_value: IndentValue) -> &'a mut IndentDsl {
         let dsl = self.dsl;
         let name = self.rule_name;
+        let parent = self.parent.unwrap_or_else(|| panic!("incomplete rule: {}", name.id()));
         let rule = IndentRule {
-            name: RuleName::new(name),
-            parent: self.parent.unwrap_or_else(|| panic!("incomplete rule: {}", name)),
+            name,
+            parent,
             child: self.child,
             child_modality: self.child_modality,
             anchor_pattern: self.anchor_pattern,
             indent_value,
         };
+        let tests = dsl.pending_tests.drain(..).map(|example| (rule.name.clone(), example));
+        dsl.tests.extend(tests.collect::<Vec<_>>());
         dsl.rules.push(rule);
         dsl
     }
 
     /// Only apply this rule when `cond` is true for the anchor node, relative
     /// to which we compute indentation level.
-    pub(crate) fn when_anchor(mut self, cond: impl Into<Pattern>) -> Self {
+    pub fn when_anchor(mut self, cond: impl Into<Pattern>) -> Self {
         self.anchor_pattern = Some(cond.into());
         self
     }
 }
+
+fn check_dsl(error: &Option<String>, pending_tests: &[Example]) -> Result<(), String> {
+    if let Some(error) = error {
+        return Err(error.clone());
+    }
+    match pending_tests.first() {
+        Some((before, _)) => Err(format!("the example `{}` should be followed by a rule", before)),
+        None => Ok(()),
+    }
+}
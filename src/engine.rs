@@ -4,66 +4,142 @@ mod fmt_model;
 mod indentation;
 mod spacing;
 mod fixes;
+mod commas;
+mod conflicts;
 
-use rnix::{SyntaxNode, TextRange};
+use rnix::{SyntaxKind::NODE_PATTERN, SyntaxNode, TextRange, TextSize};
 use smol_str::SmolStr;
 
 use crate::{
-    dsl::{IndentDsl, RuleName, SpacingDsl},
-    engine::fmt_model::{BlockPosition, FmtModel, SpaceBlock, SpaceBlockOrToken},
+    dsl::{IndentDsl, RuleName, SpacingDsl, SpacingRule},
+    engine::{
+        fmt_model::{BlockPosition, FmtModel, SpaceBlock, SpaceBlockOrToken},
+        indentation::IndentDecision,
+    },
     pattern::PatternSet,
+    rules::{LIMIT_BLANK_LINES, TRIM_BLANK_LINES},
     tree_utils::walk_non_whitespace_non_interpol,
-    AtomEdit, FmtDiff,
+    AtomEdit, FmtDiff, FormatOptions, IndentInfo, RuleConflict, RuleKind, RuleStats,
 };
 
+pub(crate) use conflicts::find_conflicts;
+pub(crate) use fixes::node_indent_ranges;
+
+/// An edit, the rule which caused it, if any, and, for verbose explanations,
+/// how the rule matched.
+pub(crate) type ExplainedEdit = (AtomEdit, Option<RuleName>, Option<String>);
+
 pub(crate) enum ExtraInfo<'a> {
     // Explanation contains a vector of edits, each of which is optionally paired with the rule name
-    // that caused the edit.
-    Explanation(&'a mut Vec<(AtomEdit, Option<RuleName>)>),
+    // that caused the edit. With `verbose`, the edits of rules also record the matched pattern and,
+    // for indentation, the anchor and the computed indent.
+    Explanation {
+        explanation: &'a mut Vec<ExplainedEdit>,
+        verbose: bool,
+    },
     /// Edits contains spacing edits to be applied to the document and indent edits to be applied in
     /// a second, separate transaction.
     Edits {
         spacing_edits: &'a mut Vec<AtomEdit>,
         indent_edits: &'a mut Vec<AtomEdit>,
     },
+    /// Stats counts the edits of every phase by rule, unlike Explanation,
+    /// which only has the edits of the first phase which changed something.
+    Stats(&'a mut RuleStats),
+    /// Indents describes how each element which starts a line is indented.
+    /// The lines refer to `text`, which is the text after spacing edits.
+    Indents {
+        text: &'a mut String,
+        indents: &'a mut Vec<IndentInfo>,
+    },
     None,
 }
 
-/// The main entry point for formatting
+/// The main entry point for formatting. Besides the formatted node, returns
+/// the conflicting rules found along the way.
 pub(crate) fn reformat(
     spacing_dsl: &SpacingDsl,
     indent_dsl: &IndentDsl,
+    options: &FormatOptions,
     node: &SyntaxNode,
     // Passing this enum is just a cute type-safe way for the caller to
     // select what extra info they need.
     mut extra_info: ExtraInfo,
-) -> SyntaxNode {
-    // First, adjust spacing rules between the nodes.
-    // This can force some newlines.
+) -> (SyntaxNode, Vec<RuleConflict>) {
+    // First, move the commas of lambda patterns according to the comma style.
+    // This is the only phase which changes the structure of the tree, so we
+    // reparse the result before looking at the whitespace.
     let mut model = FmtModel::new(node.clone());
+    for element in walk_non_whitespace_non_interpol(node) {
+        if let Some(pattern) = element.as_node().filter(|it| it.kind() == NODE_PATTERN) {
+            commas::fix_commas(pattern, options, &mut model)
+        }
+    }
 
-    // First, adjust spacing rules between the nodes.
+    let comma_diff = model.into_diff();
+    let verbose = matches!(extra_info, ExtraInfo::Explanation { verbose: true, .. });
+    if let ExtraInfo::Explanation { explanation, .. } = &mut extra_info {
+        if comma_diff.has_changes() {
+            explanation.extend(comma_diff.explained_edits())
+        }
+    } else if let ExtraInfo::Stats(stats) = &mut extra_info {
+        stats.add_edits(&comma_diff.edits)
+    }
+    let original_node = node;
+    let node = &comma_diff.to_node();
+
+    // Next, adjust spacing rules between the nodes.
     // This can force some newlines.
-    let spacing_rule_set = PatternSet::new(spacing_dsl.rules.iter());
+    let mut model = FmtModel::new(node.clone());
+    model.verbose = verbose;
+
+    let spacing_rule_set = PatternSet::new(
+        spacing_dsl.rules.iter().filter(|rule| options.is_rule_enabled(rule.name.id())),
+    );
+    let mut conflicts = Vec::new();
     for element in walk_non_whitespace_non_interpol(node) {
+        let mut applied: Vec<&SpacingRule> = Vec::new();
         for rule in spacing_rule_set.matching(element.clone()) {
-            rule.apply(&element, &mut model)
+            // Later rules override earlier ones, which is intended within a
+            // rule, but likely an accident between different rules.
+            for prev in applied.iter().filter(|prev| spacing_rules_clash(prev, rule)) {
+                conflicts.push(RuleConflict::new(
+                    RuleKind::Spacing,
+                    &prev.name,
+                    &rule.name,
+                    Some(element.text_range()),
+                ));
+            }
+            rule.apply(&element, &mut model);
+            applied.push(rule);
         }
     }
 
     let spacing_diff = model.into_diff();
-    if let ExtraInfo::Explanation(explanation) = &mut extra_info {
-        if spacing_diff.has_changes() {
-            explanation.extend(spacing_diff.edits.clone())
+    if let ExtraInfo::Explanation { explanation, .. } = &mut extra_info {
+        // Like with indentation below, we only explain spacing if the commas
+        // were fine, as the ranges refer to the text with moved commas.
+        if spacing_diff.has_changes() && explanation.is_empty() {
+            explanation.extend(spacing_diff.explained_edits())
         }
     } else if let ExtraInfo::Edits { spacing_edits, .. } = &mut extra_info {
-        spacing_edits
-            .extend(spacing_diff.edits.iter().map(|(ae, _)| ae.clone()).collect::<Vec<_>>());
+        // Moving commas is reported as a part of the spacing transaction.
+        spacing_edits.extend(compose_edits(
+            &original_node.to_string(),
+            &comma_diff.text_diff(),
+            &spacing_diff.text_diff(),
+        ));
+    } else if let ExtraInfo::Stats(stats) = &mut extra_info {
+        stats.add_edits(&spacing_diff.edits)
     }
     let node = spacing_diff.to_node();
 
     // Next, for each node which starts the newline, adjust the indent.
     let mut model = FmtModel::new(node.clone());
+    model.verbose = verbose;
+    if let ExtraInfo::Indents { .. } = extra_info {
+        model.indent_decisions = Some(Vec::new());
+    }
 
     let anchor_set = PatternSet::new(indent_dsl.anchors.iter());
     for element in walk_non_whitespace_non_interpol(&node) {
@@ -88,31 +164,170 @@ pub(crate) fn reformat(
 
         let mut matching = indent_dsl.rules.iter().filter(|it| it.matches(&element));
         if let Some(rule) = matching.next() {
-            rule.apply(&element, &mut model, &anchor_set);
-            assert!(matching.next().is_none(), "more that one indent rule matched");
+            // A disabled rule still takes the element away from the default
+            // indentation, so that disabling rules never adds changes.
+            if options.is_rule_enabled(rule.name.id()) {
+                rule.apply(&element, &mut model, &anchor_set, options);
+            }
+            // The first rule wins, but the result depends on the order of the
+            // rules, so we let the caller know.
+            for other in matching {
+                conflicts.push(RuleConflict::new(
+                    RuleKind::Indentation,
+                    &rule.name,
+                    &other.name,
+                    Some(element.text_range()),
+                ));
+            }
         } else {
-            indentation::default_indent(&element, &mut model, &anchor_set)
+            if let ExtraInfo::Stats(stats) = &mut extra_info {
+                stats.default_indent += 1;
+            }
+            indentation::default_indent(&element, &mut model, &anchor_set, options)
         }
     }
 
+    if let ExtraInfo::Indents { text, indents } = &mut extra_info {
+        **text = node.to_string();
+        let decisions = model.indent_decisions.take().unwrap_or_default();
+        indents.extend(decisions.into_iter().map(|it| indent_info(text, it)));
+    }
+
     // Finally, do custom touch-ups like re-indenting of string literals and
     // replacing URLs with string literals.
     for element in walk_non_whitespace_non_interpol(&node) {
-        fixes::fix(element, &mut model, &anchor_set)
+        fixes::fix(element, &mut model, &anchor_set, options)
+    }
+
+    // Blank lines are only normalized when asked to, as by default we preserve
+    // the developer's choice.
+    let max_blank_lines =
+        options.max_blank_lines.filter(|_| options.is_rule_enabled(LIMIT_BLANK_LINES.id()));
+    let trim_blank_lines =
+        options.trim_blank_lines && options.is_rule_enabled(TRIM_BLANK_LINES.id());
+    if max_blank_lines.is_some() || trim_blank_lines {
+        model.normalize_blank_lines(&node, max_blank_lines, trim_blank_lines);
     }
 
     let indent_diff = model.into_diff();
-    if let ExtraInfo::Explanation(explanation) = extra_info {
+    if let ExtraInfo::Explanation { explanation, .. } = extra_info {
         // We don't add indentation explanations if we had whitespace changes,
         // as that'll require fixing up the original ranges. This could be done,
         // but it's not clear if it is really necessary.
         if indent_diff.has_changes() && explanation.is_empty() {
-            explanation.extend(indent_diff.edits.clone())
+            explanation.extend(indent_diff.explained_edits())
         }
     } else if let ExtraInfo::Edits { indent_edits, .. } = extra_info {
         indent_edits.extend(indent_diff.edits.iter().map(|(ae, _)| ae.clone()).collect::<Vec<_>>());
+    } else if let ExtraInfo::Stats(stats) = extra_info {
+        stats.add_edits(&indent_diff.edits)
+    }
+    (indent_diff.to_node(), conflicts)
+}
+
+/// Two different rules which set the whitespace on the same side of an element
+/// to different values. Like in `find_conflicts`, a conditional later rule is
+/// likely a refinement of the former, so it's only a conflict if the later
+/// rule is unconditional.
+fn spacing_rules_clash(first: &SpacingRule, second: &SpacingRule) -> bool {
+    let (l1, l2) = (first.space.loc, second.space.loc);
+    let same_side = (l1.is_before() && l2.is_before()) || (l1.is_after() && l2.is_after());
+    same_side
+        && first.name.id() != second.name.id()
+        && first.space.value != second.space.value
+        && second.pattern.is_exact()
+}
+
+fn indent_info(text: &str, decision: IndentDecision) -> IndentInfo {
+    let line_of = |offset: TextSize| text[..usize::from(offset)].matches('\n').count() + 1;
+    IndentInfo {
+        line: line_of(decision.element.text_range().start()),
+        kind: decision.element.kind(),
+        rule: decision.rule.id.clone(),
+        anchor: decision.anchor.as_ref().map(|it| it.kind()),
+        anchor_line: decision.anchor.as_ref().map(|it| line_of(it.text_range().start())),
+        level: decision.indent.level(),
+        alignment: decision.indent.alignment(),
+    }
+}
+
+/// Combines two consecutive transactions into a single one.
+///
+/// Edits in `first` refer to `text`, edits in `second` refer to the text after
+/// applying `first`. Edits which touch each other are merged together.
+pub(crate) fn compose_edits(text: &str, first: &[AtomEdit], second: &[AtomEdit]) -> Vec<AtomEdit> {
+    if first.is_empty() {
+        return second.to_vec();
+    }
+    let mut first = first.to_vec();
+    first.sort_by_key(|edit| edit.delete.start());
+    let mut second = second.to_vec();
+    second.sort_by_key(|edit| edit.delete.start());
+
+    // The text after `first` was applied, and the ranges of `first` in it.
+    let mut intermediate = String::new();
+    let mut first_ranges = Vec::with_capacity(first.len());
+    let mut prev = 0;
+    for edit in first.iter() {
+        intermediate.push_str(&text[prev..usize::from(edit.delete.start())]);
+        let start = TextSize::of(intermediate.as_str());
+        intermediate.push_str(&edit.insert);
+        first_ranges.push(TextRange::at(start, TextSize::of(edit.insert.as_str())));
+        prev = edit.delete.end().into();
+    }
+    intermediate.push_str(&text[prev..]);
+
+    // Group the edits of both transactions into clusters of adjacent ranges
+    // in the intermediate text.
+    let mut ranges: Vec<(TextRange, Option<&AtomEdit>)> =
+        first_ranges.iter().map(|&range| (range, None)).collect();
+    ranges.extend(second.iter().map(|edit| (edit.delete, Some(edit))));
+    ranges.sort_by_key(|(range, _)| range.start());
+
+    let mut res = Vec::new();
+    // How much longer the intermediate text is compared to the original text
+    // before the current cluster.
+    let mut shift: i64 = 0;
+    let mut first_edits = first.iter();
+    let mut idx = 0;
+    while idx < ranges.len() {
+        let start = ranges[idx].0.start();
+        let mut end = ranges[idx].0.end();
+        let mut cluster_end = idx + 1;
+        while cluster_end < ranges.len() && ranges[cluster_end].0.start() <= end {
+            end = end.max(ranges[cluster_end].0.end());
+            cluster_end += 1;
+        }
+
+        let mut insert = String::new();
+        let mut prev = start;
+        let mut cluster_shift = 0;
+        for (range, edit) in &ranges[idx..cluster_end] {
+            match edit {
+                Some(edit) => {
+                    insert.push_str(&intermediate[prev.into()..range.start().into()]);
+                    insert.push_str(&edit.insert);
+                    prev = range.end();
+                }
+                None => {
+                    let first_edit = first_edits.next().unwrap();
+                    cluster_shift += first_edit.insert.len() as i64
+                        - i64::from(u32::from(first_edit.delete.len()));
+                }
+            }
+        }
+        insert.push_str(&intermediate[prev.into()..end.into()]);
+
+        let original_start = (i64::from(u32::from(start)) - shift) as u32;
+        shift += cluster_shift;
+        let original_end = (i64::from(u32::from(end)) - shift) as u32;
+        res.push(AtomEdit {
+            delete: TextRange::new(original_start.into(), original_end.into()),
+            insert: insert.into(),
+        });
+        idx = cluster_end;
     }
-    indent_diff.to_node()
+    res
 }
 
 impl FmtDiff {
@@ -0,0 +1,131 @@
+use rnix::{
+    NodeOrToken, SyntaxElement,
+    SyntaxKind::{NODE_PAT_ENTRY, TOKEN_COMMA, TOKEN_COMMENT, TOKEN_ELLIPSIS, TOKEN_WHITESPACE},
+    SyntaxNode, TextRange, TextSize,
+};
+
+use crate::{
+    dsl::RuleName,
+    engine::FmtModel,
+    rules::{LEADING_COMMAS, TRAILING_COMMAS},
+    tree_utils::has_newline,
+    AtomEdit, CommaStyle, FormatOptions,
+};
+
+/// Moves the commas of a multi-line lambda pattern to the side of the
+/// arguments requested by `style`.
+///
+/// That is, with `CommaStyle::Trailing`, this turns
+///
+/// ```nix
+/// { foo
+/// , bar
+/// }: foo
+/// ```
+///
+/// into `{ foo,\n bar,\n}: foo`, and with `CommaStyle::Leading` does the
+/// opposite. Only the commas are moved, the spacing rules take care of the
+/// whitespace afterwards.
+pub(super) fn fix_commas(pattern: &SyntaxNode, options: &FormatOptions, model: &mut FmtModel) {
+    let style = match options.comma_style {
+        Some(it) => it,
+        None => return,
+    };
+    let rule = match style {
+        CommaStyle::Leading => LEADING_COMMAS,
+        CommaStyle::Trailing => TRAILING_COMMAS,
+    };
+    if !options.is_rule_enabled(rule.id()) || !has_newline(pattern) {
+        return;
+    }
+    let items: Vec<SyntaxElement> = pattern
+        .children_with_tokens()
+        .filter(|it| matches!(it.kind(), NODE_PAT_ENTRY | TOKEN_ELLIPSIS | TOKEN_COMMA))
+        .collect();
+    match style {
+        CommaStyle::Leading => to_leading(&items, model),
+        CommaStyle::Trailing => to_trailing(&items, model),
+    }
+}
+
+fn to_leading(items: &[SyntaxElement], model: &mut FmtModel) {
+    let reason = &LEADING_COMMAS;
+    for (idx, comma) in items.iter().enumerate() {
+        if comma.kind() != TOKEN_COMMA {
+            continue;
+        }
+        match items.get(idx + 1) {
+            // `{ foo, }` is valid, but looks odd with leading commas.
+            None => delete(comma, model, reason),
+            Some(next) if next.kind() == TOKEN_COMMA => (),
+            Some(next) => {
+                if separated(comma, next) {
+                    delete(comma, model, reason);
+                    insert_comma(next.text_range().start(), model, reason);
+                }
+            }
+        }
+    }
+}
+
+fn to_trailing(items: &[SyntaxElement], model: &mut FmtModel) {
+    let reason = &TRAILING_COMMAS;
+    let mut prev_entry: Option<&SyntaxElement> = None;
+    for item in items {
+        match item.kind() {
+            NODE_PAT_ENTRY => prev_entry = Some(item),
+            TOKEN_COMMA => {
+                if let Some(entry) = prev_entry.take() {
+                    if separated(entry, item) {
+                        delete(item, model, reason);
+                        insert_comma(entry.text_range().end(), model, reason);
+                    }
+                }
+            }
+            _ => prev_entry = None,
+        }
+    }
+    // The last argument gets a comma as well, unless it is an ellipsis.
+    if let Some(entry) = prev_entry {
+        insert_comma(entry.text_range().end(), model, reason);
+    }
+}
+
+/// Checks if there's a newline or a comment between two siblings.
+fn separated(first: &SyntaxElement, second: &SyntaxElement) -> bool {
+    let mut curr = first.next_sibling_or_token();
+    while let Some(element) = curr {
+        if &element == second {
+            break;
+        }
+        match &element {
+            NodeOrToken::Token(token) if token.kind() == TOKEN_COMMENT => return true,
+            NodeOrToken::Token(token) if token.text().contains('\n') => return true,
+            _ => (),
+        }
+        curr = element.next_sibling_or_token();
+    }
+    false
+}
+
+/// Deletes a comma. If the comma is on a line of its own, the whitespace after
+/// it goes too, so that no blank line is left in its place.
+fn delete(comma: &SyntaxElement, model: &mut FmtModel, reason: &RuleName) {
+    let mut range = comma.text_range();
+    let is_whitespace = |it: &Option<SyntaxElement>| {
+        it.as_ref().map(|it| it.kind() == TOKEN_WHITESPACE && it.to_string().contains('\n'))
+            == Some(true)
+    };
+    let next = comma.next_sibling_or_token();
+    if is_whitespace(&comma.prev_sibling_or_token()) && is_whitespace(&next) {
+        range = range.cover(next.unwrap().text_range());
+    }
+    model.raw_edit(AtomEdit { delete: range, insert: "".into() }, Some(reason.clone()))
+}
+
+fn insert_comma(offset: TextSize, model: &mut FmtModel, reason: &RuleName) {
+    model.raw_edit(
+        AtomEdit { delete: TextRange::empty(offset), insert: ",".into() },
+        Some(reason.clone()),
+    )
+}
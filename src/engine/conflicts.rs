@@ -0,0 +1,87 @@
+//! Finds rules which match the same elements without looking at any input.
+//!
+//! As patterns are arbitrary predicates, this only reports the conflicts which
+//! follow from the kinds of elements and their parents. Others are found while
+//! formatting, see `engine::reformat`.
+use std::collections::HashSet;
+
+use rnix::SyntaxKind;
+
+use crate::{
+    dsl::{IndentDsl, IndentRule, Modality, SpacingDsl, SpacingRule},
+    RuleConflict, RuleKind,
+};
+
+pub(crate) fn find_conflicts(
+    spacing_dsl: &SpacingDsl,
+    indent_dsl: &IndentDsl,
+) -> Vec<RuleConflict> {
+    let mut res = Vec::new();
+    for (idx, first) in spacing_dsl.rules.iter().enumerate() {
+        for second in spacing_dsl.rules[idx + 1..].iter() {
+            if spacing_rules_conflict(first, second) {
+                res.push(RuleConflict::new(RuleKind::Spacing, &first.name, &second.name, None));
+            }
+        }
+    }
+    for (idx, first) in indent_dsl.rules.iter().enumerate() {
+        for second in indent_dsl.rules[idx + 1..].iter() {
+            if indent_rules_conflict(first, second) {
+                res.push(RuleConflict::new(RuleKind::Indentation, &first.name, &second.name, None));
+            }
+        }
+    }
+    res
+}
+
+/// Spacing rules are applied in order, so the later rule wins. It's only a
+/// conflict if both rules are unconditional, as otherwise the later one is
+/// likely a refinement of the former.
+fn spacing_rules_conflict(first: &SpacingRule, second: &SpacingRule) -> bool {
+    let (l1, l2) = (first.space.loc, second.space.loc);
+    let same_side = (l1.is_before() && l2.is_before()) || (l1.is_after() && l2.is_after());
+    same_side
+        && first.space.value != second.space.value
+        && first.pattern.surely_overlaps(&second.pattern)
+}
+
+/// At most one indent rule is applied to each element.
+fn indent_rules_conflict(first: &IndentRule, second: &IndentRule) -> bool {
+    first.parent.surely_overlaps(&second.parent)
+        && Children::of(first).surely_overlaps(&Children::of(second))
+}
+
+/// Statically known children an indent rule applies to.
+enum Children<'a> {
+    All,
+    Only(&'a HashSet<SyntaxKind>),
+    /// At least all kinds but these.
+    Except(&'a HashSet<SyntaxKind>),
+    Unknown,
+}
+
+impl<'a> Children<'a> {
+    fn of(rule: &'a IndentRule) -> Children<'a> {
+        let child = match &rule.child {
+            None => return Children::All,
+            Some(it) => it,
+        };
+        match rule.child_modality {
+            Modality::Positive => child.exact_kinds().map_or(Children::Unknown, Children::Only),
+            Modality::Negative => child.kinds().map_or(Children::Unknown, Children::Except),
+        }
+    }
+
+    fn surely_overlaps(&self, other: &Children) -> bool {
+        match (self, other) {
+            (Children::Unknown, _) | (_, Children::Unknown) => false,
+            (Children::Only(lhs), Children::Only(rhs)) => !lhs.is_disjoint(rhs),
+            (Children::Only(only), Children::Except(except))
+            | (Children::Except(except), Children::Only(only)) => !only.is_subset(except),
+            (Children::Only(it), Children::All) | (Children::All, Children::Only(it)) => {
+                !it.is_empty()
+            }
+            (Children::All, _) | (Children::Except(_), _) => true,
+        }
+    }
+}
@@ -2,7 +2,7 @@ use std::{cmp::min, convert::TryFrom};
 
 use rnix::{
     NodeOrToken, SyntaxElement,
-    SyntaxKind::{NODE_STRING, TOKEN_COMMENT, TOKEN_STRING_CONTENT, TOKEN_WHITESPACE},
+    SyntaxKind::{NODE_STRING, TOKEN_COMMENT, TOKEN_STRING_CONTENT, TOKEN_URI, TOKEN_WHITESPACE},
     SyntaxNode, SyntaxToken, TextRange, TextSize,
 };
 
@@ -13,21 +13,39 @@ use crate::{
         BlockPosition, FmtModel,
     },
     pattern::{Pattern, PatternSet},
-    AtomEdit,
+    rules::{
+        COMMENT_SINGLE_LINE_VALUE, COMMENT_TRAILING_WHITESPACE, INDENT_BLOCK_COMMENTS,
+        INDENT_STRINGS, QUOTE_URLS,
+    },
+    AtomEdit, FormatOptions,
 };
 
-pub(super) fn fix(element: SyntaxElement, model: &mut FmtModel, anchor_set: &PatternSet<&Pattern>) {
+pub(super) fn fix(
+    element: SyntaxElement,
+    model: &mut FmtModel,
+    anchor_set: &PatternSet<&Pattern>,
+    options: &FormatOptions,
+) {
     match element {
         NodeOrToken::Node(node) => {
             if let NODE_STRING = node.kind() {
-                fix_string_indentation(&node, model, anchor_set)
+                if options.is_rule_enabled(INDENT_STRINGS.id()) {
+                    fix_string_indentation(&node, model, anchor_set);
+                }
             }
         }
-        NodeOrToken::Token(token) => {
-            if let TOKEN_COMMENT = token.kind() {
-                fix_comment_indentation(&token, model, anchor_set)
+        NodeOrToken::Token(token) => match token.kind() {
+            TOKEN_COMMENT => {
+                fix_comment_indentation(&token, model, anchor_set, options);
+                if options.is_rule_enabled(COMMENT_TRAILING_WHITESPACE.id()) {
+                    fix_comment_trailing_whitespace(&token, model)
+                }
             }
-        }
+            TOKEN_URI if options.quote_urls && options.is_rule_enabled(QUOTE_URLS.id()) => {
+                fix_uri(&token, model)
+            }
+            _ => (),
+        },
     }
 }
 
@@ -77,12 +95,15 @@ fn fix_string_indentation(
     if content_indent != IndentLevel::from_len(common_indent) {
         for &range in content_ranges.iter() {
             let delete = TextRange::at(range.start(), min(common_indent, range.len()));
-            model.raw_edit(AtomEdit { delete, insert: content_indent.into() })
+            model.raw_edit(AtomEdit { delete, insert: content_indent.into() }, Some(INDENT_STRINGS))
         }
     }
 
     if last_line_is_blank && last_indent.len() != quote_indent.len() {
-        model.raw_edit(AtomEdit { delete: *last_indent, insert: quote_indent.into() })
+        model.raw_edit(
+            AtomEdit { delete: *last_indent, insert: quote_indent.into() },
+            Some(INDENT_STRINGS),
+        )
     }
 }
 
@@ -91,6 +112,7 @@ fn fix_comment_indentation(
     token: &SyntaxToken,
     model: &mut FmtModel,
     anchor_set: &PatternSet<&Pattern>,
+    options: &FormatOptions,
 ) {
     let is_block_comment = token.text().starts_with("/*");
     let normal_indent = match indent_anchor(&token.clone().into(), model, anchor_set) {
@@ -99,7 +121,12 @@ fn fix_comment_indentation(
     };
     let block = model.block_for(&token.clone().into(), BlockPosition::Before);
     if !is_block_comment {
-        single_line_comment_indent(token, model, anchor_set);
+        if options.is_rule_enabled(COMMENT_SINGLE_LINE_VALUE.id()) {
+            single_line_comment_indent(token, model, anchor_set);
+        }
+        return;
+    }
+    if !options.is_rule_enabled(INDENT_BLOCK_COMMENTS.id()) {
         return;
     }
 
@@ -112,6 +139,9 @@ fn fix_comment_indentation(
     };
 
     let content_indent = comment_indent.indent();
+    // Lines holding only whitespace are emptied by the trailing whitespace
+    // fix, so indenting them as well would produce overlapping edits.
+    let strip_blank_lines = options.is_rule_enabled(COMMENT_TRAILING_WHITESPACE.id());
     let mut curr_offset = token.text_range().start();
     let mut first = true;
     for line in token.text().lines() {
@@ -121,6 +151,9 @@ fn fix_comment_indentation(
             first = false;
             continue;
         }
+        if strip_blank_lines && line.trim_matches(&[' ', '\t'][..]).is_empty() {
+            continue;
+        }
         let last_line_only_end_block = line.ends_with("*/") || line.trim_start() == "*/";
         let start_with_asterisk = line.trim_start().starts_with("*");
         let current_indent = IndentLevel::get_whitespace_block(line);
@@ -128,20 +161,52 @@ fn fix_comment_indentation(
             let delete =
                 TextRange::at(offset, TextSize::try_from(ws_end).expect("woah big number"));
             if last_line_only_end_block || start_with_asterisk {
-                model.raw_edit(AtomEdit {
-                    delete,
-                    insert: comment_indent.add_alignment(current_indent).into(),
-                })
+                model.raw_edit(
+                    AtomEdit {
+                        delete,
+                        insert: comment_indent.add_alignment(current_indent).into(),
+                    },
+                    Some(INDENT_BLOCK_COMMENTS),
+                )
             } else {
-                model.raw_edit(AtomEdit {
-                    delete,
-                    insert: content_indent.adjust_alignment(current_indent).into(),
-                })
+                model.raw_edit(
+                    AtomEdit {
+                        delete,
+                        insert: content_indent.adjust_alignment(current_indent).into(),
+                    },
+                    Some(INDENT_BLOCK_COMMENTS),
+                )
             }
         }
     }
 }
 
+/// Removes trailing whitespace from each line of a comment.
+fn fix_comment_trailing_whitespace(token: &SyntaxToken, model: &mut FmtModel) {
+    let mut offset = token.text_range().start();
+    for line in token.text().split('\n') {
+        let trimmed = line.trim_end_matches(&[' ', '\t'][..]);
+        if trimmed.len() != line.len() {
+            let delete =
+                TextRange::new(offset + TextSize::of(trimmed), offset + TextSize::of(line));
+            model
+                .raw_edit(AtomEdit { delete, insert: "".into() }, Some(COMMENT_TRAILING_WHITESPACE))
+        }
+        offset += TextSize::of(line) + TextSize::of('\n');
+    }
+}
+
+/// URL literals are deprecated (RFC 45), so we turn them into plain strings.
+///
+/// The characters allowed in a URL literal never need escaping inside of a
+/// string, so it's enough to wrap the literal in quotes.
+fn fix_uri(token: &SyntaxToken, model: &mut FmtModel) {
+    model.raw_edit(
+        AtomEdit { delete: token.text_range(), insert: format!("\"{}\"", token.text()).into() },
+        Some(QUOTE_URLS),
+    )
+}
+
 /// For indented string like
 ///
 /// ```nix
@@ -153,7 +218,7 @@ fn fix_comment_indentation(
 ///
 /// returns the ranges, corresponding to indentation. That is `"  "` before
 /// hello, `"    "` before world and `""` before the last `''`.
-fn node_indent_ranges(indented_string: &SyntaxNode) -> impl Iterator<Item = TextRange> {
+pub(crate) fn node_indent_ranges(indented_string: &SyntaxNode) -> impl Iterator<Item = TextRange> {
     indented_string
         .descendants_with_tokens()
         .filter_map(|it| it.into_token())
@@ -2,12 +2,20 @@ use std::collections::HashMap;
 
 use rnix::{
     NodeOrToken, SyntaxElement,
-    SyntaxKind::{NODE_ROOT, TOKEN_COMMENT, TOKEN_WHITESPACE},
-    SyntaxNode, SyntaxToken, TextRange, TextSize,
+    SyntaxKind::{
+        NODE_ATTR_SET, NODE_LET_IN, NODE_LIST, NODE_ROOT, TOKEN_COMMENT, TOKEN_WHITESPACE,
+    },
+    SyntaxNode, SyntaxToken, TextRange, TextSize, T,
 };
 use smol_str::SmolStr;
 
-use crate::{dsl::RuleName, engine::FmtDiff, tree_utils::preceding_tokens, AtomEdit};
+use crate::{
+    dsl::RuleName,
+    engine::{indentation::IndentDecision, FmtDiff},
+    rules::{LIMIT_BLANK_LINES, TRIM_BLANK_LINES},
+    tree_utils::{preceding_tokens, walk_tokens},
+    AtomEdit,
+};
 
 /// `FmtModel` is a data structure to which we apply formatting rules.
 ///
@@ -38,7 +46,11 @@ pub(super) struct FmtModel {
     /// offset.
     by_end_offset: HashMap<TextSize, usize>,
     /// Arbitrary non-whitespace edits created by the last formatter phase.
-    fixes: Vec<AtomEdit>,
+    fixes: Vec<(AtomEdit, Option<RuleName>)>,
+    /// Whether rules should record how they matched with `SpaceBlock::explain`.
+    pub(super) verbose: bool,
+    /// The indents computed for the elements which start a line, if asked for.
+    pub(super) indent_decisions: Option<Vec<IndentDecision>>,
 }
 
 #[derive(Debug)]
@@ -57,6 +69,8 @@ pub(super) struct SpaceBlock {
 struct SpaceChange {
     new_text: SmolStr,
     reason: Option<RuleName>,
+    /// How the `reason` rule matched, for verbose explanations.
+    details: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -104,7 +118,18 @@ impl SpaceBlock {
         self.change = match &self.original {
             OriginalSpace::Some(token) if token.text() == text => None,
             OriginalSpace::None { .. } if text.is_empty() => None,
-            _ => Some(SpaceChange { new_text: text.into(), reason: rule }),
+            _ => Some(SpaceChange { new_text: text.into(), reason: rule, details: None }),
+        }
+    }
+    /// Records how `rule` matched, if the block was last changed by `rule`.
+    ///
+    /// If several parts of the same rule change the block, the details of the
+    /// last part which changed the text are kept.
+    pub(super) fn explain(&mut self, rule: RuleName, details: String) {
+        if let Some(change) = &mut self.change {
+            if change.reason == Some(rule) && change.details.is_none() {
+                change.details = Some(details)
+            }
         }
     }
     pub(super) fn text(&self) -> &str {
@@ -122,6 +147,35 @@ impl SpaceBlock {
     pub(super) fn has_newline(&self) -> bool {
         self.text().contains('\n')
     }
+    /// Removes blank lines, so that at most `max` of them are left.
+    ///
+    /// The whitespace at the start of the next line is preserved.
+    fn limit_blank_lines(&mut self, max: usize, rule: Option<RuleName>) {
+        let text = self.text();
+        let newlines = text.matches('\n').count();
+        if newlines <= max + 1 {
+            return;
+        }
+        let indent = &text[text.rfind('\n').unwrap() + 1..];
+        let new_text = format!("{}{}", "\n".repeat(max + 1), indent);
+        self.set_text(&new_text, rule)
+    }
+    /// Checks if the block directly follows an opening delimiter or precedes
+    /// a closing one, like in `{\n\n  foo = bar;\n\n}`.
+    fn is_at_delimiter(&self) -> bool {
+        let token = match &self.original {
+            OriginalSpace::Some(token) => token,
+            OriginalSpace::None { .. } => return false,
+        };
+        let (open, close) = match token.parent().kind() {
+            NODE_ATTR_SET => (T!["{"], T!["}"]),
+            NODE_LIST => (T!["["], T!["]"]),
+            NODE_LET_IN => (T![let], T![in]),
+            _ => return false,
+        };
+        token.prev_sibling_or_token().map(|it| it.kind()) == Some(open)
+            || token.next_sibling_or_token().map(|it| it.kind()) == Some(close)
+    }
 }
 
 pub(super) enum SpaceBlockOrToken<'a> {
@@ -137,17 +191,27 @@ impl FmtModel {
             by_start_offset: HashMap::default(),
             by_end_offset: HashMap::default(),
             fixes: vec![],
+            verbose: false,
+            indent_decisions: None,
         }
     }
 
     pub(super) fn into_diff(self) -> FmtDiff {
-        let mut diff = FmtDiff { original_node: self.original_node.to_owned(), edits: vec![] };
+        let mut diff = FmtDiff {
+            original_node: self.original_node.to_owned(),
+            edits: vec![],
+            details: HashMap::default(),
+        };
         for block in self.blocks {
             if let Some(change) = block.change {
-                diff.replace(block.original.text_range(), change.new_text, change.reason);
+                let range = block.original.text_range();
+                if let Some(details) = change.details {
+                    diff.details.insert(range, details);
+                }
+                diff.replace(range, change.new_text, change.reason);
             }
         }
-        diff.edits.extend(self.fixes.into_iter().map(|edit| (edit, None)));
+        diff.edits.extend(self.fixes);
         diff
     }
 
@@ -263,8 +327,38 @@ impl FmtModel {
         }
     }
 
-    pub(super) fn raw_edit(&mut self, edit: AtomEdit) {
-        self.fixes.push(edit)
+    /// Limits the number of consecutive blank lines to `max_blank_lines`, and,
+    /// if `trim_delimiters` is set, removes blank lines right after opening and
+    /// before closing delimiters of attribute sets, lists and `let` blocks.
+    ///
+    /// This affects every whitespace token in `node`, including the ones in
+    /// string interpolations, which the other rules skip. The content of
+    /// strings is never touched.
+    pub(super) fn normalize_blank_lines(
+        &mut self,
+        node: &SyntaxNode,
+        max_blank_lines: Option<usize>,
+        trim_delimiters: bool,
+    ) {
+        for token in walk_tokens(node).filter(|it| it.kind() == TOKEN_WHITESPACE) {
+            if let Some(next) = token.next_sibling_or_token() {
+                self.block_for(&next, BlockPosition::Before);
+            } else if let Some(prev) = token.prev_sibling_or_token() {
+                self.block_for(&prev, BlockPosition::After);
+            }
+        }
+        for block in self.blocks.iter_mut() {
+            if trim_delimiters && block.is_at_delimiter() {
+                block.limit_blank_lines(0, Some(TRIM_BLANK_LINES))
+            }
+            if let Some(max) = max_blank_lines {
+                block.limit_blank_lines(max, Some(LIMIT_BLANK_LINES))
+            }
+        }
+    }
+
+    pub(super) fn raw_edit(&mut self, edit: AtomEdit, reason: Option<RuleName>) {
+        self.fixes.push((edit, reason))
     }
 
     fn push_block(&mut self, block: SpaceBlock) -> &mut SpaceBlock {
@@ -10,7 +10,9 @@ use crate::{
     dsl::{IndentRule, Modality, RuleName},
     engine::{BlockPosition, FmtModel, SpaceBlock, SpaceBlockOrToken},
     pattern::{Pattern, PatternSet},
+    rules::{COMMENT_SINGLE_LINE_VALUE, PRESERVE_INDENTATION},
     tree_utils::prev_non_whitespace_token_sibling,
+    FormatOptions,
 };
 
 const INDENT_SIZE: u32 = 2;
@@ -131,6 +133,14 @@ impl IndentLevel {
         (self.level * INDENT_SIZE + self.alignment).into()
     }
 
+    pub(super) fn level(self) -> u32 {
+        self.level
+    }
+
+    pub(super) fn alignment(self) -> u32 {
+        self.alignment
+    }
+
     fn as_short_str(self) -> Option<&'static str> {
         #[rustfmt::skip]
         const SPACES: &str =
@@ -144,6 +154,16 @@ impl IndentLevel {
     }
 }
 
+/// How an element which starts a line got its indent.
+#[derive(Debug)]
+pub(super) struct IndentDecision {
+    pub(super) element: SyntaxElement,
+    pub(super) rule: RuleName,
+    /// The anchor from `indent_anchor`, if any.
+    pub(super) anchor: Option<SyntaxNode>,
+    pub(super) indent: IndentLevel,
+}
+
 impl IndentRule {
     pub(super) fn matches(&self, element: &SyntaxElement) -> bool {
         let parent = match element.parent() {
@@ -165,25 +185,54 @@ impl IndentRule {
         element: &SyntaxElement,
         model: &mut FmtModel,
         anchor_set: &PatternSet<&Pattern>,
+        options: &FormatOptions,
     ) {
         debug_assert!(self.matches(element));
-        let anchor_indent = match indent_anchor(element, model, anchor_set) {
+        let anchor = indent_anchor(element, model, anchor_set);
+        let anchor_indent = match &anchor {
             Some((anchor, indent)) => {
                 if let Some(p) = &self.anchor_pattern {
-                    if !p.matches(&anchor.into()) {
-                        default_indent(element, model, anchor_set);
+                    if !p.matches(&anchor.clone().into()) {
+                        default_indent(element, model, anchor_set, options);
                         return;
                     }
                 }
-                indent
+                *indent
             }
             _ => IndentLevel::default(),
         };
+        let indent = anchor_indent.indent();
+        let verbose = model.verbose;
+        model.record_indent(element, self.name.clone(), &anchor, indent);
         let block = model.block_for(element, BlockPosition::Before);
-        block.set_indent(anchor_indent.indent(), self.name);
+        block.set_indent(indent, self.name.clone());
+        if verbose {
+            let mut details = format!("inside {:?}", self.parent);
+            if let Some(child) = &self.child {
+                let modality = match self.child_modality {
+                    Modality::Positive => "matching",
+                    Modality::Negative => "not matching",
+                };
+                details.push_str(&format!(", {} {:?}", modality, child));
+            }
+            if let Some(anchor_pattern) = &self.anchor_pattern {
+                details.push_str(&format!(", anchor matching {:?}", anchor_pattern));
+            }
+            details.push_str(&format!(", {}", explain_indent(anchor.as_ref(), indent)));
+            block.explain(self.name.clone(), details);
+        }
     }
 }
 
+/// Describes the anchor from `indent_anchor` and the indent computed from it.
+fn explain_indent(anchor: Option<&(SyntaxNode, IndentLevel)>, indent: IndentLevel) -> String {
+    let anchor = match anchor {
+        Some((node, anchor_indent)) => format!("anchor {:?} at {:?}", node, anchor_indent),
+        None => "no anchor".to_string(),
+    };
+    format!("{}, indent {:?}", anchor, indent)
+}
+
 impl SpaceBlock {
     fn set_indent(&mut self, indent: IndentLevel, rule: RuleName) {
         let newlines: String = self.text().chars().filter(|&it| it == '\n').collect();
@@ -199,13 +248,23 @@ pub(super) fn default_indent(
     element: &SyntaxElement,
     model: &mut FmtModel,
     anchor_set: &PatternSet<&Pattern>,
+    options: &FormatOptions,
 ) {
-    let anchor_indent = match indent_anchor(element, model, anchor_set) {
-        Some((_anchor, indent)) => indent,
+    if !options.is_rule_enabled(PRESERVE_INDENTATION.id()) {
+        return;
+    }
+    let anchor = indent_anchor(element, model, anchor_set);
+    let anchor_indent = match &anchor {
+        Some((_anchor, indent)) => *indent,
         _ => IndentLevel::default(),
     };
+    let verbose = model.verbose;
+    model.record_indent(element, PRESERVE_INDENTATION, &anchor, anchor_indent);
     let block = model.block_for(element, BlockPosition::Before);
-    block.set_indent(anchor_indent, RuleName::new("Preserve indentation"));
+    block.set_indent(anchor_indent, PRESERVE_INDENTATION);
+    if verbose {
+        block.explain(PRESERVE_INDENTATION, explain_indent(anchor.as_ref(), anchor_indent));
+    }
 }
 
 pub(super) fn single_line_comment_indent(
@@ -223,7 +282,7 @@ pub(super) fn single_line_comment_indent(
         .map(|e| e.kind() == TOKEN_IN)
         .unwrap_or(false);
     if prev_is_token_in {
-        block.set_indent(anchor_indent, RuleName::new("Comment Single Line Value"));
+        block.set_indent(anchor_indent, COMMENT_SINGLE_LINE_VALUE);
         return;
     }
     return;
@@ -260,6 +319,24 @@ pub(super) fn indent_anchor(
 }
 
 impl FmtModel {
+    /// Remembers the indent of `element` if `indent_decisions` are collected.
+    fn record_indent(
+        &mut self,
+        element: &SyntaxElement,
+        rule: RuleName,
+        anchor: &Option<(SyntaxNode, IndentLevel)>,
+        indent: IndentLevel,
+    ) {
+        if let Some(decisions) = &mut self.indent_decisions {
+            decisions.push(IndentDecision {
+                element: element.clone(),
+                rule,
+                anchor: anchor.as_ref().map(|(node, _)| node.clone()),
+                indent,
+            })
+        }
+    }
+
     /// Calculates current indent level for node.
     fn indent_of(&mut self, node: &SyntaxNode) -> IndentLevel {
         // The impl is tricky: we need to account for whitespace in `model`, which
@@ -11,25 +11,36 @@ impl SpacingRule {
         if !self.pattern.matches(element) {
             return;
         }
+        let verbose = model.verbose;
         if self.space.loc.is_before() {
             let block = model.block_for(element, BlockPosition::Before);
-            ensure_space(element, block, self.space.value, self.name);
+            ensure_space(element, block, self.space.value, Some(self.name.clone()));
+            if verbose {
+                block.explain(self.name.clone(), self.details());
+            }
         }
         if self.space.loc.is_after() {
             let block = model.block_for(element, BlockPosition::After);
-            ensure_space(element, block, self.space.value, self.name);
+            ensure_space(element, block, self.space.value, Some(self.name.clone()));
+            if verbose {
+                block.explain(self.name.clone(), self.details());
+            }
         }
     }
+
+    fn details(&self) -> String {
+        format!("{:?}, {:?} {:?}", self.pattern, self.space.value, self.space.loc)
+    }
 }
 
 impl SpaceLoc {
-    fn is_before(self) -> bool {
+    pub(super) fn is_before(self) -> bool {
         match self {
             SpaceLoc::Before | SpaceLoc::Around => true,
             SpaceLoc::After => false,
         }
     }
-    fn is_after(self) -> bool {
+    pub(super) fn is_after(self) -> bool {
         match self {
             SpaceLoc::After | SpaceLoc::Around => true,
             SpaceLoc::Before => false,
@@ -56,6 +67,12 @@ fn ensure_space(
                 block.set_text("\n", rule_name)
             }
         }
+        SpaceValue::BlankLine => {
+            // The indentation after the last newline is fixed up later
+            if block.text().matches('\n').count() != 2 {
+                block.set_text("\n\n", rule_name)
+            }
+        }
         SpaceValue::None => block.set_text("", rule_name),
         SpaceValue::NoneOptionalNewline => {
             if !block.has_newline() {
@@ -65,7 +82,7 @@ fn ensure_space(
         SpaceValue::SingleOrNewline => {
             let parent_is_multiline = element.parent().map_or(false, |it| has_newline(&it));
             if parent_is_multiline {
-                block.set_line_break_preserving_existing_newlines(None)
+                block.set_line_break_preserving_existing_newlines(rule_name)
             } else {
                 block.set_text(" ", rule_name)
             }
@@ -73,7 +90,7 @@ fn ensure_space(
         SpaceValue::NoneOrNewline => {
             let parent_is_multiline = element.parent().map_or(false, |it| has_newline(&it));
             if parent_is_multiline {
-                block.set_line_break_preserving_existing_newlines(None)
+                block.set_line_break_preserving_existing_newlines(rule_name)
             } else {
                 block.set_text("", rule_name)
             }
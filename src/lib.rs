@@ -2,16 +2,31 @@
 mod dsl;
 mod engine;
 mod rules;
+mod rules_file;
+mod lints;
 mod tree_utils;
-mod pattern;
+pub mod pattern;
+mod query;
+mod rewrite;
 
-use std::{borrow::Cow, fmt, fmt::Formatter};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
-use engine::ExtraInfo;
-use rnix::{SyntaxNode, TextRange, TextSize};
+use engine::{ExplainedEdit, ExtraInfo};
+use rnix::{SyntaxKind, SyntaxNode, TextRange, TextSize};
 use smol_str::SmolStr;
 
 use crate::dsl::RuleName;
+pub use crate::{
+    dsl::{IndentDsl, IndentRuleBuilder, IndentValue, SpacingDsl, SpacingRuleBuilder},
+    pattern::{p, Cond, Nav, Part, Pattern},
+    query::{child_roles, Query},
+    rewrite::Rewrite,
+    rules_file::{parse_rules_file, CustomRule, RulesFileError},
+};
 
 /// The result of formatting.
 ///
@@ -21,6 +36,9 @@ use crate::dsl::RuleName;
 pub(crate) struct FmtDiff {
     original_node: SyntaxNode,
     edits: Vec<(AtomEdit, Option<RuleName>)>,
+    /// How the rules matched, by the range of their edits, if asked for with
+    /// `FmtModel::verbose`.
+    details: HashMap<TextRange, String>,
 }
 
 /// An edit where the `delete` range represents the range of the original text
@@ -32,7 +50,7 @@ pub struct AtomEdit {
 }
 
 impl fmt::Display for FmtDiff {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // TODO: don't copy strings all over the place
         let old_text = self.original_node.to_string();
 
@@ -68,6 +86,17 @@ impl FmtDiff {
         self.edits.iter().map(|(edit, _reason)| edit.clone()).collect()
     }
 
+    /// The edits together with the rules which caused them and the details of
+    /// how the rules matched.
+    pub(crate) fn explained_edits(&self) -> Vec<ExplainedEdit> {
+        self.edits
+            .iter()
+            .map(|(edit, reason)| {
+                (edit.clone(), reason.clone(), self.details.get(&edit.delete).cloned())
+            })
+            .collect()
+    }
+
     /// Whether or not formatting did caused any changes
     pub(crate) fn has_changes(&self) -> bool {
         !self.edits.is_empty()
@@ -83,22 +112,113 @@ impl FmtDiff {
     }
 }
 
+/// Options which tweak the behavior of the formatter.
+///
+/// `FormatOptions::default()` corresponds to the nixpkgs style. As options
+/// are added over time, start from the default and set the fields you need,
+/// then pass the options to `FormatterBuilder::options`:
+///
+/// ```
+/// use nixpkgs_fmt::{FormatOptions, Formatter};
+///
+/// let mut options = FormatOptions::default();
+/// options.quote_urls = false;
+/// let formatter = Formatter::builder().options(options).build();
+/// assert_eq!(formatter.reformat_string("https://nixos.org"), "https://nixos.org\n");
+/// ```
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FormatOptions {
+    /// Rewrite URL literals (deprecated by RFC 45) into string literals, so
+    /// that `https://example.com` becomes `"https://example.com"`.
+    pub quote_urls: bool,
+    /// Placement of commas in multi-line lambda patterns. By default, the
+    /// commas stay where they are, and are spaced like `CommaStyle::Leading`.
+    pub comma_style: Option<CommaStyle>,
+    /// The maximal number of consecutive blank lines. By default, blank lines
+    /// are left as is.
+    pub max_blank_lines: Option<usize>,
+    /// Remove blank lines right after opening and before closing delimiters of
+    /// attribute sets, lists and `let` blocks.
+    pub trim_blank_lines: bool,
+    /// Put exactly one blank line between adjacent bindings if either of them
+    /// spans several lines.
+    pub separate_multiline_bindings: bool,
+    /// Make `lint` report the whitespace-only lines of `''` strings, where
+    /// removing the whitespace wouldn't change the value of the string. The
+    /// formatter itself never changes string content.
+    pub report_string_whitespace: bool,
+    /// IDs of the rules which are not applied, see `rule_registry`.
+    pub disabled_rules: Vec<String>,
+    /// If set, only the rules with these IDs are applied.
+    pub only_rules: Option<Vec<String>>,
+    /// Additional spacing rules, usually loaded with `parse_rules_file`.
+    pub custom_rules: Vec<CustomRule>,
+}
+
+impl FormatOptions {
+    /// Checks if the rule with the given ID should be applied. Note that some
+    /// rules also need to be turned on by other options.
+    pub fn is_rule_enabled(&self, id: &str) -> bool {
+        let selected = match &self.only_rules {
+            Some(only_rules) => only_rules.iter().any(|it| it == id),
+            None => true,
+        };
+        selected && !self.disabled_rules.iter().any(|it| it == id)
+    }
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions {
+            quote_urls: true,
+            comma_style: None,
+            max_blank_lines: None,
+            trim_blank_lines: false,
+            separate_multiline_bindings: false,
+            report_string_whitespace: false,
+            disabled_rules: Vec::new(),
+            only_rules: None,
+            custom_rules: Vec::new(),
+        }
+    }
+}
+
+/// Where to put the commas which separate the arguments of a multi-line lambda
+/// pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommaStyle {
+    /// The nixpkgs style:
+    ///
+    /// ```nix
+    /// { stdenv
+    /// , fetchurl
+    /// }:
+    /// ```
+    Leading,
+    /// ```nix
+    /// {
+    ///   stdenv,
+    ///   fetchurl,
+    /// }:
+    /// ```
+    Trailing,
+}
+
 pub fn reformat_node(node: &SyntaxNode) -> SyntaxNode {
-    let spacing = rules::spacing();
-    let indentation = rules::indentation();
-    engine::reformat(&spacing, &indentation, node, ExtraInfo::None)
+    reformat_node_with_options(node, &FormatOptions::default())
+}
+
+pub fn reformat_node_with_options(node: &SyntaxNode, options: &FormatOptions) -> SyntaxNode {
+    Formatter::builder().options(options.clone()).build().reformat_node(node)
 }
 
 pub fn reformat_string(text: &str) -> String {
-    let (text, line_endings) = convert_to_unix_line_endings(text);
+    reformat_string_with_options(text, &FormatOptions::default())
+}
 
-    let ast = rnix::parse(&*text);
-    let root_node = ast.node();
-    let res = reformat_node(&root_node).to_string();
-    match line_endings {
-        LineEndings::Unix => res,
-        LineEndings::Dos => convert_to_dos_line_endings(res),
-    }
+pub fn reformat_string_with_options(text: &str, options: &FormatOptions) -> String {
+    Formatter::builder().options(options.clone()).build().reformat_string(text)
 }
 
 /// Returns the edits that must be applied to `node` in order to reformat it.
@@ -110,61 +230,563 @@ pub fn reformat_string(text: &str) -> String {
 /// refer to positions in the document **after the spacing edits have been
 /// applied**.
 pub fn reformat_edits(node: &SyntaxNode) -> (Vec<AtomEdit>, Vec<AtomEdit>) {
-    let spacing = rules::spacing();
-    let indentation = rules::indentation();
+    Formatter::builder().build().reformat_edits(node)
+}
+
+/// Returns the edits that must be applied to `text` in order to reformat it,
+/// as a single transaction: unlike with `reformat_edits`, all the ranges refer
+/// to `text` itself.
+pub fn text_edits(text: &str, options: &FormatOptions) -> Vec<AtomEdit> {
+    Formatter::builder().options(options.clone()).build().text_edits(text)
+}
 
-    let (mut spacing_edits, mut indent_edits) = (Vec::new(), Vec::new());
-    engine::reformat(
-        &spacing,
-        &indentation,
-        node,
-        ExtraInfo::Edits { spacing_edits: &mut spacing_edits, indent_edits: &mut indent_edits },
-    );
-    spacing_edits.sort_by(|a, b| a.delete.start().cmp(&b.delete.start()));
-    indent_edits.sort_by(|a, b| a.delete.start().cmp(&b.delete.start()));
-    (spacing_edits, indent_edits)
+/// Applies the `rewrites` to `text` one after the other, and formats the
+/// rewritten code, leaving the rest of the text as is. Fails if the result has
+/// more syntax errors than `text`.
+pub fn rewrite_string(
+    text: &str,
+    rewrites: &[Rewrite],
+    options: &FormatOptions,
+) -> Result<String, String> {
+    Formatter::builder().options(options.clone()).build().rewrite_string(text, rewrites)
+}
+
+/// Checks `text` for the conventions of nixpkgs which the formatter can't
+/// fix, see `LintWarning`.
+pub fn lint(text: &str, options: &FormatOptions) -> Vec<LintWarning> {
+    Formatter::builder().options(options.clone()).build().lint(text)
 }
 
 pub fn explain(text: &str) -> String {
-    let (text, _line_endings) = convert_to_unix_line_endings(text);
-    let ast = rnix::parse(&*text);
-    let spacing = rules::spacing();
-    let indentation = rules::indentation();
-    let mut explanation = Vec::new();
-    engine::reformat(&spacing, &indentation, &ast.node(), ExtraInfo::Explanation(&mut explanation));
-
-    let mut buf = String::new();
-    let mut line_start: TextSize = 0.into();
-    for line in text.to_string().lines() {
-        let line_len = TextSize::of(line) + TextSize::of("\n");
-        let line_range = TextRange::at(line_start, line_len);
-
-        buf.push_str(line);
-        let mut first = true;
-        for (edit, reason) in explanation.iter() {
-            if line_range.contains(edit.delete.end()) {
-                if first {
-                    first = false;
-                    buf.push_str("  # ")
-                } else {
-                    buf.push_str(", ")
-                }
-                buf.push_str(&format!(
-                    "[{}; {}): ",
-                    usize::from(edit.delete.start()),
-                    usize::from(edit.delete.end())
-                ));
-                match reason {
-                    Some(reason) => buf.push_str(&reason.to_string()),
-                    None => buf.push_str("unnamed rule"),
+    explain_with_options(text, &FormatOptions::default())
+}
+
+pub fn explain_with_options(text: &str, options: &FormatOptions) -> String {
+    Formatter::builder().options(options.clone()).build().explain(text)
+}
+
+/// Like `explain_with_options`, but also shows how each rule matched, see
+/// `Formatter::explain_verbose`.
+pub fn explain_verbose(text: &str, options: &FormatOptions) -> String {
+    Formatter::builder().options(options.clone()).build().explain_verbose(text)
+}
+
+/// Annotates each line of `text` with how it is indented, see `IndentInfo`.
+pub fn debug_indent(text: &str, options: &FormatOptions) -> String {
+    Formatter::builder().options(options.clone()).build().debug_indent(text)
+}
+
+/// Like `debug_indent`, but renders an HTML page which shows the annotations
+/// when hovering over the lines.
+pub fn debug_indent_html(text: &str, options: &FormatOptions) -> String {
+    Formatter::builder().options(options.clone()).build().debug_indent_html(text)
+}
+
+/// Counts which rules would change `text`, see `RuleStats`.
+pub fn rule_stats(text: &str, options: &FormatOptions) -> RuleStats {
+    Formatter::builder().options(options.clone()).build().rule_stats(text)
+}
+
+/// A formatter with a custom set of rules, created with `Formatter::builder`.
+///
+/// This allows to maintain a Nix style on top of the nixpkgs one in a separate
+/// crate:
+///
+/// ```
+/// use nixpkgs_fmt::{Formatter, SpacingDsl};
+/// use rnix::{SyntaxKind::*, T};
+///
+/// let mut rules = SpacingDsl::default();
+/// rules
+///     .test("args @{ a }: a", "args@{ a }: a")
+///     .rule("no-space-before-at", "No space before @ in lambda patterns")
+///     .inside(NODE_PAT_BIND).before(T![@]).no_space();
+///
+/// let formatter = Formatter::builder().with_rules(rules).unwrap().build();
+/// assert_eq!(formatter.reformat_string("args @{ a }: a"), "args@{ a }: a\n");
+/// ```
+#[derive(Debug)]
+pub struct Formatter {
+    options: FormatOptions,
+    spacing: SpacingDsl,
+    indentation: IndentDsl,
+}
+
+impl Formatter {
+    pub fn builder() -> FormatterBuilder {
+        FormatterBuilder::default()
+    }
+
+    pub fn reformat_node(&self, node: &SyntaxNode) -> SyntaxNode {
+        self.reformat_node_with_conflicts(node).0
+    }
+
+    /// Like `reformat_node`, but also returns the rules which matched the same
+    /// element while formatting `node`. Only the first of these rules has been
+    /// applied.
+    pub fn reformat_node_with_conflicts(
+        &self,
+        node: &SyntaxNode,
+    ) -> (SyntaxNode, Vec<RuleConflict>) {
+        engine::reformat(&self.spacing, &self.indentation, &self.options, node, ExtraInfo::None)
+    }
+
+    /// Finds the rules of this formatter which match the same elements,
+    /// without looking at any input. This is only possible for the rules
+    /// which depend on the kinds of elements and of their parents only, the
+    /// others are reported by `reformat_node_with_conflicts`.
+    pub fn conflicts(&self) -> Vec<RuleConflict> {
+        engine::find_conflicts(&self.spacing, &self.indentation)
+    }
+
+    pub fn reformat_string(&self, text: &str) -> String {
+        self.reformat_string_with_conflicts(text).0
+    }
+
+    /// Like `reformat_string`, but also returns the rules which matched the
+    /// same element, see `reformat_node_with_conflicts`.
+    pub fn reformat_string_with_conflicts(&self, text: &str) -> (String, Vec<RuleConflict>) {
+        let (text, line_endings) = convert_to_unix_line_endings(text);
+
+        let ast = rnix::parse(&*text);
+        let root_node = ast.node();
+        let (node, conflicts) = self.reformat_node_with_conflicts(&root_node);
+        let res = node.to_string();
+        let res = match line_endings {
+            LineEndings::Unix => res,
+            LineEndings::Dos => convert_to_dos_line_endings(res),
+        };
+        (res, conflicts)
+    }
+
+    /// Like the free-standing `reformat_edits`, but with the rules of this
+    /// formatter.
+    pub fn reformat_edits(&self, node: &SyntaxNode) -> (Vec<AtomEdit>, Vec<AtomEdit>) {
+        let (mut spacing_edits, mut indent_edits) = (Vec::new(), Vec::new());
+        engine::reformat(
+            &self.spacing,
+            &self.indentation,
+            &self.options,
+            node,
+            ExtraInfo::Edits { spacing_edits: &mut spacing_edits, indent_edits: &mut indent_edits },
+        );
+        spacing_edits.sort_by(|a, b| a.delete.start().cmp(&b.delete.start()));
+        indent_edits.sort_by(|a, b| a.delete.start().cmp(&b.delete.start()));
+        (spacing_edits, indent_edits)
+    }
+
+    /// Like the free-standing `text_edits`, but with the rules of this
+    /// formatter.
+    pub fn text_edits(&self, text: &str) -> Vec<AtomEdit> {
+        let (unix_text, line_endings) = convert_to_unix_line_endings(text);
+        let ast = rnix::parse(&unix_text);
+        let (spacing_edits, indent_edits) = self.reformat_edits(&ast.node());
+        let edits = engine::compose_edits(&unix_text, &spacing_edits, &indent_edits);
+        match line_endings {
+            LineEndings::Unix => edits,
+            LineEndings::Dos => convert_edits_to_dos_line_endings(&unix_text, edits),
+        }
+    }
+
+    /// Like the free-standing `rewrite_string`, but with the rules of this
+    /// formatter.
+    pub fn rewrite_string(&self, text: &str, rewrites: &[Rewrite]) -> Result<String, String> {
+        let (unix_text, line_endings) = convert_to_unix_line_endings(text);
+        let n_errors = rnix::parse(&unix_text).errors().len();
+
+        let mut res = unix_text.into_owned();
+        let mut changed = Vec::new();
+        for rewrite in rewrites {
+            let edits = rewrite.edits(&rnix::parse(&res).node());
+            changed = rewrite::apply_edits(&mut res, &edits, &changed);
+        }
+        if changed.is_empty() {
+            // Nothing matched, so the text is left exactly as it was.
+            return Ok(text.to_string());
+        }
+        let errors = rnix::parse(&res).errors();
+        if errors.len() > n_errors {
+            return Err(format!("the rewritten code does not parse: {}", errors[0]));
+        }
+
+        let edits = rewrite::edits_in_ranges(self.text_edits(&res), &changed);
+        rewrite::apply_edits(&mut res, &edits, &[]);
+        Ok(match line_endings {
+            LineEndings::Unix => res,
+            LineEndings::Dos => convert_to_dos_line_endings(res),
+        })
+    }
+
+    /// Like the free-standing `lint`, with the options of this formatter.
+    pub fn lint(&self, text: &str) -> Vec<LintWarning> {
+        lints::lint(&rnix::parse(text).node(), &self.options)
+    }
+
+    pub fn rule_stats(&self, text: &str) -> RuleStats {
+        let (text, _line_endings) = convert_to_unix_line_endings(text);
+        let ast = rnix::parse(&text);
+        let mut stats = RuleStats::default();
+        engine::reformat(
+            &self.spacing,
+            &self.indentation,
+            &self.options,
+            &ast.node(),
+            ExtraInfo::Stats(&mut stats),
+        );
+        stats
+    }
+
+    /// Describes how each element which starts a line is indented. Returns
+    /// the text the indentation was computed for, that is, `text` with the
+    /// spacing edits applied, as the lines of `IndentInfo` refer to it.
+    pub fn indent_info(&self, text: &str) -> (String, Vec<IndentInfo>) {
+        let (text, _line_endings) = convert_to_unix_line_endings(text);
+        let ast = rnix::parse(&text);
+        let (mut spaced_text, mut indents) = (String::new(), Vec::new());
+        engine::reformat(
+            &self.spacing,
+            &self.indentation,
+            &self.options,
+            &ast.node(),
+            ExtraInfo::Indents { text: &mut spaced_text, indents: &mut indents },
+        );
+        (spaced_text, indents)
+    }
+
+    pub fn debug_indent(&self, text: &str) -> String {
+        let (text, indents) = self.indent_info(text);
+        let mut buf = String::new();
+        for (idx, line) in text.lines().enumerate() {
+            buf.push_str(line);
+            let infos: Vec<String> =
+                indents.iter().filter(|it| it.line == idx + 1).map(|it| it.to_string()).collect();
+            if !infos.is_empty() {
+                buf.push_str("  # ");
+                buf.push_str(&infos.join(", "));
+            }
+            buf.push('\n');
+        }
+        buf
+    }
+
+    pub fn debug_indent_html(&self, text: &str) -> String {
+        let (text, indents) = self.indent_info(text);
+        let mut buf = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <style>span[title]:hover { background: #ffd; }</style>\n</head>\n<body>\n<pre>\n",
+        );
+        for (idx, line) in text.lines().enumerate() {
+            let line_number = idx + 1;
+            let infos: Vec<&IndentInfo> =
+                indents.iter().filter(|it| it.line == line_number).collect();
+            let line = html_escape(line);
+            if infos.is_empty() {
+                buf.push_str(&format!("<span id=\"L{}\">{}</span>\n", line_number, line));
+                continue;
+            }
+            let title: Vec<String> = infos.iter().map(|it| it.to_string()).collect();
+            buf.push_str(&format!(
+                "<span id=\"L{}\" title=\"{}\"",
+                line_number,
+                html_escape(&title.join("\n"))
+            ));
+            if let Some(anchor_line) = infos[0].anchor_line {
+                buf.push_str(&format!(" data-anchor=\"L{}\"", anchor_line));
+            }
+            buf.push_str(&format!(">{}</span>\n", line));
+        }
+        buf.push_str("</pre>\n</body>\n</html>\n");
+        buf
+    }
+
+    pub fn explain(&self, text: &str) -> String {
+        self.explain_impl(text, false)
+    }
+
+    /// Like `explain`, but also shows how each rule matched: the pattern of
+    /// the rule, and, for indentation, the anchor relative to which the
+    /// element is indented together with the resulting indent.
+    pub fn explain_verbose(&self, text: &str) -> String {
+        self.explain_impl(text, true)
+    }
+
+    fn explain_impl(&self, text: &str, verbose: bool) -> String {
+        let (text, _line_endings) = convert_to_unix_line_endings(text);
+        let ast = rnix::parse(&*text);
+        let mut explanation = Vec::new();
+        engine::reformat(
+            &self.spacing,
+            &self.indentation,
+            &self.options,
+            &ast.node(),
+            ExtraInfo::Explanation { explanation: &mut explanation, verbose },
+        );
+
+        let mut buf = String::new();
+        let mut line_start: TextSize = 0.into();
+        for line in text.to_string().lines() {
+            let line_len = TextSize::of(line) + TextSize::of("\n");
+            let line_range = TextRange::at(line_start, line_len);
+
+            buf.push_str(line);
+            let mut first = true;
+            for (edit, reason, details) in explanation.iter() {
+                if line_range.contains(edit.delete.end()) {
+                    if first {
+                        first = false;
+                        buf.push_str("  # ")
+                    } else {
+                        buf.push_str(", ")
+                    }
+                    buf.push_str(&format!(
+                        "[{}; {}): ",
+                        usize::from(edit.delete.start()),
+                        usize::from(edit.delete.end())
+                    ));
+                    match reason {
+                        Some(reason) => buf.push_str(&reason.to_string()),
+                        None => buf.push_str("unnamed rule"),
+                    }
+                    if let Some(details) = details {
+                        buf.push_str(&format!(" ({})", details))
+                    }
                 }
             }
+            buf.push('\n');
+
+            line_start += line_len;
         }
-        buf.push('\n');
+        buf
+    }
+}
+
+/// A builder for `Formatter`.
+#[derive(Debug, Default)]
+pub struct FormatterBuilder {
+    options: FormatOptions,
+    without_builtin_rules: bool,
+    spacing: Vec<SpacingDsl>,
+    indentation: Vec<IndentDsl>,
+}
+
+impl FormatterBuilder {
+    pub fn options(mut self, options: FormatOptions) -> FormatterBuilder {
+        self.options = options;
+        self
+    }
+
+    /// Adds spacing rules. They are applied after the built-in ones, so they
+    /// can override them.
+    ///
+    /// Fails if `inside` was called before the first `rule`, or if there are
+    /// examples after the last rule.
+    pub fn with_rules(mut self, rules: SpacingDsl) -> Result<FormatterBuilder, String> {
+        rules.check()?;
+        self.spacing.push(rules);
+        Ok(self)
+    }
+
+    /// Adds indentation rules. At most one indentation rule may match each
+    /// element, so these should not overlap with the built-in rules.
+    ///
+    /// Fails if there are examples after the last rule.
+    pub fn with_indent_rules(mut self, rules: IndentDsl) -> Result<FormatterBuilder, String> {
+        rules.check()?;
+        self.indentation.push(rules);
+        Ok(self)
+    }
+
+    /// Leaves out the built-in rules of the nixpkgs style, so that only the
+    /// rules added with `with_rules` and `with_indent_rules` are applied.
+    pub fn without_builtin_rules(mut self) -> FormatterBuilder {
+        self.without_builtin_rules = true;
+        self
+    }
+
+    pub fn build(self) -> Formatter {
+        let (mut spacing, mut indentation) = if self.without_builtin_rules {
+            (SpacingDsl::default(), IndentDsl::default())
+        } else {
+            (rules::spacing(&self.options), rules::indentation(&self.options))
+        };
+        for rules in self.spacing {
+            spacing.extend(rules)
+        }
+        rules::custom_spacing(&mut spacing, &self.options.custom_rules);
+        for rules in self.indentation {
+            indentation.extend(rules)
+        }
+        Formatter { options: self.options, spacing, indentation }
+    }
+}
+
+/// A formatting rule, as listed by `rule_registry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleInfo {
+    /// Stable identifier of the rule, like `space-before-eq`.
+    pub id: Cow<'static, str>,
+    /// Human readable description, which is also used by `explain`.
+    pub description: Cow<'static, str>,
+    pub kind: RuleKind,
+    /// Examples of the rule in action, as `(before, after)` pairs.
+    pub examples: Vec<(String, String)>,
+}
 
-        line_start += line_len;
+/// A violation of a lint rule, that is, of a rule of kind `RuleKind::Lint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintWarning {
+    pub rule: Cow<'static, str>,
+    pub description: Cow<'static, str>,
+    /// The element which violates the rule.
+    pub range: TextRange,
+}
+
+/// How often the rules fire on some code, to find the rules which are never
+/// used and the code which isn't covered by rules.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RuleStats {
+    /// The number of edits, by rule id.
+    pub edits: BTreeMap<Cow<'static, str>, usize>,
+    /// The number of edits made without naming a rule.
+    pub unnamed_edits: usize,
+    /// The number of elements which start a line, but match none of the
+    /// indentation rules, so that their indentation is preserved.
+    pub default_indent: usize,
+}
+
+impl RuleStats {
+    fn add_edits(&mut self, edits: &[(AtomEdit, Option<RuleName>)]) {
+        for (_edit, reason) in edits {
+            match reason {
+                Some(rule) => *self.edits.entry(rule.id.clone()).or_default() += 1,
+                None => self.unnamed_edits += 1,
+            }
+        }
     }
-    buf
+}
+
+/// How an element which starts a line is indented, as computed by the
+/// indentation phase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndentInfo {
+    /// The 1-based line of the element, in the text after spacing edits.
+    pub line: usize,
+    pub kind: SyntaxKind,
+    /// The id of the rule which indented the element. Elements which match no
+    /// rule keep the indent of their anchor, by `preserve-indentation`.
+    pub rule: Cow<'static, str>,
+    /// The kind of the node relative to which the element is indented, which
+    /// is usually the closest ancestor which starts a line.
+    pub anchor: Option<SyntaxKind>,
+    /// The 1-based line where the anchor starts.
+    pub anchor_line: Option<usize>,
+    /// The indent of the element, in levels of two spaces, plus the number of
+    /// spaces to align it with something else.
+    pub level: u32,
+    pub alignment: u32,
+}
+
+impl fmt::Display for IndentInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} by {}, ", self.kind, self.rule)?;
+        match (self.anchor, self.anchor_line) {
+            (Some(anchor), Some(line)) => write!(f, "anchor {:?} on line {}", anchor, line)?,
+            _ => f.write_str("no anchor")?,
+        }
+        write!(f, ", level {} + alignment {}", self.level, self.alignment)
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Two rules which apply to the same element, so that the result depends on
+/// their order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleConflict {
+    pub kind: RuleKind,
+    /// The rule which comes first. For indentation, this is the rule which is
+    /// applied, for spacing, it is overridden by `second`.
+    pub first: Cow<'static, str>,
+    pub second: Cow<'static, str>,
+    /// The element matched by both rules, if the conflict was found while
+    /// formatting. For spacing, the range refers to the text before spacing
+    /// edits, and for indentation, to the text after them.
+    pub range: Option<TextRange>,
+}
+
+impl RuleConflict {
+    fn new(kind: RuleKind, first: &RuleName, second: &RuleName, range: Option<TextRange>) -> Self {
+        RuleConflict { kind, first: first.id.clone(), second: second.id.clone(), range }
+    }
+}
+
+impl fmt::Display for RuleConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} rules `{}` and `{}`", self.kind, self.first, self.second)?;
+        match self.range {
+            Some(range) => write!(
+                f,
+                " both match [{}; {})",
+                usize::from(range.start()),
+                usize::from(range.end())
+            ),
+            None => write!(f, " overlap"),
+        }
+    }
+}
+
+/// The formatting phase a rule belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    /// Rules which adjust whitespace between elements.
+    Spacing,
+    /// Rules which adjust the whitespace at the start of a line.
+    Indentation,
+    /// Other touch-ups, like moving commas or quoting URLs.
+    Fix,
+    /// Checks which are reported by `lint`, but don't change the code.
+    Lint,
+}
+
+impl fmt::Display for RuleKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RuleKind::Spacing => "spacing",
+            RuleKind::Indentation => "indentation",
+            RuleKind::Fix => "fix",
+            RuleKind::Lint => "lint",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Returns all the formatting rules, in the order they are defined. This
+/// includes the rules which are enabled only with some `FormatOptions`.
+pub fn rule_registry() -> Vec<RuleInfo> {
+    rules::registry()
+}
+
+/// Resolves rule IDs and rule kinds, like `spacing`, into the IDs of the rules
+/// they select, for `FormatOptions::disabled_rules` and `only_rules`.
+pub fn resolve_rules<'a>(
+    selectors: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<String>, String> {
+    let registry = rule_registry();
+    let mut res = Vec::new();
+    for selector in selectors {
+        let n_rules = res.len();
+        for rule in registry.iter() {
+            if rule.id == selector || rule.kind.to_string() == selector {
+                res.push(rule.id.to_string())
+            }
+        }
+        if res.len() == n_rules {
+            return Err(format!("unknown rule: {}", selector));
+        }
+    }
+    Ok(res)
 }
 
 enum LineEndings {
@@ -183,9 +805,29 @@ fn convert_to_dos_line_endings(text: String) -> String {
     text.replace('\n', "\r\n")
 }
 
+/// Maps `edits` of `unix_text` back to the text with DOS line endings which it
+/// was converted from. Every `\n` before an offset stands for a `\r\n` there.
+fn convert_edits_to_dos_line_endings(unix_text: &str, edits: Vec<AtomEdit>) -> Vec<AtomEdit> {
+    let newlines: Vec<TextSize> =
+        unix_text.match_indices('\n').map(|(idx, _)| TextSize::from(idx as u32)).collect();
+    let to_dos = |offset: TextSize| {
+        let n_newlines = newlines.partition_point(|&it| it < offset);
+        offset + TextSize::from(n_newlines as u32)
+    };
+    edits
+        .into_iter()
+        .map(|edit| AtomEdit {
+            delete: TextRange::new(to_dos(edit.delete.start()), to_dos(edit.delete.end())),
+            insert: convert_to_dos_line_endings(edit.insert.to_string()).into(),
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pattern::{multiline, parent};
+    use rnix::{SyntaxKind::*, T};
 
     #[test]
     fn preserves_dos_line_endings() {
@@ -198,6 +840,127 @@ mod tests {
         assert_eq!(&reformat_string("{\n\tfoo = 92;\t}\n"), "{\n  foo = 92;\n}\n");
     }
 
+    #[test]
+    fn quotes_urls() {
+        assert_eq!(
+            &reformat_string("{ url = https://example.com/foo.tar.gz; }"),
+            "{ url = \"https://example.com/foo.tar.gz\"; }\n"
+        );
+        let options = FormatOptions { quote_urls: false, ..FormatOptions::default() };
+        assert_eq!(
+            &reformat_string_with_options("{ url = https://example.com/foo.tar.gz; }", &options),
+            "{ url = https://example.com/foo.tar.gz; }\n"
+        );
+        assert_eq!(
+            explain("{\n  url = https://example.com;\n}\n"),
+            "{\n  url = https://example.com;  # [10; 29): Quote URL literals\n}\n"
+        );
+    }
+
+    #[test]
+    fn trailing_commas() {
+        let options =
+            FormatOptions { comma_style: Some(CommaStyle::Trailing), ..FormatOptions::default() };
+        let input =
+            "{ stdenv\n, fetchurl # fetcher\n, ...\n}: { f = { a\n, b }: a; g = { a, b }: a; }\n";
+        let expected = "{\n  stdenv,\n  fetchurl, # fetcher\n  ...\n}: {\n  f = {\n    a,\n    b,\n  }: a;\n  g = { a, b }: a;\n}\n";
+        assert_eq!(&reformat_string_with_options(input, &options), expected);
+        assert_eq!(&reformat_string_with_options(expected, &options), expected);
+        // A comma on a line of its own doesn't leave a blank line behind.
+        assert_eq!(&reformat_string_with_options("{ a\n,\n}: a", &options), "{\n  a,\n}: a\n");
+        let options =
+            FormatOptions { comma_style: Some(CommaStyle::Leading), ..FormatOptions::default() };
+        assert_eq!(
+            &reformat_string_with_options("{ a\n,\n  b\n}: a", &options),
+            "{ a\n, b\n}: a\n"
+        );
+        assert_eq!(
+            &reformat_string_with_options("{\n  stdenv,\n  fetchurl,\n}: 92\n", &options),
+            "{ stdenv\n, fetchurl\n}: 92\n"
+        );
+    }
+
+    #[test]
+    fn keeps_comments_before_pattern_entries() {
+        let trailing =
+            FormatOptions { comma_style: Some(CommaStyle::Trailing), ..FormatOptions::default() };
+        let leading =
+            FormatOptions { comma_style: Some(CommaStyle::Leading), ..FormatOptions::default() };
+        for comment in ["# comment about b", "/* comment about b */"] {
+            let input = format!("{{\n  a,\n  {}\n  b,\n}}: a\n", comment);
+            assert_eq!(reformat_string_with_options(&input, &trailing), input);
+            let expected = format!("{{ a\n  {}\n, b\n}}: a\n", comment);
+            assert_eq!(reformat_string_with_options(&input, &leading), expected);
+            assert_eq!(reformat_string_with_options(&expected, &leading), expected);
+            assert_eq!(reformat_string_with_options(&expected, &trailing), input);
+        }
+        let input = "{ a, # about a\n  b,\n}: a\n";
+        assert_eq!(
+            reformat_string_with_options(input, &trailing),
+            "{\n  a, # about a\n  b,\n}: a\n"
+        );
+    }
+
+    #[test]
+    fn normalizes_blank_lines() {
+        let input =
+            "let\n\n  a = [\n\n    1\n\n\n\n    2\n\n  ];\n\nin\n{\n\n  inherit a;\n}\n\n\n";
+        assert_eq!(reformat_string(input), input);
+
+        let options = FormatOptions { max_blank_lines: Some(1), ..FormatOptions::default() };
+        assert_eq!(
+            reformat_string_with_options(input, &options),
+            "let\n\n  a = [\n\n    1\n\n    2\n\n  ];\n\nin\n{\n\n  inherit a;\n}\n\n"
+        );
+
+        let options = FormatOptions { trim_blank_lines: true, ..FormatOptions::default() };
+        assert_eq!(
+            reformat_string_with_options(input, &options),
+            "let\n  a = [\n    1\n\n\n\n    2\n  ];\nin\n{\n  inherit a;\n}\n\n\n"
+        );
+
+        // Interpolations are capped too, but not the content of strings.
+        let input = "\"${\n\n\n  x}\n\n\n\"\n";
+        let options = FormatOptions { max_blank_lines: Some(0), ..FormatOptions::default() };
+        assert_eq!(reformat_string_with_options(input, &options), "\"${\n  x}\n\n\n\"\n");
+    }
+
+    #[test]
+    fn separates_multiline_bindings() {
+        let input = "let\n  a = 1;\n  b = 2;\n  c = {\n    x = 1;\n  };\n\n\n  d = 3;\n  e = 4;\nin\n{\n  f = [\n  ];\n  # comment\n  g = 5;\n}\n";
+        assert_eq!(reformat_string(input), input);
+
+        let options =
+            FormatOptions { separate_multiline_bindings: true, ..FormatOptions::default() };
+        assert_eq!(
+            reformat_string_with_options(input, &options),
+            "let\n  a = 1;\n  b = 2;\n\n  c = {\n    x = 1;\n  };\n\n  d = 3;\n  e = 4;\nin\n{\n  f = [\n  ];\n  # comment\n  g = 5;\n}\n"
+        );
+        assert_eq!(
+            explain_with_options("{\n  a = 1;\n  b = {\n    x = 1;\n  };\n}\n", &options),
+            "{\n  a = 1;\n  b = {  # [10; 13): Blank line around multi-line bindings\n    x = 1;\n  };\n}\n"
+        );
+    }
+
+    #[test]
+    fn strips_trailing_whitespace() {
+        assert_eq!(
+            reformat_string("# comment  \n/* block \n   comment\t\n*/\n92\n"),
+            "# comment\n/* block\n   comment\n*/\n92\n"
+        );
+
+        let input = "''\n  foo  \n  \n    \n    bar\n\n''\n";
+        let options = FormatOptions { report_string_whitespace: true, ..FormatOptions::default() };
+        assert_eq!(reformat_string_with_options(input, &options), input);
+        let warnings: Vec<(String, TextRange)> =
+            lint(input, &options).into_iter().map(|it| (it.rule.into_owned(), it.range)).collect();
+        assert_eq!(
+            warnings,
+            vec![("string-trailing-whitespace".to_string(), TextRange::new(11.into(), 13.into()))]
+        );
+        assert_eq!(lint(input, &FormatOptions::default()), Vec::new());
+    }
+
     #[test]
     fn explain_smoke_test() {
         let input = "{\nfoo =1;\n}\n";
@@ -211,6 +974,244 @@ foo =1;  # [7; 7): Space after =
         )
     }
 
+    #[test]
+    fn explain_verbose_shows_patterns_and_anchors() {
+        let options = FormatOptions::default();
+        assert_eq!(
+            explain_verbose("{\nfoo =1;\n}\n", &options),
+            "{\nfoo =1;  # [7; 7): Space after = (Pattern(kind(TOKEN_ASSIGN) & \
+             parent(kind(NODE_KEY_VALUE))), SingleOptionalNewline After)\n}\n"
+        );
+        assert_eq!(
+            explain_verbose("[\n1\n]\n", &options),
+            "[\n1  # [1; 2): Indent list content (inside Pattern(kind(NODE_LIST)), \
+             not matching Pattern(kind(TOKEN_SQUARE_B_OPEN, TOKEN_SQUARE_B_CLOSE)), \
+             anchor NODE_ROOT@0..6 at IndentLevel { level: 0, alignment: 0 }, \
+             indent IndentLevel { level: 1, alignment: 0 })\n]\n"
+        );
+    }
+
+    #[test]
+    fn debug_indent_shows_anchors() {
+        let options = FormatOptions::default();
+        assert_eq!(
+            debug_indent("f  {\nfoo = [\n1 ];\n}\n", &options),
+            "f {\n\
+             foo = [  # NODE_KEY_VALUE by indent-attr-set, anchor NODE_ROOT on line 1, level 1 + alignment 0\n\
+             1  # NODE_LITERAL by indent-list, anchor NODE_KEY_VALUE on line 2, level 2 + alignment 0\n\
+             ];  # TOKEN_SQUARE_B_CLOSE by preserve-indentation, anchor NODE_KEY_VALUE on line 2, level 1 + alignment 0\n\
+             }  # TOKEN_CURLY_B_CLOSE by preserve-indentation, anchor NODE_ROOT on line 1, level 0 + alignment 0\n"
+        );
+        let html = debug_indent_html("{\nfoo = \"<a>\";\n}\n", &options);
+        assert!(html.contains(
+            "<span id=\"L2\" title=\"NODE_KEY_VALUE by indent-attr-set, anchor NODE_ROOT on line 1, \
+             level 1 + alignment 0\" data-anchor=\"L1\">foo = &quot;&lt;a&gt;&quot;;</span>\n"
+        ));
+    }
+
+    #[test]
+    fn edits_with_moved_commas() {
+        let input = "{\n  stdenv,\n  fetchurl,\n}: {\nx = 1;\n}\n";
+        let (spacing_edits, indent_edits) = reformat_edits(&rnix::parse(input).node());
+        let actual = apply_edits(&apply_edits(input, &spacing_edits), &indent_edits);
+        assert_eq!(actual, reformat_string(input));
+    }
+
+    #[test]
+    fn text_edits_are_a_single_transaction() {
+        let input = "{\n  stdenv,\n  fetchurl,\n}: {\nx = 1;\n  y = {a=1;};\n}\n";
+        let edits = text_edits(input, &FormatOptions::default());
+        assert_eq!(apply_edits(input, &edits), reformat_string(input));
+
+        let input = "{\r\n  stdenv,\r\n  fetchurl,\r\n}: {\r\nx = 1;\r\n  y = {a=1;};}";
+        let edits = text_edits(input, &FormatOptions::default());
+        assert_eq!(apply_edits(input, &edits), reformat_string(input));
+    }
+
+    fn apply_edits(text: &str, edits: &[AtomEdit]) -> String {
+        let mut res = String::new();
+        let mut prev = 0;
+        for ae in edits {
+            res.push_str(&text[prev..ae.delete.start().into()]);
+            res.push_str(&ae.insert);
+            prev = ae.delete.end().into();
+        }
+        res.push_str(&text[prev..]);
+        res
+    }
+
+    #[test]
+    fn rule_registry_is_well_formed() {
+        let all_options = FormatOptions {
+            comma_style: Some(CommaStyle::Trailing),
+            trim_blank_lines: true,
+            separate_multiline_bindings: true,
+            ..FormatOptions::default()
+        };
+        let rules = rule_registry();
+        for (idx, rule) in rules.iter().enumerate() {
+            assert!(
+                rule.id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'),
+                "rule ids should be kebab-cased: {}",
+                rule.id
+            );
+            assert!(rules[..idx].iter().all(|it| it.id != rule.id), "duplicate id: {}", rule.id);
+            assert!(
+                rule.description.chars().next().unwrap().is_uppercase(),
+                "rule descriptions should be capitalized: {}",
+                rule.id
+            );
+            assert!(!rule.description.ends_with('.'), "rule descriptions should not end in `.`");
+            for (before, after) in rule.examples.iter() {
+                let default = reformat_string(before);
+                let all = reformat_string_with_options(before, &all_options);
+                assert!(&default == after || &all == after, "wrong example for {}", rule.id);
+            }
+        }
+        assert!(rules.iter().any(|it| it.id == "final-newline"));
+        assert!(rules.iter().any(|it| it.id == "trailing-commas" && it.kind == RuleKind::Fix));
+    }
+
+    #[test]
+    fn disables_rules() {
+        let input = "{a=1;\nb=  [1 2];\n   c = https://example.com;}";
+        let options = FormatOptions {
+            disabled_rules: vec!["space-inside-brackets".to_string(), "quote-urls".to_string()],
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            reformat_string_with_options(input, &options),
+            "{\n  a = 1;\n  b = [1 2];\n  c = https://example.com;\n}\n"
+        );
+
+        assert_eq!(resolve_rules(vec!["quote-urls"]), Ok(vec!["quote-urls".to_string()]));
+        assert_eq!(resolve_rules(vec!["no-such-rule"]), Err("unknown rule: no-such-rule".into()));
+        let spacing = rule_registry().into_iter().filter(|it| it.kind == RuleKind::Spacing);
+        let options = FormatOptions {
+            only_rules: Some(spacing.map(|it| it.id.to_string()).collect()),
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            reformat_string_with_options(input, &options),
+            "{\na = 1;\nb = [ 1 2 ];\n   c = https://example.com;\n}\n"
+        );
+    }
+
+    #[test]
+    fn reports_rule_conflicts() {
+        let options = FormatOptions {
+            separate_multiline_bindings: true,
+            comma_style: Some(CommaStyle::Trailing),
+            ..FormatOptions::default()
+        };
+        for options in [FormatOptions::default(), options].iter() {
+            let formatter = Formatter::builder().options(options.clone()).build();
+            assert_eq!(formatter.conflicts(), vec![]);
+        }
+
+        let mut spacing = SpacingDsl::default();
+        spacing
+            .rule("tight-lists", "No space inside brackets")
+            .inside(NODE_LIST)
+            .after(T!["["])
+            .no_space();
+        let mut indentation = IndentDsl::default();
+        indentation
+            .rule("indent-list-items", "Indent list items")
+            .inside(NODE_LIST)
+            .set(IndentValue::Indent);
+        let formatter = Formatter::builder()
+            .with_rules(spacing)
+            .unwrap()
+            .with_indent_rules(indentation)
+            .unwrap()
+            .build();
+        let conflicts = formatter.conflicts();
+        assert_eq!(
+            conflicts.iter().map(|it| it.to_string()).collect::<Vec<_>>(),
+            vec![
+                "spacing rules `space-inside-brackets` and `tight-lists` overlap",
+                "indentation rules `indent-list` and `indent-list-items` overlap",
+            ]
+        );
+
+        let (node, conflicts) =
+            formatter.reformat_node_with_conflicts(&rnix::parse("[\n1\n2\n]").node());
+        assert_eq!(node.to_string(), "[1\n  2\n  ]\n");
+        assert_eq!(
+            conflicts.iter().map(|it| it.to_string()).collect::<Vec<_>>(),
+            vec![
+                "spacing rules `space-inside-brackets` and `tight-lists` both match [0; 1)",
+                "indentation rules `indent-list` and `indent-list-items` both match [3; 4)",
+            ]
+        );
+
+        // A condition on the earlier rule hides the conflict from `conflicts`.
+        let mut spacing = SpacingDsl::default();
+        spacing
+            .rule("multiline-let", "Newline after let")
+            .inside(NODE_LET_IN)
+            .after(T![let])
+            .when(parent(multiline()))
+            .newline()
+            .rule("tight-let", "One space after let")
+            .inside(NODE_LET_IN)
+            .after(T![let])
+            .single_space();
+        let formatter =
+            Formatter::builder().without_builtin_rules().with_rules(spacing).unwrap().build();
+        assert_eq!(formatter.conflicts(), vec![]);
+        let (text, conflicts) = formatter.reformat_string_with_conflicts("let a = 1;\nin a\n");
+        assert_eq!(text, "let a = 1;\nin a\n");
+        assert_eq!(
+            conflicts.iter().map(|it| it.to_string()).collect::<Vec<_>>(),
+            vec!["spacing rules `multiline-let` and `tight-let` both match [0; 3)"]
+        );
+        assert_eq!(formatter.reformat_string_with_conflicts("let a = 1; in a\n").1, vec![]);
+    }
+
+    #[test]
+    fn rejects_misused_rule_dsl() {
+        let mut spacing = SpacingDsl::default();
+        spacing.inside(NODE_LIST).after(T!["["]).no_space();
+        assert_eq!(
+            Formatter::builder().with_rules(spacing).unwrap_err(),
+            "`inside` should follow `rule`"
+        );
+
+        let mut spacing = SpacingDsl::default();
+        spacing
+            .rule("tight-lists", "No space inside brackets")
+            .inside(NODE_LIST)
+            .after(T!["["])
+            .no_space()
+            .test("[ 1]", "[1]");
+        assert_eq!(
+            Formatter::builder().with_rules(spacing).unwrap_err(),
+            "the example `[ 1]` should be followed by a rule"
+        );
+
+        let mut indentation = IndentDsl::default();
+        indentation
+            .test("[\n1\n]", "[\n  1\n]")
+            .rule("indent-list-items", "Indent list items")
+            .inside(NODE_LIST)
+            .set(IndentValue::Indent);
+        assert_eq!(indentation.tests[0].0.id(), "indent-list-items");
+        assert!(Formatter::builder().with_indent_rules(indentation).is_ok());
+    }
+
+    #[test]
+    fn counts_rule_edits() {
+        let stats = rule_stats("{\n a=1;\n    b = 2;}\n", &FormatOptions::default());
+        assert_eq!(stats.edits.get("space-before-eq"), Some(&1));
+        assert_eq!(stats.edits.get("space-after-eq"), Some(&1));
+        assert_eq!(stats.edits.get("space-inside-braces"), Some(&1));
+        assert_eq!(stats.unnamed_edits, 0);
+        // `}` is only indented by default.
+        assert_eq!(stats.default_indent, 1);
+    }
+
     #[test]
     fn edits() {
         let input = include_str!("../test_data/indent_tabs-2.bad.nix");
@@ -0,0 +1,272 @@
+//! This module contains the lints: nixpkgs conventions which can be checked
+//! on the syntax tree, but can't be fixed by changing whitespace. They are
+//! reported by `crate::lint`, and never change the code.
+use std::collections::HashSet;
+
+use rnix::{
+    types::{Apply, AttrSet, EntryHolder, Select, TokenWrapper, TypedNode},
+    SyntaxElement,
+    SyntaxKind::*,
+    SyntaxNode, TextRange, TextSize,
+};
+
+use crate::{
+    dsl::RuleName,
+    engine::node_indent_ranges,
+    pattern::{p, top_level, Pattern, PatternSet},
+    tree_utils::walk,
+    FormatOptions, LintWarning,
+};
+
+pub(crate) const UNNECESSARY_REC: RuleName =
+    RuleName::new("unnecessary-rec", "No rec on sets which don't refer to their own attributes");
+pub(crate) const TOP_LEVEL_WITH: RuleName =
+    RuleName::new("top-level-with", "No with on the top level of a file");
+pub(crate) const STDENV_LIB: RuleName =
+    RuleName::new("stdenv-lib", "Use lib instead of stdenv.lib");
+pub(crate) const FETCH_TARBALL_WITHOUT_HASH: RuleName =
+    RuleName::new("fetch-tarball-without-hash", "Pin builtins.fetchTarball with a sha256");
+/// Checked only with `FormatOptions::report_string_whitespace`, see
+/// `string_whitespace_lines`.
+pub(crate) const STRING_TRAILING_WHITESPACE: RuleName =
+    RuleName::new("string-trailing-whitespace", "No whitespace-only lines in indented strings");
+
+/// A lint matches the elements it warns about.
+pub(crate) struct Lint {
+    pub(crate) name: RuleName,
+    pattern: Pattern,
+}
+
+impl AsRef<Pattern> for Lint {
+    fn as_ref(&self) -> &Pattern {
+        &self.pattern
+    }
+}
+
+pub(crate) fn lints() -> Vec<Lint> {
+    vec![
+        Lint { name: UNNECESSARY_REC, pattern: p(NODE_ATTR_SET) & p(unnecessary_rec) },
+        Lint { name: TOP_LEVEL_WITH, pattern: p(NODE_WITH) & p(top_level()) },
+        Lint { name: STDENV_LIB, pattern: p(NODE_SELECT) & p(stdenv_lib) },
+        Lint {
+            name: FETCH_TARBALL_WITHOUT_HASH,
+            pattern: p(NODE_APPLY) & p(fetch_tarball_without_hash),
+        },
+    ]
+}
+
+pub(crate) fn lint(node: &SyntaxNode, options: &FormatOptions) -> Vec<LintWarning> {
+    let lints = lints();
+    let lint_set =
+        PatternSet::new(lints.iter().filter(|lint| options.is_rule_enabled(lint.name.id())));
+    let mut res = Vec::new();
+    // Unlike formatting, linting looks into string interpolations too.
+    for element in walk(node) {
+        for lint in lint_set.matching(element.clone()) {
+            res.push(LintWarning {
+                rule: lint.name.id.clone(),
+                description: lint.name.description.clone(),
+                range: element.text_range(),
+            })
+        }
+    }
+    if options.report_string_whitespace && options.is_rule_enabled(STRING_TRAILING_WHITESPACE.id())
+    {
+        let strings = node.descendants().filter(|it| it.kind() == NODE_STRING);
+        for range in strings.flat_map(|it| string_whitespace_lines(&it)) {
+            res.push(LintWarning {
+                rule: STRING_TRAILING_WHITESPACE.id,
+                description: STRING_TRAILING_WHITESPACE.description,
+                range,
+            })
+        }
+        res.sort_by_key(|it| it.range.start());
+    }
+    res
+}
+
+/// Returns the whitespace-only lines of an indented string which could be
+/// emptied without changing the value of the string, as Nix strips the
+/// whitespace anyway. That is the case when the line is not longer than the
+/// common indentation of the string.
+///
+/// The whitespace is a part of the string as far as the formatter is
+/// concerned, so it is only reported, never removed. Trailing whitespace
+/// after the content of a line is a part of the string value, so it is not
+/// reported. Neither is the first line, which Nix ignores if it is blank.
+fn string_whitespace_lines(node: &SyntaxNode) -> Vec<TextRange> {
+    let is_indented_string = node.first_token().map(|it| it.text() == "''") == Some(true);
+    if !is_indented_string {
+        return Vec::new();
+    }
+
+    let common_indent = {
+        let mut indent_ranges: Vec<TextRange> = node_indent_ranges(node).collect();
+        let last_line_is_blank =
+            indent_ranges.last().map(|it| it.end() + TextSize::of("''") == node.text_range().end())
+                == Some(true);
+        if last_line_is_blank {
+            indent_ranges.pop();
+        }
+        let first_line = node
+            .children_with_tokens()
+            .nth(1)
+            .and_then(|it| it.into_token())
+            .filter(|it| it.kind() == TOKEN_STRING_CONTENT)
+            .map(|it| it.text().split('\n').next().unwrap().to_string());
+        let first_line_indent = match first_line {
+            Some(line) if line.trim_start_matches(' ').is_empty() => None,
+            Some(line) => Some(TextSize::of(line.as_str()) - TextSize::of(line.trim_start())),
+            // The string starts with an interpolation.
+            None => Some(0.into()),
+        };
+        indent_ranges.iter().map(|it| it.len()).chain(first_line_indent).min()
+    };
+
+    let mut res = Vec::new();
+    let string_bits = node
+        .children_with_tokens()
+        .filter_map(|it| it.into_token())
+        .filter(|it| it.kind() == TOKEN_STRING_CONTENT);
+    for string_bit in string_bits {
+        let mut offset = string_bit.text_range().start();
+        let lines: Vec<&str> = string_bit.text().split('\n').collect();
+        for (idx, line) in lines.iter().enumerate() {
+            let line_range = TextRange::at(offset, TextSize::of(*line));
+            offset += TextSize::of(*line) + TextSize::of('\n');
+            // Only look at complete lines, not at the parts which are adjacent
+            // to quotes or interpolations.
+            if idx == 0 || idx == lines.len() - 1 || line.is_empty() {
+                continue;
+            }
+            if !line.trim_start_matches(' ').is_empty() {
+                continue;
+            }
+            if common_indent.map(|it| line_range.len() <= it) != Some(false) {
+                res.push(line_range)
+            }
+        }
+    }
+    res
+}
+
+/// A `rec` set none of whose values mention the names of its attributes.
+/// Shadowing is not taken into account, so some unnecessary `rec`s are missed.
+fn unnecessary_rec(element: &SyntaxElement) -> bool {
+    let set = match element.as_node().cloned().and_then(AttrSet::cast) {
+        Some(it) if it.recursive() => it,
+        _ => return false,
+    };
+    let mut names = HashSet::new();
+    for entry in set.entries() {
+        let first_ident = entry.key().and_then(|key| key.path().next());
+        if let Some(ident) = first_ident.filter(|it| it.kind() == NODE_IDENT) {
+            names.insert(ident.text().to_string());
+        }
+    }
+    for inherit in set.inherits() {
+        names.extend(inherit.idents().map(|it| it.as_str().to_string()));
+    }
+    let mentioned = set.node().descendants().any(|node| {
+        node.kind() == NODE_IDENT
+            && names.contains(&node.text().to_string())
+            && !is_attr_name(&node)
+            && !is_select_index(&node)
+    });
+    !mentioned
+}
+
+/// `a` in `a.b = 1;` or `inherit a;`, which names an attribute rather than
+/// refers to a variable. `${a}` in a key and `(a)` in `inherit (a) b;` do
+/// refer to variables.
+fn is_attr_name(node: &SyntaxNode) -> bool {
+    matches!(node.parent().map(|it| it.kind()), Some(NODE_KEY | NODE_INHERIT))
+}
+
+/// `b` in `a.b`, which is the name of an attribute rather than a variable.
+fn is_select_index(node: &SyntaxNode) -> bool {
+    let select = node.parent().and_then(Select::cast);
+    select.and_then(|it| it.index()).as_ref() == Some(node)
+}
+
+fn stdenv_lib(element: &SyntaxElement) -> bool {
+    let select = match element.as_node().cloned().and_then(Select::cast) {
+        Some(it) => it,
+        None => return false,
+    };
+    let is_ident = |node: Option<SyntaxNode>, name: &str| {
+        node.map(|it| it.kind() == NODE_IDENT && it.text() == name) == Some(true)
+    };
+    is_ident(select.set(), "stdenv") && is_ident(select.index(), "lib")
+}
+
+/// `builtins.fetchTarball url`, or with a set which has no `sha256`.
+fn fetch_tarball_without_hash(element: &SyntaxElement) -> bool {
+    let apply = match element.as_node().cloned().and_then(Apply::cast) {
+        Some(it) => it,
+        None => return false,
+    };
+    let lambda = match apply.lambda() {
+        Some(it) => it.text().to_string(),
+        None => return false,
+    };
+    if lambda != "builtins.fetchTarball" && lambda != "fetchTarball" {
+        return false;
+    }
+    match apply.value().and_then(AttrSet::cast) {
+        Some(set) => !set.entries().any(|entry| {
+            let key = entry.key().map(|it| it.node().text().to_string());
+            key.as_deref() == Some("sha256")
+        }),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lint, FormatOptions};
+
+    fn lint_ids(text: &str) -> Vec<String> {
+        lint(text, &FormatOptions::default()).into_iter().map(|it| it.rule.into_owned()).collect()
+    }
+
+    #[test]
+    fn finds_lints() {
+        assert_eq!(lint_ids("rec { a = 1; b = 2; }"), vec!["unnecessary-rec"]);
+        assert_eq!(lint_ids("rec { a = 1; b = a; }"), Vec::<&str>::new());
+        assert_eq!(lint_ids("rec { a = 1; b = x.a; c.a = 2; }"), vec!["unnecessary-rec"]);
+        assert_eq!(lint_ids("rec { inherit a; b = \"${a}\"; }"), Vec::<&str>::new());
+        assert_eq!(lint_ids("rec { foo = { x = 1; }; inherit (foo) x; }"), Vec::<&str>::new());
+        assert_eq!(lint_ids("rec { a = \"n\"; ${a} = 2; }"), Vec::<&str>::new());
+        assert_eq!(lint_ids("rec { a = \"n\"; b.${a} = 2; }"), Vec::<&str>::new());
+        assert_eq!(lint_ids("rec { a = \"n\"; b.\"${a}\" = 2; }"), Vec::<&str>::new());
+        assert_eq!(lint_ids("rec { a = 1; inherit (x) a; }"), vec!["unnecessary-rec"]);
+        assert_eq!(lint_ids("{ lib }: with lib; { a = 1; }"), vec!["top-level-with"]);
+        assert_eq!(lint_ids("{ meta = with lib; { a = 1; }; }"), Vec::<&str>::new());
+        assert_eq!(lint_ids("stdenv.lib.licenses.mit"), vec!["stdenv-lib"]);
+        assert_eq!(lint_ids("pkgs.stdenv.lib"), Vec::<&str>::new());
+        assert_eq!(lint_ids("\"${stdenv.lib.version}\""), vec!["stdenv-lib"]);
+        assert_eq!(lint_ids("''\n  ${stdenv.lib.version}\n''"), vec!["stdenv-lib"]);
+        assert_eq!(
+            lint_ids("[ (builtins.fetchTarball \"https://a\") (fetchTarball { url = \"b\"; }) ]"),
+            vec!["fetch-tarball-without-hash", "fetch-tarball-without-hash"]
+        );
+        assert_eq!(
+            lint_ids("builtins.fetchTarball { url = \"b\"; sha256 = \"c\"; }"),
+            Vec::<&str>::new()
+        );
+    }
+
+    #[test]
+    fn disables_lints() {
+        let options = FormatOptions {
+            disabled_rules: vec!["stdenv-lib".to_string()],
+            ..FormatOptions::default()
+        };
+        let ids: Vec<String> = lint("with stdenv.lib; 1", &options)
+            .into_iter()
+            .map(|it| it.rule.into_owned())
+            .collect();
+        assert_eq!(ids, vec!["top-level-with"]);
+    }
+}
@@ -1,14 +1,24 @@
 use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    env, error, fmt,
     fmt::Write,
     fs,
     io::{self, stdin, Read},
+    panic,
     path::{Path, PathBuf},
+    sync::Mutex,
     thread,
 };
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use rnix::types::TypedNode;
+use nixpkgs_fmt::{CommaStyle, FormatOptions, RuleConflict, RuleKind, RuleStats};
+use rnix::{
+    parser::ParseError,
+    types::{ParsedType, TokenWrapper, TypedNode},
+    NodeOrToken, SyntaxNode, TextRange, TextSize,
+};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -16,12 +26,37 @@ type FormatResult = (PathBuf, FormatStatus);
 enum FormatStatus {
     Change,
     NoChange,
+    /// The formatter panicked, with the given message.
+    InternalError(String),
+}
+
+/// The exit code when some files could not be formatted because of a bug.
+const EXIT_INTERNAL_ERROR: i32 = 2;
+
+/// Returned when processing some of the files panicked.
+#[derive(Debug)]
+struct InternalErrors(usize);
+
+impl fmt::Display for InternalErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error: internal error while processing {} file(s)", self.0)
+    }
 }
 
+impl error::Error for InternalErrors {}
+
 fn main() {
     if let Err(err) = parse_args().and_then(try_main) {
         eprintln!("{}", err);
-        std::process::exit(1);
+        std::process::exit(exit_code(&*err));
+    }
+}
+
+fn exit_code(err: &(dyn error::Error + 'static)) -> i32 {
+    if err.is::<InternalErrors>() {
+        EXIT_INTERNAL_ERROR
+    } else {
+        1
     }
 }
 
@@ -29,13 +64,21 @@ fn main() {
 struct Args {
     src: Src,
     operation: Operation,
+    options: FormatOptions,
 }
 
 #[derive(Debug)]
 enum Operation {
     Fmt { write_changes: bool, fail_on_changes: bool },
-    Explain,
+    Explain { verbose: bool },
+    DebugIndent { html: bool },
     Parse { output_format: OutputFormat },
+    ListRules,
+    TestRules,
+    Stats,
+    Lint,
+    Query { query: nixpkgs_fmt::Query },
+    Rewrite { rewrites: Vec<nixpkgs_fmt::Rewrite> },
 }
 
 #[derive(Debug)]
@@ -48,6 +91,12 @@ enum Src {
 enum OutputFormat {
     Rnix, // rnix library: ast.root().dump()
     Json,
+    /// One S-expression per node, with the tokens inline.
+    Sexpr,
+    /// A Graphviz graph of the nodes and tokens.
+    Dot,
+    /// The nodes as seen by `rnix::types`, with the roles of their children.
+    Typed,
 }
 
 fn parse_args() -> Result<Args> {
@@ -73,7 +122,7 @@ fn parse_args() -> Result<Args> {
                 .long("output-format")
                 .value_name("FORMAT")
                 .takes_value(true)
-                .possible_values(&["rnix", "json"])
+                .possible_values(&["rnix", "json", "sexpr", "dot", "typed"])
                 .default_value("rnix")
                 .help("Set output format of --parse"),
         )
@@ -84,6 +133,24 @@ fn parse_args() -> Result<Args> {
                 .conflicts_with("check")
                 .help("Show which rules are violated"),
         )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .requires("explain")
+                .help("Show how the rules matched in --explain"),
+        )
+        .arg(
+            Arg::with_name("debug-indent")
+                .long("debug-indent")
+                .conflicts_with_all(&["srcs", "parse", "explain", "check"])
+                .help("Show the indent anchor and level of each line"),
+        )
+        .arg(
+            Arg::with_name("html")
+                .long("html")
+                .requires("debug-indent")
+                .help("Render --debug-indent as HTML, with the details shown on hover"),
+        )
         .arg(
             Arg::with_name("check")
                 .long("check")
@@ -91,27 +158,208 @@ fn parse_args() -> Result<Args> {
                 .conflicts_with("explain")
                 .help("Only test if the formatter would produce differences"),
         )
+        .arg(
+            Arg::with_name("list-rules")
+                .long("list-rules")
+                .conflicts_with_all(&["srcs", "parse", "explain", "check"])
+                .help("List the formatting rules with their examples"),
+        )
+        .arg(
+            Arg::with_name("no-quote-urls")
+                .long("no-quote-urls")
+                .help("Don't rewrite URL literals into strings"),
+        )
+        .arg(
+            Arg::with_name("comma-style")
+                .long("comma-style")
+                .value_name("STYLE")
+                .takes_value(true)
+                .possible_values(&["leading", "trailing"])
+                .help("Move the commas in multi-line lambda patterns to the given side"),
+        )
+        .arg(
+            Arg::with_name("max-blank-lines")
+                .long("max-blank-lines")
+                .value_name("N")
+                .takes_value(true)
+                .help("Collapse runs of more than N blank lines"),
+        )
+        .arg(
+            Arg::with_name("trim-blank-lines")
+                .long("trim-blank-lines")
+                .help("Remove blank lines after opening and before closing brackets"),
+        )
+        .arg(
+            Arg::with_name("separate-multiline-bindings")
+                .long("separate-multiline-bindings")
+                .help("Put a blank line between bindings if either spans multiple lines"),
+        )
+        .arg(
+            Arg::with_name("string-whitespace")
+                .long("string-whitespace")
+                .help("Make --lint report the whitespace-only lines in '' strings which Nix strips"),
+        )
+        .arg(
+            Arg::with_name("disable-rule")
+                .long("disable-rule")
+                .value_name("RULE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Don't apply a rule, by ID or kind (spacing, indentation, fix, lint)"),
+        )
+        .arg(
+            Arg::with_name("only-rule")
+                .long("only-rule")
+                .value_name("RULE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Apply only the given rules, by ID or kind (spacing, indentation, fix, lint)"),
+        )
+        .arg(
+            Arg::with_name("rules")
+                .long("rules")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Load additional spacing rules from a file"),
+        )
+        .arg(
+            Arg::with_name("test-rules")
+                .long("test-rules")
+                .requires("rules")
+                .conflicts_with_all(&["srcs", "parse", "explain", "check", "list-rules"])
+                .help("Check the examples of the rules loaded with --rules"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .requires("srcs")
+                .conflicts_with_all(&["parse", "explain", "check", "list-rules", "test-rules"])
+                .help("Count the edits of each rule over the given files, without changing them"),
+        )
+        .arg(
+            Arg::with_name("lint")
+                .long("lint")
+                .conflicts_with_all(&["parse", "explain", "check", "list-rules", "test-rules", "stats"])
+                .help("Report the code which breaks the lint rules, without changing it"),
+        )
+        .subcommand(
+            SubCommand::with_name("query")
+                .about("Print the syntax nodes which match a query, like NODE_KEY_VALUE[key=version] > NODE_STRING")
+                .arg(Arg::with_name("query").value_name("QUERY").required(true).help(
+                    "Node kinds, or * for any node, filtered by the text of their children, \
+                     like [key=version] or [lambda*=fetch], and separated by > for direct children",
+                ))
+                .arg(
+                    Arg::with_name("srcs")
+                        .value_name("FILE")
+                        .multiple(true)
+                        .help("Files or directories to search. If no file is passed, read from stdin."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rewrite")
+                .about("Replace the syntax nodes which match a query, and format the replaced code")
+                .arg(
+                    Arg::with_name("rewrite")
+                        .short("e")
+                        .long("rewrite")
+                        .value_name("REWRITE")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true)
+                        .help(
+                            "A query and a replacement, like 'NODE_SELECT[text=stdenv.lib] => lib', \
+                             where $ROLE is the text of a child of the node, and $text of the node \
+                             itself. Several rewrites are applied in order",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("srcs")
+                        .value_name("FILE")
+                        .multiple(true)
+                        .help("Files or directories to rewrite in place. If no file is passed, read from stdin."),
+                ),
+        )
         .get_matches_safe()?;
 
-    let src = match matches.values_of("srcs") {
+    let src = match matches.subcommand().1.unwrap_or(&matches).values_of("srcs") {
         None => Src::Stdin, // default to reading from stdin
         Some(srcs) => Src::Paths(srcs.map(PathBuf::from).collect()),
     };
-    let operation = if matches.is_present("parse") {
+    let operation = if let Some(query_matches) = matches.subcommand_matches("query") {
+        // unwrap justification: the query is a required argument
+        let query = query_matches.value_of("query").unwrap();
+        Operation::Query { query: query.parse().map_err(|err| format!("invalid query: {}", err))? }
+    } else if let Some(rewrite_matches) = matches.subcommand_matches("rewrite") {
+        let mut rewrites = Vec::new();
+        // unwrap justification: the rewrites are a required argument
+        for rewrite in rewrite_matches.values_of("rewrite").unwrap() {
+            rewrites.push(rewrite.parse().map_err(|err| format!("invalid rewrite: {}", err))?);
+        }
+        Operation::Rewrite { rewrites }
+    } else if matches.is_present("parse") {
         let output_format = match matches.value_of("output-format") {
             Some("json") => OutputFormat::Json,
+            Some("sexpr") => OutputFormat::Sexpr,
+            Some("dot") => OutputFormat::Dot,
+            Some("typed") => OutputFormat::Typed,
             _ => OutputFormat::Rnix,
         };
         Operation::Parse { output_format }
     } else if matches.is_present("explain") {
-        Operation::Explain
+        Operation::Explain { verbose: matches.is_present("verbose") }
+    } else if matches.is_present("debug-indent") {
+        Operation::DebugIndent { html: matches.is_present("html") }
+    } else if matches.is_present("test-rules") {
+        Operation::TestRules
+    } else if matches.is_present("list-rules") {
+        Operation::ListRules
+    } else if matches.is_present("stats") {
+        Operation::Stats
+    } else if matches.is_present("lint") {
+        Operation::Lint
     } else if matches.is_present("check") {
         Operation::Fmt { write_changes: false, fail_on_changes: true }
     } else {
         Operation::Fmt { write_changes: true, fail_on_changes: false }
     };
 
-    Ok(Args { operation, src })
+    let comma_style = match matches.value_of("comma-style") {
+        Some("trailing") => Some(CommaStyle::Trailing),
+        Some(_) => Some(CommaStyle::Leading),
+        None => None,
+    };
+    let max_blank_lines = match matches.value_of("max-blank-lines") {
+        Some(max) => Some(max.parse().map_err(|_| format!("invalid --max-blank-lines: {}", max))?),
+        None => None,
+    };
+    let mut options = FormatOptions::default();
+    options.quote_urls = !matches.is_present("no-quote-urls");
+    options.comma_style = comma_style;
+    options.max_blank_lines = max_blank_lines;
+    options.trim_blank_lines = matches.is_present("trim-blank-lines");
+    options.separate_multiline_bindings = matches.is_present("separate-multiline-bindings");
+    options.report_string_whitespace = matches.is_present("string-whitespace");
+    options.disabled_rules = rule_ids(matches.values_of("disable-rule").into_iter().flatten())?;
+    if let Some(rules) = matches.values_of("only-rule") {
+        options.only_rules = Some(rule_ids(rules)?);
+    }
+    if let Some(path) = matches.value_of("rules") {
+        options.custom_rules = nixpkgs_fmt::parse_rules_file(&fs::read_to_string(path)?)
+            .map_err(|err| format!("{}: {}", path, err))?;
+    }
+
+    Ok(Args { operation, src, options })
+}
+
+/// Resolves rule IDs and rule kinds into a list of rule IDs.
+fn rule_ids<'a>(selectors: impl Iterator<Item = &'a str>) -> Result<Vec<String>> {
+    let ids = nixpkgs_fmt::resolve_rules(selectors)
+        .map_err(|err| format!("{} (see --list-rules)", err))?;
+    Ok(ids)
 }
 
 fn reset_sigpipe() -> io::Result<()> {
@@ -127,8 +375,18 @@ fn try_main(args: Args) -> Result<()> {
         Operation::Fmt { write_changes, fail_on_changes } => match &args.src {
             Src::Stdin => {
                 reset_sigpipe()?;
+                silence_panics();
                 let input = read_stdin_to_string()?;
-                let output = nixpkgs_fmt::reformat_string(&input);
+                let output = match reformat_string(&input, &args.options) {
+                    Ok((output, conflicts)) => {
+                        report_conflicts(Path::new("<stdin>"), &conflicts);
+                        output
+                    }
+                    Err(message) => {
+                        report_internal_error(Path::new("<stdin>"), &message);
+                        return Err(InternalErrors(1).into());
+                    }
+                };
                 let has_changes = input != output;
                 if write_changes {
                     print!("{}", output);
@@ -138,6 +396,7 @@ fn try_main(args: Args) -> Result<()> {
                 }
             }
             Src::Paths(paths) => {
+                silence_panics();
                 let (sender, receiver): (Sender<FormatResult>, Receiver<FormatResult>) =
                     unbounded();
 
@@ -145,22 +404,30 @@ fn try_main(args: Args) -> Result<()> {
                 let reducer = thread::spawn(move || {
                     let mut files_count = 0;
                     let mut files_changed = 0;
+                    let mut files_failed = 0;
                     for (file_path, status) in receiver {
                         files_count += 1;
-                        if let FormatStatus::Change = status {
-                            files_changed += 1;
-                            println!("{}", file_path.display());
+                        match status {
+                            FormatStatus::Change => {
+                                files_changed += 1;
+                                println!("{}", file_path.display());
+                            }
+                            FormatStatus::NoChange => {}
+                            FormatStatus::InternalError(message) => {
+                                files_failed += 1;
+                                report_internal_error(&file_path, &message);
+                            }
                         }
                     }
-                    (files_count, files_changed)
+                    (files_count, files_changed, files_failed)
                 });
 
                 // Start formatting
                 for path in paths {
                     if path.is_dir() {
-                        reformat_dir_in_place(path, write_changes, &sender)?;
+                        reformat_dir_in_place(path, write_changes, &args.options, &sender)?;
                     } else {
-                        let status = reformat_file(path, write_changes)?;
+                        let status = reformat_file(path, write_changes, &args.options)?;
                         // unwrap justification: the channel only fails if it's closed on either
                         // end. The drop() happens below.
                         sender.send((path.clone(), status)).unwrap()
@@ -170,7 +437,7 @@ fn try_main(args: Args) -> Result<()> {
                 // Time to collect the results
                 drop(sender);
                 // unwrap justification: the reducer code has no exceptions
-                let (files_count, files_changed) = reducer.join().unwrap();
+                let (files_count, files_changed, files_failed) = reducer.join().unwrap();
 
                 let text = if write_changes {
                     "have been reformatted"
@@ -178,6 +445,9 @@ fn try_main(args: Args) -> Result<()> {
                     "would have been reformatted"
                 };
                 eprintln!("{} / {} {}", files_changed, files_count, text);
+                if files_failed > 0 {
+                    return Err(InternalErrors(files_failed).into());
+                }
                 if fail_on_changes && files_changed > 0 {
                     return Err("error: fail on changes".into());
                 }
@@ -185,53 +455,717 @@ fn try_main(args: Args) -> Result<()> {
         },
         Operation::Parse { output_format } => {
             reset_sigpipe()?;
-            let input = read_single_source(&args.src)?;
+            let (path, input) = read_single_source(&args.src)?;
             let ast = rnix::parse(&input);
-            let mut error_buf = String::new();
+            let errors: Vec<SyntaxError> =
+                ast.errors().iter().map(|it| SyntaxError::new(&input, it)).collect();
             let res = match output_format {
-                OutputFormat::Rnix => {
-                    let mut buf = String::new();
-                    for error in ast.errors() {
-                        writeln!(error_buf, "error: {}", error).unwrap();
+                OutputFormat::Rnix => format!("{}\n", ast.root().dump()),
+                OutputFormat::Json => {
+                    // The errors are added to the root node, so that the tree
+                    // keeps its shape.
+                    let mut tree = serde_json::to_value(ast.node())?;
+                    let errors = errors.iter().map(|it| it.to_json(&path)).collect();
+                    if let serde_json::Value::Object(root) = &mut tree {
+                        root.insert("errors".to_string(), serde_json::Value::Array(errors));
                     }
-                    writeln!(buf, "{}", ast.root().dump()).unwrap();
-                    buf
+                    serde_json::to_string_pretty(&tree)?
                 }
-                OutputFormat::Json => serde_json::to_string_pretty(&ast.node())?,
-            };
-            match (output_format, error_buf.is_empty()) {
-                (OutputFormat::Rnix, false) => {
-                    return Err(error_buf.into());
-                }
-                (_, _) => {}
+                OutputFormat::Sexpr => dump_sexpr(&ast.node()),
+                OutputFormat::Dot => dump_dot(&ast.node()),
+                OutputFormat::Typed => dump_typed(&ast.node()),
             };
+            if !matches!(output_format, OutputFormat::Json) && !errors.is_empty() {
+                let rendered: Vec<String> =
+                    errors.iter().map(|it| it.render(&path, &input)).collect();
+                return Err(rendered.join("\n").trim_end().into());
+            }
             print!("{}", res)
         }
-        Operation::Explain => {
+        Operation::Explain { verbose } => {
+            reset_sigpipe()?;
+            let input = read_stdin_to_string()?;
+            let formatter = nixpkgs_fmt::Formatter::builder().options(args.options.clone()).build();
+            let output =
+                if verbose { formatter.explain_verbose(&input) } else { formatter.explain(&input) };
+            report_conflicts(
+                Path::new("<stdin>"),
+                &formatter.reformat_string_with_conflicts(&input).1,
+            );
+            print!("{}", output);
+        }
+        Operation::DebugIndent { html } => {
             reset_sigpipe()?;
             let input = read_stdin_to_string()?;
-            let output = nixpkgs_fmt::explain(&input);
+            let output = if html {
+                nixpkgs_fmt::debug_indent_html(&input, &args.options)
+            } else {
+                nixpkgs_fmt::debug_indent(&input, &args.options)
+            };
             print!("{}", output);
         }
+        Operation::ListRules => {
+            reset_sigpipe()?;
+            print!("{}", list_rules());
+        }
+        Operation::TestRules => test_rules(&args.options)?,
+        Operation::Stats => {
+            reset_sigpipe()?;
+            silence_panics();
+            let paths = match &args.src {
+                Src::Paths(paths) => paths,
+                Src::Stdin => return Err("--stats requires files or directories".into()),
+            };
+            let totals = Mutex::new(StatsTotals::default());
+            let failed = Mutex::new(0);
+            let add_file = |path: PathBuf| -> Result<()> {
+                let input = fs::read_to_string(&path)?;
+                let stats = catch_internal_error(&path, &failed, || {
+                    nixpkgs_fmt::rule_stats(&input, &args.options)
+                });
+                if let Some(stats) = stats {
+                    totals.lock().unwrap().add(&stats);
+                }
+                Ok(())
+            };
+            for path in paths {
+                if path.is_dir() {
+                    for_each_nix_file(path, &add_file)
+                } else {
+                    add_file(path.clone())?
+                }
+            }
+            print!("{}", totals.into_inner().unwrap().report(&args.options));
+            check_internal_errors(failed)?;
+        }
+        Operation::Lint => {
+            reset_sigpipe()?;
+            silence_panics();
+            let failed = Mutex::new(0);
+            let files_with_warnings = match &args.src {
+                Src::Stdin => {
+                    let input = read_stdin_to_string()?;
+                    let output = catch_internal_error(Path::new("<stdin>"), &failed, || {
+                        lint_warnings(&input, "<stdin>", &args.options)
+                    })
+                    .unwrap_or_default();
+                    print!("{}", output);
+                    if output.is_empty() {
+                        0
+                    } else {
+                        1
+                    }
+                }
+                Src::Paths(paths) => {
+                    // Like for queries, the results are sorted by path.
+                    let results = Mutex::new(Vec::new());
+                    let lint_file = |path: PathBuf| -> Result<()> {
+                        let input = fs::read_to_string(&path)?;
+                        let output = catch_internal_error(&path, &failed, || {
+                            lint_warnings(&input, &path.display().to_string(), &args.options)
+                        });
+                        if let Some(output) = output {
+                            results.lock().unwrap().push((path, output));
+                        }
+                        Ok(())
+                    };
+                    for path in paths {
+                        if path.is_dir() {
+                            for_each_nix_file(path, &lint_file)
+                        } else {
+                            lint_file(path.clone())?
+                        }
+                    }
+                    let mut results = results.into_inner().unwrap();
+                    results.sort_by(|(a, _), (b, _)| a.cmp(b));
+                    for (_, output) in results.iter() {
+                        print!("{}", output);
+                    }
+                    let files_with_warnings =
+                        results.iter().filter(|(_, output)| !output.is_empty()).count();
+                    eprintln!("{} / {} have lint warnings", files_with_warnings, results.len());
+                    files_with_warnings
+                }
+            };
+            check_internal_errors(failed)?;
+            if files_with_warnings > 0 {
+                return Err("error: fail on lint warnings".into());
+            }
+        }
+        Operation::Query { ref query } => {
+            reset_sigpipe()?;
+            silence_panics();
+            let failed = Mutex::new(0);
+            let n_matches = match &args.src {
+                Src::Stdin => {
+                    let input = read_stdin_to_string()?;
+                    let (n_matches, output) =
+                        catch_internal_error(Path::new("<stdin>"), &failed, || {
+                            query_matches(query, "<stdin>", &input)
+                        })
+                        .unwrap_or_default();
+                    print!("{}", output);
+                    n_matches
+                }
+                Src::Paths(paths) => {
+                    // The files of a directory are searched in parallel, so the
+                    // results are sorted by path before printing.
+                    let results = Mutex::new(Vec::new());
+                    let search_file = |path: PathBuf| -> Result<()> {
+                        let input = fs::read_to_string(&path)?;
+                        let matches = catch_internal_error(&path, &failed, || {
+                            query_matches(query, &path.display().to_string(), &input)
+                        });
+                        if let Some((n_matches, output)) = matches {
+                            results.lock().unwrap().push((path, n_matches, output));
+                        }
+                        Ok(())
+                    };
+                    for path in paths {
+                        if path.is_dir() {
+                            for_each_nix_file(path, &search_file)
+                        } else {
+                            search_file(path.clone())?
+                        }
+                    }
+                    let mut results = results.into_inner().unwrap();
+                    results.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+                    let mut n_matches = 0;
+                    for (_, n, output) in results {
+                        n_matches += n;
+                        print!("{}", output);
+                    }
+                    n_matches
+                }
+            };
+            check_internal_errors(failed)?;
+            if n_matches == 0 {
+                return Err("no matches".into());
+            }
+        }
+        Operation::Rewrite { ref rewrites } => {
+            silence_panics();
+            let failed = Mutex::new(0);
+            let files_failed = Mutex::new(0);
+            match &args.src {
+                Src::Stdin => {
+                    reset_sigpipe()?;
+                    let input = read_stdin_to_string()?;
+                    let output = catch_internal_error(Path::new("<stdin>"), &failed, || {
+                        nixpkgs_fmt::rewrite_string(&input, rewrites, &args.options)
+                    });
+                    if let Some(output) = output {
+                        let output = output.map_err(|err| format!("error: <stdin>: {}", err))?;
+                        print!("{}", output);
+                    }
+                }
+                Src::Paths(paths) => {
+                    let options = &args.options;
+                    let files_count = Mutex::new(0);
+                    let files_changed = Mutex::new(0);
+                    let rewrite_file = |path: PathBuf| -> Result<()> {
+                        let input = fs::read_to_string(&path)?;
+                        let output = catch_internal_error(&path, &failed, || {
+                            nixpkgs_fmt::rewrite_string(&input, rewrites, options)
+                        });
+                        let output = match output {
+                            Some(it) => it.map_err(|err| format!("{}: {}", path.display(), err))?,
+                            None => return Ok(()),
+                        };
+                        *files_count.lock().unwrap() += 1;
+                        if input != output {
+                            fs::write(&path, output)?;
+                            *files_changed.lock().unwrap() += 1;
+                            println!("{}", path.display());
+                        }
+                        Ok(())
+                    };
+                    // Like in directories, the other files are still
+                    // rewritten when one of them fails.
+                    let rewrite_or_report = |path: PathBuf| -> Result<()> {
+                        if let Err(err) = rewrite_file(path) {
+                            eprintln!("error: {}", err);
+                            *files_failed.lock().unwrap() += 1;
+                        }
+                        Ok(())
+                    };
+                    for path in paths {
+                        if path.is_dir() {
+                            for_each_nix_file(path, &rewrite_or_report)
+                        } else {
+                            rewrite_or_report(path.clone())?
+                        }
+                    }
+                    eprintln!(
+                        "{} / {} have been rewritten",
+                        files_changed.into_inner().unwrap(),
+                        files_count.into_inner().unwrap()
+                    );
+                }
+            }
+            check_internal_errors(failed)?;
+            let files_failed = files_failed.into_inner().unwrap();
+            if files_failed > 0 {
+                return Err(
+                    format!("error: {} file(s) could not be rewritten", files_failed).into()
+                );
+            }
+        }
     };
 
     Ok(())
 }
 
+/// Prints the matches of `query` in `text` as `path:line:column: text`,
+/// returning their number. Only the first line of multiline matches is shown.
+fn query_matches(query: &nixpkgs_fmt::Query, path: &str, text: &str) -> (usize, String) {
+    let matches = query.matches(&rnix::parse(text).node());
+    let mut buf = String::new();
+    for node in matches.iter() {
+        let (line, column) = line_column(text, node.text_range().start());
+        let node_text = node.text().to_string();
+        let mut lines = node_text.lines();
+        let first_line = lines.next().unwrap_or_default();
+        let ellipsis = if lines.next().is_some() { " ..." } else { "" };
+        writeln!(buf, "{}:{}:{}: {}{}", path, line, column, first_line, ellipsis).unwrap();
+    }
+    (matches.len(), buf)
+}
+
+/// Prints the lint warnings for `text` as
+/// `path:line:column-line:column: warning[rule]: description`, where the end
+/// column is that of the last character of the range.
+fn lint_warnings(text: &str, path: &str, options: &FormatOptions) -> String {
+    let mut buf = String::new();
+    for warning in nixpkgs_fmt::lint(text, options) {
+        let (line, column) = line_column(text, warning.range.start());
+        let (end_line, end_column) = line_column(text, warning.range.end());
+        writeln!(
+            buf,
+            "{}:{}:{}-{}:{}: warning[{}]: {}",
+            path,
+            line,
+            column,
+            end_line,
+            end_column - 1,
+            warning.rule,
+            warning.description
+        )
+        .unwrap();
+    }
+    buf
+}
+
+fn list_rules() -> String {
+    let mut buf = String::new();
+    for rule in nixpkgs_fmt::rule_registry() {
+        writeln!(buf, "{} [{}]: {}", rule.id, rule.kind, rule.description).unwrap();
+        for (before, after) in rule.examples.iter() {
+            writeln!(buf, "  before:").unwrap();
+            for line in before.lines() {
+                writeln!(buf, "    {}", line).unwrap();
+            }
+            writeln!(buf, "  after:").unwrap();
+            for line in after.lines() {
+                writeln!(buf, "    {}", line).unwrap();
+            }
+        }
+        buf.push('\n');
+    }
+    buf
+}
+
+/// Prints each node as `(KIND start..end children)`, with the tokens of a node
+/// on the same line, unless the node has child nodes.
+fn dump_sexpr(root: &SyntaxNode) -> String {
+    fn go(node: &SyntaxNode, level: usize, buf: &mut String) {
+        write!(buf, "({:?} {}", node.kind(), range(node.text_range())).unwrap();
+        let nested = node.children().next().is_some();
+        for child in node.children_with_tokens() {
+            if nested {
+                write!(buf, "\n{:indent$}", "", indent = (level + 1) * 2).unwrap();
+            } else {
+                buf.push(' ');
+            }
+            match child {
+                NodeOrToken::Node(node) => go(&node, level + 1, buf),
+                NodeOrToken::Token(token) => write!(
+                    buf,
+                    "({:?} {} {:?})",
+                    token.kind(),
+                    range(token.text_range()),
+                    token.text()
+                )
+                .unwrap(),
+            }
+        }
+        buf.push(')');
+    }
+    let mut buf = String::new();
+    go(root, 0, &mut buf);
+    buf.push('\n');
+    buf
+}
+
+/// Prints the tree as a Graphviz graph, with boxes for nodes and plain text
+/// for tokens.
+fn dump_dot(root: &SyntaxNode) -> String {
+    fn escape(label: &str) -> String {
+        label.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+    let mut buf = String::from("digraph syntax {\n  node [shape=box, fontname=monospace];\n");
+    let mut ids = HashMap::new();
+    for (id, element) in root.descendants_with_tokens().enumerate() {
+        let label = match &element {
+            NodeOrToken::Node(node) => format!("{:?} {}", node.kind(), range(node.text_range())),
+            NodeOrToken::Token(token) => format!(
+                "{:?} {}\\n{}",
+                token.kind(),
+                range(token.text_range()),
+                escape(&format!("{:?}", token.text()))
+            ),
+        };
+        let shape = if element.as_token().is_some() { ", shape=plaintext" } else { "" };
+        writeln!(buf, "  n{} [label=\"{}\"{}];", id, label, shape).unwrap();
+        if let Some(parent) = element.parent() {
+            writeln!(buf, "  n{} -> n{};", ids[&parent], id).unwrap();
+        }
+        if let NodeOrToken::Node(node) = element {
+            ids.insert(node, id);
+        }
+    }
+    buf.push_str("}\n");
+    buf
+}
+
+/// Prints the nodes with the names of their `rnix::types`, labelling the
+/// children by their role in the parent, like `body:`. Tokens are omitted,
+/// except for the names and values of identifiers, literals and operators.
+fn dump_typed(root: &SyntaxNode) -> String {
+    fn go(node: &SyntaxNode, role: Option<&str>, level: usize, buf: &mut String) {
+        write!(buf, "{:indent$}", "", indent = level * 2).unwrap();
+        if let Some(role) = role {
+            write!(buf, "{}: ", role).unwrap();
+        }
+        let typed = ParsedType::try_from(node.clone()).ok();
+        match &typed {
+            Some(typed) => write!(buf, "{}", type_name(typed)).unwrap(),
+            None => write!(buf, "{:?}", node.kind()).unwrap(),
+        }
+        write!(buf, " {}", range(node.text_range())).unwrap();
+        match &typed {
+            Some(ParsedType::Ident(ident)) => write!(buf, " {:?}", ident.as_str()).unwrap(),
+            Some(ParsedType::Value(value)) => write!(buf, " {}", value.as_str()).unwrap(),
+            Some(ParsedType::BinOp(op)) => {
+                if let Some(operator) = op.operator() {
+                    write!(buf, " {:?}", operator).unwrap()
+                }
+            }
+            Some(ParsedType::UnaryOp(op)) => write!(buf, " {:?}", op.operator()).unwrap(),
+            Some(ParsedType::AttrSet(set)) if set.recursive() => buf.push_str(" rec"),
+            _ => {}
+        }
+        buf.push('\n');
+
+        let roles = nixpkgs_fmt::child_roles(node);
+        for child in node.children() {
+            let role = roles.iter().find(|(_, node)| *node == child);
+            go(&child, role.map(|(role, _)| *role), level + 1, buf);
+        }
+    }
+    let mut buf = String::new();
+    go(root, None, 0, &mut buf);
+    buf
+}
+
+fn type_name(typed: &ParsedType) -> &'static str {
+    match typed {
+        ParsedType::Apply(_) => "Apply",
+        ParsedType::Assert(_) => "Assert",
+        ParsedType::Key(_) => "Key",
+        ParsedType::Dynamic(_) => "Dynamic",
+        ParsedType::Error(_) => "Error",
+        ParsedType::Ident(_) => "Ident",
+        ParsedType::IfElse(_) => "IfElse",
+        ParsedType::Select(_) => "Select",
+        ParsedType::Inherit(_) => "Inherit",
+        ParsedType::InheritFrom(_) => "InheritFrom",
+        ParsedType::Lambda(_) => "Lambda",
+        ParsedType::LegacyLet(_) => "LegacyLet",
+        ParsedType::LetIn(_) => "LetIn",
+        ParsedType::List(_) => "List",
+        ParsedType::BinOp(_) => "BinOp",
+        ParsedType::OrDefault(_) => "OrDefault",
+        ParsedType::Paren(_) => "Paren",
+        ParsedType::PatBind(_) => "PatBind",
+        ParsedType::PatEntry(_) => "PatEntry",
+        ParsedType::Pattern(_) => "Pattern",
+        ParsedType::Root(_) => "Root",
+        ParsedType::AttrSet(_) => "AttrSet",
+        ParsedType::KeyValue(_) => "KeyValue",
+        ParsedType::Str(_) => "Str",
+        ParsedType::UnaryOp(_) => "UnaryOp",
+        ParsedType::Value(_) => "Value",
+        ParsedType::With(_) => "With",
+        ParsedType::PathWithInterpol(_) => "PathWithInterpol",
+    }
+}
+
+/// A syntax error of `--parse`, with its 1-based line and column, counted in
+/// characters. Errors at the end of the file point past the last character.
+struct SyntaxError {
+    message: String,
+    range: TextRange,
+    line: usize,
+    column: usize,
+}
+
+impl SyntaxError {
+    fn new(text: &str, error: &ParseError) -> SyntaxError {
+        let range = match error {
+            ParseError::Unexpected(range)
+            | ParseError::UnexpectedExtra(range)
+            | ParseError::UnexpectedWanted(_, range, _)
+            | ParseError::UnexpectedDoubleBind(range)
+            | ParseError::DuplicatedArgs(range, _) => *range,
+            // `UnexpectedEOF` and friends
+            _ => TextRange::empty(TextSize::of(text)),
+        };
+        let (line, column) = line_column(text, range.start());
+        SyntaxError { message: error.to_string(), range, line, column }
+    }
+
+    /// Renders the error like rustc does, with the line of the error and a
+    /// caret under the erroneous range.
+    fn render(&self, path: &str, text: &str) -> String {
+        let start = usize::from(self.range.start());
+        let line_start = text[..start].rfind('\n').map_or(0, |it| it + 1);
+        let line_end = text[start..].find('\n').map_or(text.len(), |it| start + it);
+        let end = usize::from(self.range.end()).min(line_end);
+        // Tabs are kept, so that the caret lines up with the source line.
+        let padding: String =
+            text[line_start..start].chars().map(|c| if c == '\t' { c } else { ' ' }).collect();
+        let carets = "^".repeat(text[start..end].chars().count().max(1));
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        let mut buf = String::new();
+        writeln!(buf, "error: {}", self.message).unwrap();
+        writeln!(buf, "{}--> {}:{}:{}", gutter, path, self.line, self.column).unwrap();
+        writeln!(buf, "{} |", gutter).unwrap();
+        writeln!(buf, "{} | {}", self.line, &text[line_start..line_end]).unwrap();
+        writeln!(buf, "{} | {}{}", gutter, padding, carets).unwrap();
+        buf
+    }
+
+    fn to_json(&self, path: &str) -> serde_json::Value {
+        serde_json::json!({
+            "message": self.message,
+            "path": path,
+            "range": [usize::from(self.range.start()), usize::from(self.range.end())],
+            "line": self.line,
+            "column": self.column,
+        })
+    }
+}
+
+/// The 1-based line and column of `offset`, counted in characters.
+fn line_column(text: &str, offset: TextSize) -> (usize, usize) {
+    let before = &text[..usize::from(offset)];
+    let line_start = before.rfind('\n').map_or(0, |it| it + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+fn range(range: TextRange) -> String {
+    format!("{}..{}", usize::from(range.start()), usize::from(range.end()))
+}
+
+fn test_rules(options: &FormatOptions) -> Result<()> {
+    let formatter = nixpkgs_fmt::Formatter::builder().options(options.clone()).build();
+    for conflict in formatter.conflicts() {
+        eprintln!("warning: {}", conflict);
+    }
+    let mut n_tests = 0;
+    let mut n_failed = 0;
+    for rule in options.custom_rules.iter() {
+        for (before, after) in rule.examples.iter() {
+            n_tests += 1;
+            let expected =
+                if after.ends_with('\n') { after.to_string() } else { format!("{}\n", after) };
+            let actual = nixpkgs_fmt::reformat_string_with_options(before, options);
+            let second_round = nixpkgs_fmt::reformat_string_with_options(&actual, options);
+            if actual == expected && second_round == actual {
+                continue;
+            }
+            n_failed += 1;
+            println!("FAILED: {}", rule.id);
+            println!("before:\n{}\nexpected:\n{}actual:\n{}", before, expected, actual);
+            if second_round != actual {
+                println!("formatting is not idempotent:\n{}", second_round);
+            }
+        }
+    }
+    eprintln!("{} / {} rule tests passed", n_tests - n_failed, n_tests);
+    if n_failed > 0 {
+        return Err("error: rule tests failed".into());
+    }
+    Ok(())
+}
+
+/// Reformats `input`, turning a panic of the formatter into an error message,
+/// so that a bug doesn't prevent formatting other files. Also returns the
+/// rules which matched the same elements.
+fn reformat_string(
+    input: &str,
+    options: &FormatOptions,
+) -> std::result::Result<(String, Vec<RuleConflict>), String> {
+    catch_panic(|| {
+        let formatter = nixpkgs_fmt::Formatter::builder().options(options.clone()).build();
+        formatter.reformat_string_with_conflicts(input)
+    })
+}
+
+/// Runs `f`, turning a panic into an error message.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> std::result::Result<T, String> {
+    // The library doesn't have any state which could be left inconsistent.
+    panic::catch_unwind(panic::AssertUnwindSafe(f)).map_err(|payload| {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic".to_string()
+        }
+    })
+}
+
+/// Like `catch_panic`, but reports the panic right away and counts it in
+/// `failed`, so that the other files are still processed.
+fn catch_internal_error<T>(path: &Path, failed: &Mutex<usize>, f: impl FnOnce() -> T) -> Option<T> {
+    match catch_panic(f) {
+        Ok(it) => Some(it),
+        Err(message) => {
+            report_internal_error(path, &message);
+            *failed.lock().unwrap() += 1;
+            None
+        }
+    }
+}
+
+fn check_internal_errors(failed: Mutex<usize>) -> Result<()> {
+    match failed.into_inner().unwrap() {
+        0 => Ok(()),
+        n => Err(InternalErrors(n).into()),
+    }
+}
+
+/// Panics while processing a file are reported by `report_internal_error`, so the
+/// default message is only shown when a backtrace is asked for.
+fn silence_panics() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if env::var_os("RUST_BACKTRACE").is_some() {
+            default_hook(info)
+        }
+    }));
+}
+
+/// Warns about rules which matched the same element while formatting `path`,
+/// as only one of them took effect.
+fn report_conflicts(path: &Path, conflicts: &[RuleConflict]) {
+    for conflict in conflicts {
+        eprintln!("warning: {}: {}", path.display(), conflict);
+    }
+}
+
+fn report_internal_error(path: &Path, message: &str) {
+    eprintln!("error: internal error while processing {}: {}", path.display(), message);
+    eprintln!(
+        "note: this is a bug in nixpkgs-fmt, please report it with the file attached. \
+         To reproduce it, run the same command with `RUST_BACKTRACE=1` on the file."
+    );
+}
+
+/// The sum of `RuleStats` over several files, for `--stats`.
+#[derive(Default)]
+struct StatsTotals {
+    files: usize,
+    /// The number of edits and of files with edits, by rule id.
+    edits: HashMap<String, (usize, usize)>,
+    unnamed_edits: (usize, usize),
+    default_indent: (usize, usize),
+}
+
+impl StatsTotals {
+    fn add(&mut self, stats: &RuleStats) {
+        fn add_count(total: &mut (usize, usize), count: usize) {
+            if count > 0 {
+                total.0 += count;
+                total.1 += 1;
+            }
+        }
+        self.files += 1;
+        for (id, &count) in stats.edits.iter() {
+            add_count(self.edits.entry(id.to_string()).or_default(), count)
+        }
+        add_count(&mut self.unnamed_edits, stats.unnamed_edits);
+        add_count(&mut self.default_indent, stats.default_indent);
+    }
+
+    /// Lists all the rules, including the ones which never fired, by the
+    /// number of edits.
+    fn report(&self, options: &FormatOptions) -> String {
+        let mut rules: Vec<(String, String)> = Vec::new();
+        // Lints are never applied, so they have no edits to count.
+        for rule in nixpkgs_fmt::rule_registry().into_iter().filter(|it| it.kind != RuleKind::Lint)
+        {
+            rules.push((rule.id.into_owned(), rule.kind.to_string()))
+        }
+        for rule in options.custom_rules.iter() {
+            rules.push((rule.id.clone(), RuleKind::Spacing.to_string()))
+        }
+        let mut rows: Vec<(String, String, (usize, usize))> = rules
+            .into_iter()
+            .map(|(id, kind)| {
+                let counts = self.edits.get(&id).copied().unwrap_or_default();
+                (id, kind, counts)
+            })
+            .collect();
+        if self.unnamed_edits.0 > 0 {
+            rows.push(("(unnamed)".to_string(), String::new(), self.unnamed_edits));
+        }
+        rows.sort_by_key(|&(_, _, (edits, _))| std::cmp::Reverse(edits));
+
+        let mut buf = String::new();
+        writeln!(buf, "{:<32} {:<12} {:>8} {:>8}", "RULE", "KIND", "EDITS", "FILES").unwrap();
+        for (id, kind, (edits, files)) in rows {
+            writeln!(buf, "{:<32} {:<12} {:>8} {:>8}", id, kind, edits, files).unwrap();
+        }
+        writeln!(
+            buf,
+            "\n{} elements in {} / {} files matched no indentation rule",
+            self.default_indent.0, self.default_indent.1, self.files
+        )
+        .unwrap();
+        buf
+    }
+}
+
 fn read_stdin_to_string() -> Result<String> {
     let mut buf = String::new();
     stdin().read_to_string(&mut buf)?;
     Ok(buf)
 }
 
-fn read_single_source(src: &Src) -> Result<String> {
+/// Reads the only source, returning its name for error messages together
+/// with its contents.
+fn read_single_source(src: &Src) -> Result<(String, String)> {
     let res = match src {
-        Src::Stdin => read_stdin_to_string()?,
+        Src::Stdin => ("<stdin>".to_string(), read_stdin_to_string()?),
         Src::Paths(paths) => {
             if paths.len() != 1 {
                 return Err("exactly one path required".into());
             }
-            fs::read_to_string(&paths[0])?
+            (paths[0].display().to_string(), fs::read_to_string(&paths[0])?)
         }
     };
     Ok(res)
@@ -240,8 +1174,20 @@ fn read_single_source(src: &Src) -> Result<String> {
 fn reformat_dir_in_place(
     dir: &Path,
     write_changes: bool,
+    options: &FormatOptions,
     sender: &Sender<FormatResult>,
 ) -> Result<()> {
+    for_each_nix_file(dir, &|path| {
+        let status = reformat_file(&path, write_changes, options)?;
+        sender.send((path, status))?;
+        Ok(())
+    });
+    Ok(())
+}
+
+/// Calls `f` with each `.nix` file in `dir`, from several threads. Errors are
+/// reported, without stopping the traversal.
+fn for_each_nix_file(dir: &Path, f: &(dyn Fn(PathBuf) -> Result<()> + Sync)) {
     let nix_file_types = {
         let mut builder = ignore::types::TypesBuilder::new();
         builder.add_defaults();
@@ -252,38 +1198,40 @@ fn reformat_dir_in_place(
         builder.build().unwrap()
     };
 
-    ignore::WalkBuilder::new(dir).types(nix_file_types).threads(8).build_parallel().run(
-        move || {
-            let s = sender.clone();
-            Box::new(move |entry| {
-                match reformat_dir_entry(entry, write_changes, &s) {
-                    Err(err) => eprintln!("error: {}", err),
-                    Ok(()) => {}
-                }
-                ignore::WalkState::Continue
-            })
-        },
-    );
-    Ok(())
+    ignore::WalkBuilder::new(dir).types(nix_file_types).threads(8).build_parallel().run(|| {
+        Box::new(move |entry| {
+            if let Err(err) = visit_dir_entry(entry, f) {
+                eprintln!("error: {}", err)
+            }
+            ignore::WalkState::Continue
+        })
+    });
 }
 
-fn reformat_dir_entry(
+fn visit_dir_entry(
     entry: std::result::Result<ignore::DirEntry, ignore::Error>,
-    write_changes: bool,
-    sender: &Sender<FormatResult>,
+    f: &(dyn Fn(PathBuf) -> Result<()> + Sync),
 ) -> Result<()> {
     let path = entry?.into_path();
     if !path.is_file() {
         return Ok(());
     }
-    let status = reformat_file(&path, write_changes)?;
-    sender.send((path, status))?;
-    Ok(())
+    f(path)
 }
 
-fn reformat_file(file: &Path, write_changes: bool) -> Result<FormatStatus> {
+fn reformat_file(
+    file: &Path,
+    write_changes: bool,
+    options: &FormatOptions,
+) -> Result<FormatStatus> {
     let input = fs::read_to_string(file)?;
-    let output = nixpkgs_fmt::reformat_string(&input);
+    let output = match reformat_string(&input, options) {
+        Ok((output, conflicts)) => {
+            report_conflicts(file, &conflicts);
+            output
+        }
+        Err(message) => return Ok(FormatStatus::InternalError(message)),
+    };
     if input != output {
         if write_changes {
             fs::write(file, &output)?;
@@ -292,3 +1240,33 @@ fn reformat_file(file: &Path, write_changes: bool) -> Result<FormatStatus> {
     }
     Ok(FormatStatus::NoChange)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_are_isolated_per_file() {
+        silence_panics();
+        let failed = Mutex::new(0);
+        let a = catch_internal_error(Path::new("a.nix"), &failed, || -> usize { panic!("test") });
+        let b = catch_internal_error(Path::new("b.nix"), &failed, || 92);
+        assert_eq!((a, b), (None, Some(92)));
+
+        let err = check_internal_errors(failed).unwrap_err();
+        assert_eq!(err.to_string(), "error: internal error while processing 1 file(s)");
+        assert_eq!(exit_code(&*err), EXIT_INTERNAL_ERROR);
+        assert_eq!(exit_code(&*Box::<dyn error::Error>::from("unknown rule")), 1);
+    }
+
+    #[test]
+    fn lint_warnings_have_ranges() {
+        let text = "{\n  a = stdenv.lib.id;\n  b = rec {\n    c = 1;\n  };\n}\n";
+        assert_eq!(
+            lint_warnings(text, "a.nix", &FormatOptions::default()),
+            "a.nix:2:7-2:16: warning[stdenv-lib]: Use lib instead of stdenv.lib\n\
+             a.nix:3:7-5:3: warning[unnecessary-rec]: \
+             No rec on sets which don't refer to their own attributes\n"
+        );
+    }
+}
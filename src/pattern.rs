@@ -1,15 +1,27 @@
-//! This module defines `Pattern`: a predicate over syntax elements
+//! This module defines `Pattern`: a predicate over syntax elements, and
+//! `Cond`: a predicate which is plain data, so that it can be printed and
+//! parsed back.
 use std::{
     collections::{HashMap, HashSet},
     fmt, iter, ops,
+    str::FromStr,
     sync::Arc,
 };
 
-use rnix::{SyntaxElement, SyntaxKind};
+use rnix::{
+    types::{Apply, TypedNode},
+    NixLanguage, NodeOrToken, SyntaxElement, SyntaxKind,
+    SyntaxKind::TOKEN_WHITESPACE,
+};
+
+use crate::tree_utils::{
+    next_non_whitespace_sibling, next_sibling, on_top_level, prev_non_whitespace_sibling,
+    prev_sibling, prev_token_sibling, walk_tokens,
+};
 
 /// A convenience function to convert something a pattern for use with `&` and
 /// `|` operators
-pub(crate) fn p(p: impl Into<Pattern>) -> Pattern {
+pub fn p(p: impl Into<Pattern>) -> Pattern {
     p.into()
 }
 
@@ -22,9 +34,23 @@ pub(crate) fn p(p: impl Into<Pattern>) -> Pattern {
 ///
 /// Currently, we liberally box predicates inside of `Pattern`s, as there's only
 /// a constant amount of patterns.
+///
+/// Besides the predicate, a pattern tracks what is statically known about the
+/// elements it matches, which allows to find overlapping rules without any
+/// input.
+///
+/// Patterns built from `SyntaxKind`s and `Cond`s only also remember the
+/// equivalent `Cond`, which is what their `Debug` impl prints.
 #[derive(Clone)]
-pub(crate) struct Pattern {
+pub struct Pattern {
     kinds: Option<HashSet<SyntaxKind>>,
+    /// The kinds of the parents of matching elements.
+    parent_kinds: Option<HashSet<SyntaxKind>>,
+    /// Whether the pattern matches every element allowed by `kinds` and
+    /// `parent_kinds`, that is, whether `pred` adds nothing to them.
+    exact: bool,
+    /// The same predicate as data, unless the pattern contains a closure.
+    cond: Option<Cond>,
     pred: Arc<dyn (Fn(&SyntaxElement) -> bool)>,
 }
 
@@ -36,7 +62,10 @@ impl AsRef<Pattern> for Pattern {
 
 impl fmt::Debug for Pattern {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str("Pattern { ... }")
+        match &self.cond {
+            Some(cond) => write!(f, "Pattern({})", cond),
+            None => f.write_str("Pattern { ... }"),
+        }
     }
 }
 
@@ -45,24 +74,60 @@ impl Pattern {
         kinds: Option<HashSet<SyntaxKind>>,
         pred: impl Fn(&SyntaxElement) -> bool + 'static,
     ) -> Pattern {
-        Pattern { kinds, pred: Arc::new(pred) }
+        Pattern { kinds, parent_kinds: None, exact: false, cond: None, pred: Arc::new(pred) }
     }
 
     fn filter_by_kind(kinds: impl Iterator<Item = SyntaxKind>) -> Pattern {
-        Pattern::new(Some(kinds.collect()), |_| true)
+        let kinds: Vec<SyntaxKind> = kinds.collect();
+        let cond = Some(Cond::Kind(kinds.clone()));
+        Pattern { exact: true, cond, ..Pattern::new(Some(kinds.into_iter().collect()), |_| true) }
     }
 
     /// Creates a pattern which matches the same elements as `self` with the
     /// additional constraint that their parent matches `parent`.
-    pub(crate) fn with_parent(self, parent: Pattern) -> Pattern {
-        let Pattern { kinds, pred } = self;
-        Pattern::new(kinds, move |element| {
+    pub fn with_parent(self, parent: Pattern) -> Pattern {
+        let Pattern { kinds, parent_kinds, exact, cond, pred } = self;
+        let parent_kinds = intersect(parent_kinds, parent.kinds.clone());
+        let exact = exact && parent.exact && parent.parent_kinds.is_none();
+        let cond = both(cond, parent.cond.clone(), |cond, parent_cond| {
+            cond & Cond::At(Nav::Parent, Box::new(parent_cond))
+        });
+        let res = Pattern::new(kinds, move |element| {
             (pred)(element) && element.parent().map(|it| parent.matches(&it.into())) == Some(true)
-        })
+        });
+        Pattern { parent_kinds, exact, cond, ..res }
+    }
+
+    /// Creates a pattern which matches the same elements as `self` with the
+    /// additional constraint that the next non-whitespace sibling matches
+    /// `next`.
+    pub(crate) fn with_next(self, next: Pattern) -> Pattern {
+        let cond = both(self.cond.clone(), next.cond.clone(), |cond, next_cond| {
+            cond & Cond::At(Nav::Next, Box::new(next_cond))
+        });
+        let res = self
+            & Pattern::new(None, move |element| {
+                next_non_whitespace_sibling(element).map(|it| next.matches(&it)) == Some(true)
+            });
+        Pattern { cond, ..res }
+    }
+
+    /// Creates a pattern which matches the same elements as `self` with the
+    /// additional constraint that the previous non-whitespace sibling matches
+    /// `prev`.
+    pub(crate) fn with_prev(self, prev: Pattern) -> Pattern {
+        let cond = both(self.cond.clone(), prev.cond.clone(), |cond, prev_cond| {
+            cond & Cond::At(Nav::Prev, Box::new(prev_cond))
+        });
+        let res = self
+            & Pattern::new(None, move |element| {
+                prev_non_whitespace_sibling(element).map(|it| prev.matches(&it)) == Some(true)
+            });
+        Pattern { cond, ..res }
     }
 
     /// Checks if this pattern matches an element
-    pub(crate) fn matches(&self, element: &SyntaxElement) -> bool {
+    pub fn matches(&self, element: &SyntaxElement) -> bool {
         if let Some(kinds) = self.kinds.as_ref() {
             if !kinds.contains(&element.kind()) {
                 return false;
@@ -70,19 +135,86 @@ impl Pattern {
         }
         (self.pred)(element)
     }
+
+    /// Checks if some element surely matches both patterns. This is only known
+    /// for patterns which don't depend on anything but the kinds of elements
+    /// and their parents.
+    pub(crate) fn surely_overlaps(&self, other: &Pattern) -> bool {
+        self.exact
+            && other.exact
+            && intersects(&self.kinds, &other.kinds)
+            && intersects(&self.parent_kinds, &other.parent_kinds)
+    }
+
+    /// The kinds of the elements matching the pattern, if the pattern matches
+    /// every element of these kinds. `None` means that it depends on more than
+    /// the kind of the element.
+    pub(crate) fn exact_kinds(&self) -> Option<&HashSet<SyntaxKind>> {
+        match (&self.kinds, &self.parent_kinds) {
+            (Some(kinds), None) if self.exact => Some(kinds),
+            _ => None,
+        }
+    }
+
+    /// Checks if the pattern depends on nothing but the kinds of elements and
+    /// their parents.
+    pub(crate) fn is_exact(&self) -> bool {
+        self.exact
+    }
+
+    /// The kinds outside of which no element matches.
+    pub(crate) fn kinds(&self) -> Option<&HashSet<SyntaxKind>> {
+        self.kinds.as_ref()
+    }
+}
+
+fn intersect(
+    lhs: Option<HashSet<SyntaxKind>>,
+    rhs: Option<HashSet<SyntaxKind>>,
+) -> Option<HashSet<SyntaxKind>> {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => Some(lhs.intersection(&rhs).cloned().collect()),
+        (Some(it), None) | (None, Some(it)) => Some(it),
+        (None, None) => None,
+    }
+}
+
+fn union(
+    lhs: Option<HashSet<SyntaxKind>>,
+    rhs: Option<HashSet<SyntaxKind>>,
+) -> Option<HashSet<SyntaxKind>> {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => Some(lhs.union(&rhs).cloned().collect()),
+        _ => None,
+    }
+}
+
+fn both(lhs: Option<Cond>, rhs: Option<Cond>, f: impl FnOnce(Cond, Cond) -> Cond) -> Option<Cond> {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => Some(f(lhs, rhs)),
+        _ => None,
+    }
+}
+
+fn intersects(lhs: &Option<HashSet<SyntaxKind>>, rhs: &Option<HashSet<SyntaxKind>>) -> bool {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => !lhs.is_disjoint(rhs),
+        (Some(it), None) | (None, Some(it)) => !it.is_empty(),
+        (None, None) => true,
+    }
 }
 
 /// `pat1 & pat2` operator
 impl ops::BitAnd for Pattern {
     type Output = Pattern;
     fn bitand(self, other: Pattern) -> Pattern {
-        let kinds = match (self.kinds, other.kinds) {
-            (Some(lhs), Some(rhs)) => Some(lhs.intersection(&rhs).cloned().collect::<HashSet<_>>()),
-            (Some(it), None) | (None, Some(it)) => Some(it),
-            (None, None) => None,
-        };
+        let kinds = intersect(self.kinds, other.kinds);
+        let parent_kinds = intersect(self.parent_kinds, other.parent_kinds);
+        let exact = self.exact && other.exact;
+        let cond = both(self.cond, other.cond, |lhs, rhs| lhs & rhs);
         let (p1, p2) = (self.pred, other.pred);
-        Pattern::new(kinds, move |element| p1(element) && p2(element))
+        let res = Pattern::new(kinds, move |element| p1(element) && p2(element));
+        Pattern { parent_kinds, exact, cond, ..res }
     }
 }
 
@@ -90,13 +222,21 @@ impl ops::BitAnd for Pattern {
 impl ops::BitOr for Pattern {
     type Output = Pattern;
     fn bitor(self, other: Pattern) -> Pattern {
+        // `(a, p) | (b, q)` is only described exactly by kinds `a | b` and
+        // parent kinds `p | q` if one of the sides is the same.
+        let exact = self.exact
+            && other.exact
+            && (self.kinds == other.kinds || self.parent_kinds == other.parent_kinds);
         let kinds = match (self.kinds, other.kinds) {
             (Some(lhs), Some(rhs)) => Some(lhs.union(&rhs).cloned().collect::<HashSet<_>>()),
             (Some(it), None) | (None, Some(it)) => Some(it),
             (None, None) => None,
         };
+        let parent_kinds = union(self.parent_kinds, other.parent_kinds);
+        let cond = both(self.cond, other.cond, |lhs, rhs| lhs | rhs);
         let (p1, p2) = (self.pred, other.pred);
-        Pattern::new(kinds, move |element| p1(element) || p2(element))
+        let res = Pattern::new(kinds, move |element| p1(element) || p2(element));
+        Pattern { parent_kinds, exact, cond, ..res }
     }
 }
 
@@ -136,6 +276,560 @@ macro_rules! from_array {
 }
 from_array!(0, 1, 2, 3, 4, 5, 6, 7, 8);
 
+/// Construct pattern from a condition.
+impl From<Cond> for Pattern {
+    fn from(cond: Cond) -> Pattern {
+        match cond {
+            Cond::Kind(kinds) => Pattern::from(&kinds[..]),
+            cond => {
+                let pred = cond.clone();
+                Pattern { cond: Some(cond), ..Pattern::new(None, move |it| pred.matches(it)) }
+            }
+        }
+    }
+}
+
+/// `Cond` is a predicate on `SyntaxElement`, like `Pattern`, but it is plain
+/// data rather than a closure.
+///
+/// Conditions are composed from a handful of primitives with `!`, `&` and `|`.
+/// They print as, and parse from, expressions like
+///
+/// ```text
+/// kind(NODE_LET_IN) & !before_token_has_newline & parent(multiline(until TOKEN_IN))
+/// ```
+///
+/// which is how they are written in rules files.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cond {
+    /// Matches every element.
+    Any,
+    /// The element is of one of the kinds.
+    Kind(Vec<SyntaxKind>),
+    /// The `Part` of the element spans several lines.
+    Multiline(Part),
+    /// The closest token among the preceding siblings contains a newline.
+    BeforeTokenHasNewline,
+    /// The element is not nested in anything but lambdas, function
+    /// applications, `with`, `assert`, `let`, `if` and parentheses.
+    TopLevel,
+    /// The element reached with `Nav` exists and matches the condition.
+    At(Nav, Box<Cond>),
+    /// Each of the child nodes matches the condition.
+    AllChildren(Box<Cond>),
+    Not(Box<Cond>),
+    And(Box<Cond>, Box<Cond>),
+    Or(Box<Cond>, Box<Cond>),
+}
+
+/// The part of an element checked by `Cond::Multiline`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part {
+    /// The whole element.
+    Element,
+    /// The text of the element up to the first token of the kind.
+    Until(SyntaxKind),
+    /// Like `Until`, but without the whitespace right before the token.
+    Before(SyntaxKind),
+}
+
+/// How to get from an element to a related one in `Cond::At`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nav {
+    Parent,
+    /// The previous sibling, skipping whitespace.
+    Prev,
+    /// The next sibling, skipping whitespace.
+    Next,
+    /// The previous sibling node, skipping tokens.
+    PrevNode,
+    /// The next sibling node, skipping tokens.
+    NextNode,
+    FirstChild,
+    LastChild,
+    /// The first child node of the kind.
+    ChildIs(SyntaxKind),
+    /// The element itself or its closest ancestor of the kind.
+    Closest(SyntaxKind),
+    /// The outermost ancestor of the kind, or the element itself.
+    Outermost(SyntaxKind),
+    /// The first node which is not of the kind, following the first children
+    /// from the element, like the leftmost operand of `a ++ b ++ c`.
+    Leftmost(SyntaxKind),
+    /// The argument of a function application with the index, counting from
+    /// zero, so that the element `f a b` has `b` as argument 1.
+    NthArg(usize),
+}
+
+impl Nav {
+    /// The navigations without a parameter.
+    const ALL: [Nav; 7] = [
+        Nav::Parent,
+        Nav::Prev,
+        Nav::Next,
+        Nav::PrevNode,
+        Nav::NextNode,
+        Nav::FirstChild,
+        Nav::LastChild,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Nav::Parent => "parent",
+            Nav::Prev => "prev",
+            Nav::Next => "next",
+            Nav::PrevNode => "prev_node",
+            Nav::NextNode => "next_node",
+            Nav::FirstChild => "first_child",
+            Nav::LastChild => "last_child",
+            Nav::ChildIs(_) => "child_is",
+            Nav::Closest(_) => "closest",
+            Nav::Outermost(_) => "outermost",
+            Nav::Leftmost(_) => "leftmost",
+            Nav::NthArg(_) => "nth_arg",
+        }
+    }
+
+    fn by_kind(name: &str, kind: SyntaxKind) -> Option<Nav> {
+        let res = match name {
+            "child_is" => Nav::ChildIs(kind),
+            "closest" => Nav::Closest(kind),
+            "outermost" => Nav::Outermost(kind),
+            "leftmost" => Nav::Leftmost(kind),
+            _ => return None,
+        };
+        Some(res)
+    }
+
+    fn apply(self, element: &SyntaxElement) -> Option<SyntaxElement> {
+        match self {
+            Nav::Parent => element.parent().map(|it| it.into()),
+            Nav::Prev => prev_non_whitespace_sibling(element),
+            Nav::Next => next_non_whitespace_sibling(element),
+            Nav::PrevNode => prev_sibling(element).map(|it| it.into()),
+            Nav::NextNode => next_sibling(element).map(|it| it.into()),
+            Nav::FirstChild => element.as_node()?.first_child().map(|it| it.into()),
+            Nav::LastChild => element.as_node()?.last_child().map(|it| it.into()),
+            Nav::ChildIs(kind) => {
+                element.as_node()?.children().find(|it| it.kind() == kind).map(|it| it.into())
+            }
+            Nav::Closest(kind) => {
+                element.ancestors().find(|it| it.kind() == kind).map(|it| it.into())
+            }
+            Nav::Outermost(kind) => {
+                element.ancestors().filter(|it| it.kind() == kind).last().map(|it| it.into())
+            }
+            Nav::Leftmost(kind) => {
+                let mut node = element.as_node()?.clone();
+                while node.kind() == kind {
+                    node = node.first_child()?;
+                }
+                Some(node.into())
+            }
+            Nav::NthArg(idx) => {
+                let mut apply = element.as_node().cloned().and_then(Apply::cast)?;
+                let mut args = vec![apply.value()?];
+                while let Some(inner) = apply.lambda().and_then(Apply::cast) {
+                    args.push(inner.value()?);
+                    apply = inner;
+                }
+                args.into_iter().rev().nth(idx).map(|it| it.into())
+            }
+        }
+    }
+}
+
+/// Matches every element.
+pub fn any() -> Cond {
+    Cond::Any
+}
+
+/// The element is of one of the `kinds`.
+pub fn kind(kinds: &[SyntaxKind]) -> Cond {
+    Cond::Kind(kinds.to_vec())
+}
+
+/// The element spans several lines.
+pub fn multiline() -> Cond {
+    Cond::Multiline(Part::Element)
+}
+
+/// The element spans several lines before its first `kind` token.
+pub fn multiline_until(kind: SyntaxKind) -> Cond {
+    Cond::Multiline(Part::Until(kind))
+}
+
+/// The element spans several lines before the whitespace preceding its first
+/// `kind` token.
+pub fn multiline_before(kind: SyntaxKind) -> Cond {
+    Cond::Multiline(Part::Before(kind))
+}
+
+/// The closest token among the preceding siblings contains a newline.
+pub fn before_token_has_newline() -> Cond {
+    Cond::BeforeTokenHasNewline
+}
+
+/// The element is not nested in anything but lambdas, function applications,
+/// `with`, `assert`, `let`, `if` and parentheses.
+pub fn top_level() -> Cond {
+    Cond::TopLevel
+}
+
+pub fn parent(cond: Cond) -> Cond {
+    Cond::At(Nav::Parent, Box::new(cond))
+}
+
+pub fn prev(cond: Cond) -> Cond {
+    Cond::At(Nav::Prev, Box::new(cond))
+}
+
+pub fn next(cond: Cond) -> Cond {
+    Cond::At(Nav::Next, Box::new(cond))
+}
+
+pub fn prev_node(cond: Cond) -> Cond {
+    Cond::At(Nav::PrevNode, Box::new(cond))
+}
+
+pub fn next_node(cond: Cond) -> Cond {
+    Cond::At(Nav::NextNode, Box::new(cond))
+}
+
+pub fn first_child(cond: Cond) -> Cond {
+    Cond::At(Nav::FirstChild, Box::new(cond))
+}
+
+pub fn last_child(cond: Cond) -> Cond {
+    Cond::At(Nav::LastChild, Box::new(cond))
+}
+
+pub fn all_children(cond: Cond) -> Cond {
+    Cond::AllChildren(Box::new(cond))
+}
+
+/// The first child node of the `kind` matches the condition.
+pub fn child_is(kind: SyntaxKind, cond: Cond) -> Cond {
+    Cond::At(Nav::ChildIs(kind), Box::new(cond))
+}
+
+/// The element or its closest ancestor of the `kind` matches the condition.
+pub fn closest(kind: SyntaxKind, cond: Cond) -> Cond {
+    Cond::At(Nav::Closest(kind), Box::new(cond))
+}
+
+/// The outermost element of the `kind` among the element and its ancestors
+/// matches the condition.
+pub fn outermost(kind: SyntaxKind, cond: Cond) -> Cond {
+    Cond::At(Nav::Outermost(kind), Box::new(cond))
+}
+
+/// The first node which is not of the `kind`, following the first children
+/// from the element, matches the condition.
+pub fn leftmost(kind: SyntaxKind, cond: Cond) -> Cond {
+    Cond::At(Nav::Leftmost(kind), Box::new(cond))
+}
+
+/// The element is a function application whose argument with the index,
+/// counting from zero, matches the condition.
+pub fn nth_arg(idx: usize, cond: Cond) -> Cond {
+    Cond::At(Nav::NthArg(idx), Box::new(cond))
+}
+
+impl Cond {
+    /// Checks if this condition holds for an element
+    pub fn matches(&self, element: &SyntaxElement) -> bool {
+        match self {
+            Cond::Any => true,
+            Cond::Kind(kinds) => kinds.contains(&element.kind()),
+            Cond::Multiline(part) => {
+                let until = match part {
+                    Part::Element => None,
+                    Part::Until(kind) | Part::Before(kind) => Some(*kind),
+                };
+                let tokens: Box<dyn Iterator<Item = _>> = match element {
+                    NodeOrToken::Node(node) => Box::new(walk_tokens(node)),
+                    NodeOrToken::Token(token) => Box::new(iter::once(token.clone())),
+                };
+                let mut tokens = tokens.take_while(|it| Some(it.kind()) != until).peekable();
+                // Stops at the first newline, as the elements can be large.
+                while let Some(token) = tokens.next() {
+                    // The whitespace right before `kind` doesn't count for
+                    // `multiline(before kind)`.
+                    let is_excluded = matches!(part, Part::Before(_))
+                        && token.kind() == TOKEN_WHITESPACE
+                        && tokens.peek().is_none();
+                    if !is_excluded && token.text().contains('\n') {
+                        return true;
+                    }
+                }
+                false
+            }
+            Cond::BeforeTokenHasNewline => {
+                prev_token_sibling(element).map(|it| it.text().contains('\n')) == Some(true)
+            }
+            Cond::TopLevel => on_top_level(element),
+            Cond::At(nav, cond) => nav.apply(element).map(|it| cond.matches(&it)) == Some(true),
+            Cond::AllChildren(cond) => match element {
+                NodeOrToken::Node(node) => node.children().all(|it| cond.matches(&it.into())),
+                NodeOrToken::Token(_) => true,
+            },
+            Cond::Not(cond) => !cond.matches(element),
+            Cond::And(lhs, rhs) => lhs.matches(element) && rhs.matches(element),
+            Cond::Or(lhs, rhs) => lhs.matches(element) || rhs.matches(element),
+        }
+    }
+
+    /// Prints the condition, adding parentheses if its operator binds less
+    /// tightly than `precedence`.
+    fn fmt_prec(&self, f: &mut fmt::Formatter<'_>, precedence: u8) -> fmt::Result {
+        let (own, lhs, op, rhs) = match self {
+            Cond::Or(lhs, rhs) => (0, lhs, " | ", rhs),
+            Cond::And(lhs, rhs) => (1, lhs, " & ", rhs),
+            Cond::Not(cond) => {
+                f.write_str("!")?;
+                return cond.fmt_prec(f, 2);
+            }
+            Cond::Any => return f.write_str("any"),
+            Cond::Kind(kinds) => {
+                let kinds: Vec<String> = kinds.iter().map(|it| format!("{:?}", it)).collect();
+                return write!(f, "kind({})", kinds.join(", "));
+            }
+            Cond::Multiline(Part::Element) => return f.write_str("multiline"),
+            Cond::Multiline(Part::Until(kind)) => return write!(f, "multiline(until {:?})", kind),
+            Cond::Multiline(Part::Before(kind)) => {
+                return write!(f, "multiline(before {:?})", kind)
+            }
+            Cond::BeforeTokenHasNewline => return f.write_str("before_token_has_newline"),
+            Cond::TopLevel => return f.write_str("top_level"),
+            Cond::At(nav, cond) => {
+                // The condition after a parameter is optional.
+                let param = match nav {
+                    Nav::ChildIs(kind)
+                    | Nav::Closest(kind)
+                    | Nav::Outermost(kind)
+                    | Nav::Leftmost(kind) => format!("{:?}", kind),
+                    Nav::NthArg(idx) => idx.to_string(),
+                    _ => return write!(f, "{}({})", nav.name(), cond),
+                };
+                return match **cond {
+                    Cond::Any => write!(f, "{}({})", nav.name(), param),
+                    _ => write!(f, "{}({}, {})", nav.name(), param, cond),
+                };
+            }
+            Cond::AllChildren(cond) => return write!(f, "all_children({})", cond),
+        };
+        if own < precedence {
+            f.write_str("(")?;
+        }
+        lhs.fmt_prec(f, own)?;
+        f.write_str(op)?;
+        rhs.fmt_prec(f, own + 1)?;
+        if own < precedence {
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_prec(f, 0)
+    }
+}
+
+impl ops::Not for Cond {
+    type Output = Cond;
+    fn not(self) -> Cond {
+        Cond::Not(Box::new(self))
+    }
+}
+
+impl ops::BitAnd for Cond {
+    type Output = Cond;
+    fn bitand(self, other: Cond) -> Cond {
+        Cond::And(Box::new(self), Box::new(other))
+    }
+}
+
+impl ops::BitOr for Cond {
+    type Output = Cond;
+    fn bitor(self, other: Cond) -> Cond {
+        Cond::Or(Box::new(self), Box::new(other))
+    }
+}
+
+/// Parses a condition in the syntax of its `Display` impl.
+impl FromStr for Cond {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Cond, String> {
+        let mut parser = CondParser { tokens: tokenize(text)?, pos: 0 };
+        let res = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(res),
+            Some(token) => Err(format!("unexpected `{}`", token)),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut res = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if "()!&|,".contains(c) {
+            res.push(c.to_string());
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let mut end = start + c.len_utf8();
+            while let Some(&(idx, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = idx + c.len_utf8();
+                chars.next();
+            }
+            res.push(text[start..end].to_string());
+        } else {
+            return Err(format!("unexpected `{}`", c));
+        }
+    }
+    Ok(res)
+}
+
+struct CondParser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl CondParser {
+    fn or(&mut self) -> Result<Cond, String> {
+        let mut res = self.and()?;
+        while self.eat("|") {
+            res = res | self.and()?;
+        }
+        Ok(res)
+    }
+
+    fn and(&mut self) -> Result<Cond, String> {
+        let mut res = self.unary()?;
+        while self.eat("&") {
+            res = res & self.unary()?;
+        }
+        Ok(res)
+    }
+
+    fn unary(&mut self) -> Result<Cond, String> {
+        if self.eat("!") {
+            return Ok(!self.unary()?);
+        }
+        if self.eat("(") {
+            let res = self.or()?;
+            self.expect(")")?;
+            return Ok(res);
+        }
+        let name = self.next().ok_or_else(|| "unexpected end of condition".to_string())?;
+        let res = match name.as_str() {
+            "any" => any(),
+            "before_token_has_newline" => before_token_has_newline(),
+            "top_level" => top_level(),
+            "nth_arg" => {
+                self.expect("(")?;
+                let idx = self.next().ok_or_else(|| "expected an index".to_string())?;
+                let idx = idx.parse().map_err(|_| format!("invalid index: {}", idx))?;
+                self.parameter_argument(Nav::NthArg(idx))?
+            }
+            "multiline" if self.eat("(") => {
+                let res = match self.next().as_deref() {
+                    Some("until") => multiline_until(self.kind()?),
+                    Some("before") => multiline_before(self.kind()?),
+                    _ => return Err("expected `until` or `before`".to_string()),
+                };
+                self.expect(")")?;
+                res
+            }
+            "multiline" => multiline(),
+            "kind" => {
+                self.expect("(")?;
+                let mut kinds = vec![self.kind()?];
+                while self.eat(",") {
+                    kinds.push(self.kind()?);
+                }
+                self.expect(")")?;
+                Cond::Kind(kinds)
+            }
+            "all_children" => all_children(self.argument()?),
+            "child_is" | "closest" | "outermost" | "leftmost" => {
+                self.expect("(")?;
+                // unwrap justification: the names are matched above
+                let nav = Nav::by_kind(&name, self.kind()?).unwrap();
+                self.parameter_argument(nav)?
+            }
+            name => match Nav::ALL.iter().find(|it| it.name() == name) {
+                Some(&nav) => Cond::At(nav, Box::new(self.argument()?)),
+                None => return Err(format!("unknown condition: {}", name)),
+            },
+        };
+        Ok(res)
+    }
+
+    /// The optional condition after the parameter of `nav`, and the closing
+    /// parenthesis.
+    fn parameter_argument(&mut self, nav: Nav) -> Result<Cond, String> {
+        let cond = if self.eat(",") { self.or()? } else { any() };
+        self.expect(")")?;
+        Ok(Cond::At(nav, Box::new(cond)))
+    }
+
+    fn argument(&mut self) -> Result<Cond, String> {
+        self.expect("(")?;
+        let res = self.or()?;
+        self.expect(")")?;
+        Ok(res)
+    }
+
+    fn kind(&mut self) -> Result<SyntaxKind, String> {
+        let name = self.next().ok_or_else(|| "expected a syntax kind".to_string())?;
+        kind_by_name(&name).ok_or_else(|| format!("unknown syntax kind: {}", name))
+    }
+
+    fn next(&mut self) -> Option<String> {
+        let res = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        res
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let res = self.tokens.get(self.pos).map(|it| it == token) == Some(true);
+        if res {
+            self.pos += 1;
+        }
+        res
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.eat(token) {
+            return Ok(());
+        }
+        match self.tokens.get(self.pos) {
+            Some(other) => Err(format!("expected `{}`, found `{}`", token, other)),
+            None => Err(format!("expected `{}`", token)),
+        }
+    }
+}
+
+/// Finds a `SyntaxKind` by its name, like `NODE_LET_IN`.
+pub(crate) fn kind_by_name(name: &str) -> Option<SyntaxKind> {
+    use rowan::Language;
+    (0..SyntaxKind::__LAST as u16)
+        .map(|raw| NixLanguage::kind_from_raw(rowan::SyntaxKind(raw)))
+        .find(|kind| format!("{:?}", kind) == name)
+}
+
 /// `PatternSet` allows to match many patterns at the same time efficiently.
 ///
 /// This is generic over `P: AsRef<Pattern>`, so it works with any type which
@@ -176,3 +870,71 @@ impl<'a, P: AsRef<Pattern>> PatternSet<&'a P> {
             .filter(move |p| p.as_ref().matches(&element))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rnix::{SyntaxKind::*, T};
+
+    use super::*;
+
+    #[test]
+    fn conds_round_trip_through_text() {
+        let cond = !before_token_has_newline()
+            & parent(kind(&[NODE_KEY_VALUE, NODE_LAMBDA]))
+            & (multiline_before(T![in]) | next(any()) & !multiline());
+        let text = cond.to_string();
+        assert_eq!(
+            text,
+            "!before_token_has_newline & parent(kind(NODE_KEY_VALUE, NODE_LAMBDA)) \
+             & (multiline(before TOKEN_IN) | next(any) & !multiline)"
+        );
+        assert_eq!(text.parse::<Cond>(), Ok(cond));
+
+        let cond = closest(NODE_KEY_VALUE, child_is(NODE_LAMBDA, any()))
+            | nth_arg(1, leftmost(NODE_BIN_OP, top_level()))
+            | outermost(NODE_APPLY, !before_token_has_newline());
+        let text = cond.to_string();
+        assert_eq!(
+            text,
+            "closest(NODE_KEY_VALUE, child_is(NODE_LAMBDA)) \
+             | nth_arg(1, leftmost(NODE_BIN_OP, top_level)) \
+             | outermost(NODE_APPLY, !before_token_has_newline)"
+        );
+        assert_eq!(text.parse::<Cond>(), Ok(cond));
+
+        assert_eq!("kind(NODE_FOO)".parse::<Cond>(), Err("unknown syntax kind: NODE_FOO".into()));
+        assert_eq!("nth_arg(x)".parse::<Cond>(), Err("invalid index: x".into()));
+        assert_eq!("parent(any".parse::<Cond>(), Err("expected `)`".into()));
+        assert_eq!("any any".parse::<Cond>(), Err("unexpected `any`".into()));
+    }
+
+    #[test]
+    fn conds_match_elements() {
+        let root = rnix::parse("let\n  a = 1;\nin { b = let c = 2;\n  in c; }").node();
+        let let_in: Vec<SyntaxElement> =
+            root.descendants().filter(|it| it.kind() == NODE_LET_IN).map(|it| it.into()).collect();
+        let check = |cond: &str| -> Vec<bool> {
+            let cond: Cond = cond.parse().unwrap();
+            let_in.iter().map(|it| cond.matches(it)).collect()
+        };
+        assert_eq!(check("multiline(until TOKEN_IN)"), [true, true]);
+        assert_eq!(check("multiline(before TOKEN_IN)"), [true, false]);
+        assert_eq!(check("parent(kind(NODE_KEY_VALUE)) & prev(kind(TOKEN_ASSIGN))"), [false, true]);
+        assert_eq!(check("last_child(kind(NODE_ATTR_SET))"), [true, false]);
+        assert_eq!(check("top_level"), [true, false]);
+        assert_eq!(check("closest(NODE_KEY_VALUE, child_is(NODE_KEY))"), [false, true]);
+        assert_eq!(check("outermost(NODE_LET_IN, multiline(before TOKEN_IN))"), [true, true]);
+
+        let root = rnix::parse("f (a ++ b) c").node();
+        let apply: SyntaxElement = root.first_child().unwrap().into();
+        let check = |cond: &str| cond.parse::<Cond>().unwrap().matches(&apply);
+        assert!(check("nth_arg(0, kind(NODE_PAREN))"));
+        assert!(check("nth_arg(1, kind(NODE_IDENT))"));
+        assert!(!check("nth_arg(2)"));
+        assert!(check("nth_arg(0, first_child(leftmost(NODE_BIN_OP, kind(NODE_IDENT))))"));
+        assert_eq!(
+            format!("{:?}", p(NODE_LET_IN).with_parent(p(NODE_ROOT))),
+            "Pattern(kind(NODE_LET_IN) & parent(kind(NODE_ROOT)))"
+        );
+    }
+}
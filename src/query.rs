@@ -0,0 +1,332 @@
+//! Structural queries over the syntax tree, like
+//! `NODE_KEY_VALUE[key=version] > NODE_STRING`.
+//!
+//! A query is a chain of steps, each of which matches a node by its kind and
+//! by the text of its children, in the CSS selector syntax: `A B` matches `B`
+//! inside of `A`, and `A > B` matches `B` directly inside of `A`.
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use rnix::{
+    types::{ParsedType, TypedNode, Wrapper},
+    SyntaxKind, SyntaxNode,
+};
+
+use crate::pattern::kind_by_name;
+
+/// A parsed query, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    /// The first combinator is always `Combinator::Descendant`.
+    steps: Vec<(Combinator, Step)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+/// `KIND[filter]...`, where the kind may be `*` to match any node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Step {
+    kind: Option<SyntaxKind>,
+    filters: Vec<Filter>,
+}
+
+/// `[role]`, `[role=text]` or `[role*=text]`, where the role names a child of
+/// the node, see `child_roles`, or is `text` for the node itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    role: String,
+    op: Option<(FilterOp, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilterOp {
+    Equals,
+    Contains,
+}
+
+impl Query {
+    /// Returns the nodes in `root` which match the query, in the order of
+    /// the text.
+    pub fn matches(&self, root: &SyntaxNode) -> Vec<SyntaxNode> {
+        root.descendants().filter(|node| self.matches_at(node, self.steps.len() - 1)).collect()
+    }
+
+    fn matches_at(&self, node: &SyntaxNode, idx: usize) -> bool {
+        let (combinator, step) = &self.steps[idx];
+        if !step.matches(node) {
+            return false;
+        }
+        if idx == 0 {
+            return true;
+        }
+        match combinator {
+            Combinator::Child => {
+                node.parent().map(|it| self.matches_at(&it, idx - 1)) == Some(true)
+            }
+            Combinator::Descendant => {
+                node.ancestors().skip(1).any(|it| self.matches_at(&it, idx - 1))
+            }
+        }
+    }
+}
+
+impl Step {
+    fn matches(&self, node: &SyntaxNode) -> bool {
+        if matches!(self.kind, Some(kind) if kind != node.kind()) {
+            return false;
+        }
+        self.filters.iter().all(|filter| {
+            let target = if filter.role == "text" {
+                Some(node.clone())
+            } else {
+                child_roles(node).into_iter().find(|(role, _)| *role == filter.role).map(|it| it.1)
+            };
+            match (target, &filter.op) {
+                (None, _) => false,
+                (Some(_), None) => true,
+                (Some(target), Some((FilterOp::Equals, text))) => target.text() == text.as_str(),
+                (Some(target), Some((FilterOp::Contains, text))) => {
+                    target.text().to_string().contains(text.as_str())
+                }
+            }
+        })
+    }
+}
+
+/// Returns the children of `node` which have a name in `rnix::types`, like
+/// the `body` of a `Lambda`.
+pub fn child_roles(node: &SyntaxNode) -> Vec<(&'static str, SyntaxNode)> {
+    let typed = match ParsedType::try_from(node.clone()) {
+        Ok(it) => it,
+        Err(_) => return Vec::new(),
+    };
+    let roles = match typed {
+        ParsedType::Apply(it) => vec![("lambda", it.lambda()), ("value", it.value())],
+        ParsedType::Assert(it) => vec![("condition", it.condition()), ("body", it.body())],
+        ParsedType::IfElse(it) => {
+            vec![("condition", it.condition()), ("body", it.body()), ("else_body", it.else_body())]
+        }
+        ParsedType::Select(it) => vec![("set", it.set()), ("index", it.index())],
+        ParsedType::Lambda(it) => vec![("arg", it.arg()), ("body", it.body())],
+        ParsedType::LetIn(it) => vec![("body", it.body())],
+        ParsedType::BinOp(it) => vec![("lhs", it.lhs()), ("rhs", it.rhs())],
+        ParsedType::OrDefault(it) => {
+            vec![("index", it.index().map(|it| it.node().clone())), ("default", it.default())]
+        }
+        ParsedType::PatEntry(it) => {
+            vec![("name", it.name().map(|it| it.node().clone())), ("default", it.default())]
+        }
+        ParsedType::KeyValue(it) => {
+            vec![("key", it.key().map(|it| it.node().clone())), ("value", it.value())]
+        }
+        ParsedType::UnaryOp(it) => vec![("value", it.value())],
+        ParsedType::Paren(it) => vec![("inner", it.inner())],
+        ParsedType::Dynamic(it) => vec![("inner", it.inner())],
+        ParsedType::With(it) => vec![("namespace", it.namespace()), ("body", it.body())],
+        _ => Vec::new(),
+    };
+    roles.into_iter().filter_map(|(role, node)| Some((role, node?))).collect()
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, (combinator, step)) in self.steps.iter().enumerate() {
+            match (idx, combinator) {
+                (0, _) => {}
+                (_, Combinator::Descendant) => f.write_str(" ")?,
+                (_, Combinator::Child) => f.write_str(" > ")?,
+            }
+            match step.kind {
+                Some(kind) => write!(f, "{:?}", kind)?,
+                None => f.write_str("*")?,
+            }
+            for filter in step.filters.iter() {
+                write!(f, "[{}", filter.role)?;
+                match &filter.op {
+                    None => {}
+                    Some((FilterOp::Equals, text)) => write!(f, "={:?}", text)?,
+                    Some((FilterOp::Contains, text)) => write!(f, "*={:?}", text)?,
+                }
+                f.write_str("]")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Query {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Query, String> {
+        let mut parser = QueryParser { text, pos: 0 };
+        let mut steps = Vec::new();
+        parser.skip_whitespace();
+        loop {
+            let combinator = if steps.is_empty() {
+                Combinator::Descendant
+            } else if parser.eat('>') {
+                parser.skip_whitespace();
+                Combinator::Child
+            } else {
+                Combinator::Descendant
+            };
+            steps.push((combinator, parser.step()?));
+            let had_whitespace = parser.skip_whitespace();
+            match parser.peek() {
+                None => break,
+                Some('>') => {}
+                Some(_) if had_whitespace => {}
+                Some(c) => return Err(format!("unexpected `{}`", c)),
+            }
+        }
+        Ok(Query { steps })
+    }
+}
+
+struct QueryParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl QueryParser<'_> {
+    fn step(&mut self) -> Result<Step, String> {
+        let kind = if self.eat('*') {
+            None
+        } else {
+            let name = self.word();
+            if name.is_empty() {
+                return Err(match self.peek() {
+                    Some(c) => format!("unexpected `{}`", c),
+                    None => "unexpected end of query".to_string(),
+                });
+            }
+            let kind =
+                kind_by_name(name).ok_or_else(|| format!("unknown syntax kind: {}", name))?;
+            if name.starts_with("TOKEN_") {
+                return Err(format!("only nodes can be queried, not {}", name));
+            }
+            Some(kind)
+        };
+        let mut filters = Vec::new();
+        while self.eat('[') {
+            let role = self.word().to_string();
+            if role.is_empty() {
+                return Err("expected a role".to_string());
+            }
+            let op = if self.eat('=') {
+                Some((FilterOp::Equals, self.value()?))
+            } else if self.eat('*') {
+                if !self.eat('=') {
+                    return Err("expected `=` after `*`".to_string());
+                }
+                Some((FilterOp::Contains, self.value()?))
+            } else {
+                None
+            };
+            if !self.eat(']') {
+                return Err("expected `]`".to_string());
+            }
+            filters.push(Filter { role, op });
+        }
+        Ok(Step { kind, filters })
+    }
+
+    /// Either a string in double quotes, with `\"` and `\\` escapes, or the
+    /// text up to the closing bracket.
+    fn value(&mut self) -> Result<String, String> {
+        if !self.eat('"') {
+            let rest = &self.text[self.pos..];
+            let len = rest.find(']').unwrap_or(rest.len());
+            self.pos += len;
+            return Ok(rest[..len].to_string());
+        }
+        let mut res = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += idx + 1;
+                    return Ok(res);
+                }
+                '\\' => match chars.next() {
+                    Some((_, c)) => res.push(c),
+                    None => break,
+                },
+                c => res.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn word(&mut self) -> &str {
+        let rest = &self.text[self.pos..];
+        let len =
+            rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let rest = &self.text[self.pos..];
+        let len = rest.len() - rest.trim_start().len();
+        self.pos += len;
+        len > 0
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let res = self.peek() == Some(c);
+        if res {
+            self.pos += c.len_utf8();
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(query: &str, text: &str) -> Vec<String> {
+        let query: Query = query.parse().unwrap();
+        let root = rnix::parse(text).node();
+        query.matches(&root).iter().map(|it| it.text().to_string()).collect()
+    }
+
+    #[test]
+    fn matches_nodes() {
+        let text = r#"{
+  version = "1.0";
+  src = fetchurl { url = "https://example.com/${version}.tar.gz"; sha256 = "abc"; };
+  meta.version = 2;
+}"#;
+        assert_eq!(query("NODE_KEY_VALUE[key=version] > NODE_STRING", text), vec!["\"1.0\""]);
+        assert_eq!(query("NODE_KEY_VALUE[key*=version]", text).len(), 2);
+        assert_eq!(
+            query("NODE_APPLY[lambda=fetchurl] NODE_KEY_VALUE[key=sha256] > *", text),
+            vec!["sha256", "\"abc\""]
+        );
+        assert_eq!(query("NODE_APPLY[lambda=fetchgit]", text), Vec::<String>::new());
+        assert_eq!(query("NODE_STRING[text=\"\\\"abc\\\"\"]", text), vec!["\"abc\""]);
+    }
+
+    #[test]
+    fn queries_round_trip_through_text() {
+        for text in &[
+            "NODE_KEY_VALUE[key=\"version\"] > NODE_STRING",
+            "NODE_APPLY[lambda=\"fetchurl\"] NODE_KEY_VALUE[key*=\"sha\"][value]",
+            "*",
+        ] {
+            let query: Query = text.parse().unwrap();
+            assert_eq!(query.to_string(), *text);
+        }
+        assert_eq!("NODE_KEY_VALUE[key".parse::<Query>(), Err("expected `]`".to_string()));
+        assert_eq!("NODE_FOO".parse::<Query>(), Err("unknown syntax kind: NODE_FOO".to_string()));
+        assert_eq!("NODE_LIST)".parse::<Query>(), Err("unexpected `)`".to_string()));
+    }
+}
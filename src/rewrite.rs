@@ -0,0 +1,229 @@
+//! Structural search and replace, like
+//! `NODE_SELECT[text=stdenv.lib] => lib`.
+//!
+//! The nodes which match the query on the left are replaced with the template
+//! on the right, where `$role` stands for the text of a child of the node, see
+//! `child_roles`, and `$text` for the text of the node itself. `$$` is a
+//! literal `$`, and other dollar signs, like in `${version}`, are kept as is.
+use std::{fmt, str::FromStr};
+
+use rnix::{SyntaxNode, TextRange, TextSize};
+
+use crate::{query::child_roles, AtomEdit, Query};
+
+/// A parsed rewrite, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    query: Query,
+    template: String,
+}
+
+impl Rewrite {
+    /// Returns the edits which replace the matches of the query in `root`,
+    /// sorted by their ranges. Matches inside of other matches are skipped,
+    /// as are the matches without a child which the template refers to.
+    pub fn edits(&self, root: &SyntaxNode) -> Vec<AtomEdit> {
+        let mut res: Vec<AtomEdit> = Vec::new();
+        for node in self.query.matches(root) {
+            let range = node.text_range();
+            if res.last().map(|prev| prev.delete.contains_range(range)) == Some(true) {
+                continue;
+            }
+            if let Some(insert) = self.expand(&node) {
+                res.push(AtomEdit { delete: range, insert: insert.into() })
+            }
+        }
+        res
+    }
+
+    fn expand(&self, node: &SyntaxNode) -> Option<String> {
+        let roles = child_roles(node);
+        let mut res = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(idx) = rest.find('$') {
+            res.push_str(&rest[..idx]);
+            rest = &rest[idx + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                res.push('$');
+                rest = after;
+                continue;
+            }
+            let len =
+                rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let role = &rest[..len];
+            rest = &rest[len..];
+            if role.is_empty() {
+                res.push('$');
+            } else if role == "text" {
+                res.push_str(&node.text().to_string());
+            } else {
+                let (_, child) = roles.iter().find(|(it, _)| *it == role)?;
+                res.push_str(&child.text().to_string());
+            }
+        }
+        res.push_str(rest);
+        Some(res)
+    }
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} => {}", self.query, self.template)
+    }
+}
+
+impl FromStr for Rewrite {
+    type Err = String;
+    fn from_str(text: &str) -> Result<Rewrite, String> {
+        let idx = text.find("=>").ok_or("expected `QUERY => TEMPLATE`")?;
+        let query = text[..idx].parse()?;
+        let template = text[idx + 2..].trim().to_string();
+        Ok(Rewrite { query, template })
+    }
+}
+
+/// Applies the sorted `edits` to `text`, returning the ranges of `changed`
+/// after the edits, together with the ranges of the edits themselves.
+pub(crate) fn apply_edits(
+    text: &mut String,
+    edits: &[AtomEdit],
+    changed: &[TextRange],
+) -> Vec<TextRange> {
+    // Maps an offset of the old text to the new one. Offsets inside of an
+    // edit are moved to its start or end, as the range they belong to grows
+    // to cover the edit.
+    let map_offset = |offset: TextSize, is_start: bool| -> TextSize {
+        let mut res = offset;
+        for edit in edits {
+            if edit.delete.start() >= offset {
+                break;
+            }
+            let insert_len = TextSize::of(edit.insert.as_str());
+            if edit.delete.end() <= offset {
+                res = res + insert_len - edit.delete.len();
+            } else if is_start {
+                res -= offset - edit.delete.start();
+            } else {
+                res = res - (offset - edit.delete.start()) + insert_len;
+            }
+        }
+        res
+    };
+    let mut res: Vec<TextRange> = changed
+        .iter()
+        .map(|range| {
+            TextRange::new(map_offset(range.start(), true), map_offset(range.end(), false))
+        })
+        .collect();
+
+    let mut new_text = String::with_capacity(text.len());
+    let mut prev = 0;
+    for edit in edits {
+        new_text.push_str(&text[prev..usize::from(edit.delete.start())]);
+        let start = TextSize::of(new_text.as_str());
+        new_text.push_str(&edit.insert);
+        res.push(TextRange::at(start, TextSize::of(edit.insert.as_str())));
+        prev = edit.delete.end().into();
+    }
+    new_text.push_str(&text[prev..]);
+    *text = new_text;
+
+    res.sort_by_key(|range| range.start());
+    res.dedup_by(|next, prev| {
+        if next.start() > prev.end() {
+            return false;
+        }
+        *prev = prev.cover(*next);
+        true
+    });
+    res
+}
+
+/// Keeps the `edits` of the formatter which touch one of the `changed`
+/// ranges.
+pub(crate) fn edits_in_ranges(edits: Vec<AtomEdit>, changed: &[TextRange]) -> Vec<AtomEdit> {
+    edits
+        .into_iter()
+        .filter(|edit| {
+            changed.iter().any(|range| {
+                edit.delete.start() <= range.end() && range.start() <= edit.delete.end()
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rewrite_string, FormatOptions};
+
+    fn rewrite(rewrites: &[&str], text: &str) -> Result<String, String> {
+        let rewrites: Vec<Rewrite> = rewrites.iter().map(|it| it.parse().unwrap()).collect();
+        rewrite_string(text, &rewrites, &FormatOptions::default())
+    }
+
+    #[test]
+    fn rewrites_nodes() {
+        assert_eq!(
+            rewrite(
+                &["NODE_SELECT[text=stdenv.lib] => lib"],
+                "{ meta.license = stdenv.lib.licenses.mit; }"
+            ),
+            Ok("{ meta.license = lib.licenses.mit; }".to_string())
+        );
+        assert_eq!(
+            rewrite(
+                &["NODE_KEY_VALUE > NODE_KEY[text=sha256] => hash"],
+                "fetchurl { url = \"a\"; sha256 = \"b\"; }"
+            ),
+            Ok("fetchurl { url = \"a\"; hash = \"b\"; }".to_string())
+        );
+        assert_eq!(
+            rewrite(&["NODE_APPLY[lambda=toString] => \"${$value}\""], "toString x"),
+            Ok("\"${x}\"\n".to_string())
+        );
+        assert_eq!(
+            rewrite(&["NODE_APPLY[lambda=toString] => $missing"], "toString x"),
+            Ok("toString x".to_string())
+        );
+    }
+
+    #[test]
+    fn formats_only_the_rewritten_code() {
+        assert_eq!(
+            rewrite(
+                &["NODE_KEY_VALUE[key=src] > NODE_IDENT => fetchurl {inherit url;}"],
+                "{\n  a =   1;\n  src = null;\n}"
+            ),
+            Ok("{\n  a =   1;\n  src = fetchurl { inherit url; };\n}".to_string())
+        );
+        assert_eq!(
+            rewrite(
+                &["NODE_SELECT[text=stdenv.lib] => lib", "NODE_WITH[namespace=lib] => $body"],
+                "with stdenv.lib;   [ x ]"
+            ),
+            Ok("[ x ]\n".to_string())
+        );
+        assert!(rewrite(&["NODE_IDENT => {"], "x").is_err());
+    }
+
+    #[test]
+    fn keeps_dos_line_endings() {
+        let text = "{\r\n  a = stdenv.lib.id;\r\n}\r\n";
+        assert_eq!(
+            rewrite(&["NODE_SELECT[text=stdenv.lib] => lib"], text),
+            Ok("{\r\n  a = lib.id;\r\n}\r\n".to_string())
+        );
+        assert_eq!(rewrite(&["NODE_SELECT[text=pkgs.lib] => lib"], text), Ok(text.to_string()));
+    }
+
+    #[test]
+    fn rewrites_round_trip_through_text() {
+        let rewrite: Rewrite = "NODE_SELECT[text=stdenv.lib]=>lib".parse().unwrap();
+        assert_eq!(rewrite.to_string(), "NODE_SELECT[text=\"stdenv.lib\"] => lib");
+        assert_eq!(
+            "NODE_SELECT".parse::<Rewrite>(),
+            Err("expected `QUERY => TEMPLATE`".to_string())
+        );
+    }
+}
@@ -1,37 +1,163 @@
 //! This module contains specific `super::dsl` rules for formatting nix language.
-use rnix::{
-    types::{Lambda, LetIn, TypedNode, With},
-    NodeOrToken, SyntaxElement, SyntaxKind,
-    SyntaxKind::*,
-    T,
-};
+use rnix::{SyntaxKind, SyntaxKind::*, T};
 
 use crate::{
-    dsl::{self, IndentDsl, IndentValue::*, SpacingDsl},
-    pattern::p,
-    tree_utils::{
-        has_newline, next_non_whitespace_sibling, next_sibling, not_on_top_level, on_top_level,
-        prev_non_whitespace_sibling, prev_sibling, prev_token_sibling,
+    dsl::{self, IndentDsl, IndentValue::*, RuleName, SpacingDsl},
+    lints,
+    pattern::{
+        all_children, any, before_token_has_newline, child_is, closest, first_child, kind,
+        last_child, leftmost, multiline, multiline_before, multiline_until, next, next_node,
+        outermost, p, parent, prev, prev_node, top_level, Cond,
     },
+    rules_file::{CustomRule, Target},
+    CommaStyle, FormatOptions, RuleInfo, RuleKind,
 };
 
+// Rules which are implemented by the engine directly, rather than with the
+// DSL. Keep `engine_rules` in sync when adding new ones.
+pub(crate) const PRESERVE_INDENTATION: RuleName =
+    RuleName::new("preserve-indentation", "Preserve indentation");
+pub(crate) const COMMENT_SINGLE_LINE_VALUE: RuleName =
+    RuleName::new("comment-single-line-value", "Align line comments after in with the let");
+pub(crate) const INDENT_STRINGS: RuleName =
+    RuleName::new("indent-strings", "Indent indented string content");
+pub(crate) const INDENT_BLOCK_COMMENTS: RuleName =
+    RuleName::new("indent-block-comments", "Indent block comment content");
+pub(crate) const LEADING_COMMAS: RuleName =
+    RuleName::new("leading-commas", "Leading commas in lambda patterns");
+pub(crate) const TRAILING_COMMAS: RuleName =
+    RuleName::new("trailing-commas", "Trailing commas in lambda patterns");
+pub(crate) const QUOTE_URLS: RuleName = RuleName::new("quote-urls", "Quote URL literals");
+pub(crate) const TRIM_BLANK_LINES: RuleName =
+    RuleName::new("trim-blank-lines", "No blank lines at block boundaries");
+pub(crate) const LIMIT_BLANK_LINES: RuleName =
+    RuleName::new("max-blank-lines", "Limit consecutive blank lines");
+pub(crate) const COMMENT_TRAILING_WHITESPACE: RuleName =
+    RuleName::new("comment-trailing-whitespace", "No trailing whitespace in comments");
+
+type Examples = &'static [(&'static str, &'static str)];
+
+/// Engine rules together with their examples.
 #[rustfmt::skip]
-pub(crate) fn spacing() -> SpacingDsl {
+fn engine_rules() -> Vec<(RuleName, RuleKind, Examples)> {
+    vec![
+        (PRESERVE_INDENTATION, RuleKind::Indentation, &[]),
+        (COMMENT_SINGLE_LINE_VALUE, RuleKind::Indentation, &[]),
+        (INDENT_STRINGS, RuleKind::Indentation, &[("{\n  x = ''\n  foo\n  '';\n}\n", "{\n  x = ''\n    foo\n  '';\n}\n")]),
+        (INDENT_BLOCK_COMMENTS, RuleKind::Indentation, &[]),
+        (LEADING_COMMAS, RuleKind::Fix, &[("{ foo,\n  bar\n}: foo", "{ foo\n, bar\n}: foo\n")]),
+        (TRAILING_COMMAS, RuleKind::Fix, &[("{ foo\n, bar\n}: foo", "{\n  foo,\n  bar,\n}: foo\n")]),
+        (QUOTE_URLS, RuleKind::Fix, &[("https://nixos.org", "\"https://nixos.org\"\n")]),
+        (TRIM_BLANK_LINES, RuleKind::Fix, &[("{\n\n  a = 1;\n}", "{\n  a = 1;\n}\n")]),
+        (LIMIT_BLANK_LINES, RuleKind::Fix, &[]),
+        (COMMENT_TRAILING_WHITESPACE, RuleKind::Fix, &[("# foo  \n1", "# foo\n1\n")]),
+    ]
+}
+
+/// Collects all the rules, including the ones which are enabled only with
+/// non-default `FormatOptions`.
+pub(crate) fn registry() -> Vec<RuleInfo> {
+    let mut res: Vec<RuleInfo> = Vec::new();
+    let mut add = |name: &RuleName, kind: RuleKind, example: Option<(String, String)>| {
+        let idx = match res.iter().position(|it| it.id == name.id()) {
+            Some(idx) => {
+                debug_assert!(res[idx].kind == kind, "rule {} has several kinds", name.id());
+                idx
+            }
+            None => {
+                res.push(RuleInfo {
+                    id: name.id.clone(),
+                    description: name.description.clone(),
+                    kind,
+                    examples: Vec::new(),
+                });
+                res.len() - 1
+            }
+        };
+        if let Some(example) = example {
+            if !res[idx].examples.contains(&example) {
+                res[idx].examples.push(example)
+            }
+        }
+    };
+
+    let all_options = [
+        FormatOptions::default(),
+        FormatOptions {
+            comma_style: Some(CommaStyle::Trailing),
+            separate_multiline_bindings: true,
+            ..FormatOptions::default()
+        },
+    ];
+    for options in all_options.iter() {
+        let spacing = spacing(options);
+        for rule in spacing.rules.iter() {
+            add(&rule.name, RuleKind::Spacing, None)
+        }
+        for (name, (before, after)) in spacing.tests.iter() {
+            add(name, RuleKind::Spacing, Some((before.to_string(), format!("{}\n", after))))
+        }
+    }
+    for options in all_options.iter() {
+        let indentation = indentation(options);
+        for rule in indentation.rules.iter() {
+            add(&rule.name, RuleKind::Indentation, None)
+        }
+        for (name, (before, after)) in indentation.tests.iter() {
+            add(name, RuleKind::Indentation, Some((unindent(before), unindent(after))))
+        }
+    }
+    for (name, kind, examples) in engine_rules() {
+        add(&name, kind, None);
+        for &(before, after) in examples.iter() {
+            add(&name, kind, Some((before.to_string(), after.to_string())))
+        }
+    }
+    for lint in lints::lints() {
+        add(&lint.name, RuleKind::Lint, None)
+    }
+    add(&lints::STRING_TRAILING_WHITESPACE, RuleKind::Lint, None);
+    res
+}
+
+/// Removes the leading newline and the common indentation of an example
+/// specified inline with a raw string.
+fn unindent(text: &str) -> String {
+    let text = text.strip_prefix('\n').unwrap_or(text);
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    text.lines().map(|line| line.get(indent..).unwrap_or("")).collect::<Vec<_>>().join("\n")
+}
+
+#[rustfmt::skip]
+pub(crate) fn spacing(options: &FormatOptions) -> SpacingDsl {
     let mut dsl = SpacingDsl::default();
 
+    let multiline_value = match options.comma_style {
+        // Without leading commas, there's nothing to align the pattern to.
+        Some(CommaStyle::Trailing) => next_sibling_is_multiline_letin_pattern(),
+        _ => next_sibling_is_multiline_lambda_pattern() | next_sibling_is_multiline_letin_pattern(),
+    };
+
     dsl
         .test("{ a=92; }", "{ a = 92; }")
-        .rule("Space before =")
+        .rule("space-before-eq", "Space before =")
         .inside(NODE_KEY_VALUE).before(T![=]).single_space()
 
-        .rule("Space after =")
+        .rule("space-after-eq", "Space after =")
         .inside(NODE_KEY_VALUE).after(T![=]).single_space_or_optional_newline()
 
         .test("{ a = 92 ; }", "{ a = 92; }")
+        .rule("no-space-before-semicolon", "No space before ;")
         .inside(NODE_KEY_VALUE).before(T![;]).no_space_or_optional_newline()
-        .inside(NODE_KEY_VALUE).before(T![;]).when(after_literal).no_space()
-        .inside(NODE_KEY_VALUE).before(NODE_IF_ELSE).when(not_inline_if).single_space_or_newline()
-        .inside(NODE_KEY_VALUE).before(NODE_LET_IN).when(inline_let_in).single_space_or_newline()
+        .inside(NODE_KEY_VALUE).before(T![;]).when(after_literal()).no_space()
+        .rule("multiline-binding-value", "Line break before multi-line values")
+        .inside(NODE_KEY_VALUE).before(NODE_IF_ELSE).when(not_inline_if()).single_space_or_newline()
+        .inside(NODE_KEY_VALUE).before(NODE_LET_IN).when(inline_let_in()).single_space_or_newline()
 
         .test("a++\nb", "a ++\nb")
         .test("a==  b", "a == b")
@@ -41,56 +167,89 @@ pub(crate) fn spacing() -> SpacingDsl {
         .test("a  -   b", "a - b")
         .test("a*  b", "a * b")
         .test("a/  b", "a / b")
+        .rule("space-around-binop", "Space around binary operators")
         .inside(NODE_BIN_OP).around(BIN_OPS).single_space_or_optional_newline()
 
         .test("foo . bar . baz", "foo.bar.baz")
+        .rule("no-space-around-dot", "No space around dots in selections")
         .inside(NODE_SELECT).around(T![.]).no_space()
         .test("{} :92", "{}: 92")
+        .rule("no-space-before-colon", "No space before :")
         .inside(NODE_LAMBDA).before(T![:]).no_space()
+        .rule("space-after-colon", "Space after :")
         .inside(NODE_LAMBDA).after(T![:]).single_space_or_optional_newline()
-        .inside(NODE_LAMBDA).before(NODE_IF_ELSE).when(not_inline_if).single_space_or_newline()
+        .rule("multiline-lambda-body", "Line break before multi-line lambda bodies")
+        .inside(NODE_LAMBDA).before(NODE_IF_ELSE).when(not_inline_if()).single_space_or_newline()
         .inside(NODE_LAMBDA).before(NODE_LET_IN).single_space_or_newline()
 
         .test("[1 2 3]", "[ 1 2 3 ]")
+        .rule("space-inside-brackets", "Space inside list brackets")
         .inside(NODE_LIST).after(T!["["]).single_space_or_newline()
         .inside(NODE_LIST).before(T!["]"]).single_space_or_newline()
-        .inside(NODE_LIST).after(T!["["]).when(inline_with_attr_set).no_space()
-        .inside(NODE_LIST).before(T!["]"]).when(inline_with_attr_set).no_space()
+        .rule("inline-attr-set-in-list", "No space around an inline attribute set in a list")
+        .inside(NODE_LIST).after(T!["["]).when(inline_with_attr_set()).no_space()
+        .inside(NODE_LIST).before(T!["]"]).when(inline_with_attr_set()).no_space()
         .test("[]", "[ ]")
+        // Continues `space-inside-brackets` above, for empty lists, which
+        // `inline-attr-set-in-list` must not override.
+        .rule("space-inside-brackets", "Space inside list brackets")
         .inside(NODE_LIST).between(T!["["], T!["]"]).single_space_or_optional_newline()
+        .rule("space-between-elements", "Space between list elements")
         .inside(NODE_LIST).between(VALUES, VALUES).single_space_or_newline()
         .inside(NODE_LIST).between(VALUES, TOKEN_COMMENT).single_space_or_optional_newline()
         .inside(NODE_LIST).between(TOKEN_COMMENT, VALUES).single_space_or_newline()
 
         .test("( 92 )", "(92)")
 
+        .rule("no-space-inside-parens", "No space inside parentheses")
         .inside(NODE_PAREN).after(T!["("]).no_space_or_optional_newline()
         .inside(NODE_PAREN).before(T![")"]).no_space_or_optional_newline()
-        .inside(NODE_PAREN).after(T!["("]).when(has_no_brackets).no_space_or_newline()
-        .inside(NODE_PAREN).before(T![")"]).when(has_no_brackets).no_space_or_newline()
+        .rule("multiline-parens", "Line breaks inside multi-line parentheses")
+        .inside(NODE_PAREN).after(T!["("]).when(has_no_brackets()).no_space_or_newline()
+        .inside(NODE_PAREN).before(T![")"]).when(has_no_brackets()).no_space_or_newline()
 
         .test("{foo = 92;}", "{ foo = 92; }")
+        .rule("space-inside-braces", "Space inside attribute set braces")
         .inside(NODE_ATTR_SET).after(T!["{"]).single_space_or_newline()
         .inside(NODE_ATTR_SET).before(T!["}"]).single_space_or_newline()
         .test("{}", "{ }")
         .inside(NODE_ATTR_SET).between(T!["{"], T!["}"]).single_space()
+        .rule("space-between-bindings", "Space between bindings")
         .inside(NODE_ATTR_SET).before(NODE_KEY_VALUE).single_space_or_optional_newline()
         .inside(NODE_ATTR_SET).between(NODE_KEY_VALUE, NODE_KEY_VALUE).single_space_or_newline()
         .inside(NODE_ATTR_SET).between(NODE_INHERIT, [NODE_INHERIT, TOKEN_COMMENT]).single_space_or_optional_newline()
         .inside(NODE_ATTR_SET).between(NODE_KEY_VALUE, TOKEN_COMMENT).single_space_or_optional_newline()
 
         .test("{arg}: 92", "{ arg }: 92")
-        .inside(NODE_PATTERN).after(T!["{"]).single_space()
+        .test("{ foo,bar }: 92", "{ foo, bar }: 92");
+
+    // The commas themselves are moved by `engine::commas`, under the
+    // `leading-commas` and `trailing-commas` fixes, here we only handle the
+    // whitespace around them.
+    match options.comma_style {
+        Some(CommaStyle::Trailing) => dsl
+            .rule("space-around-pattern-commas", "Space around commas in lambda patterns")
+            .inside(NODE_PATTERN).after(T!["{"]).single_space_or_newline()
+            .inside(NODE_PATTERN).between(T![,], [NODE_PAT_ENTRY, TOKEN_ELLIPSIS]).single_space_or_newline()
+            .inside(NODE_PATTERN).before(T![,]).no_space()
+            .inside(NODE_PATTERN).between(T![,], TOKEN_COMMENT).single_space_or_optional_newline(),
+        _ => dsl
+            .rule("space-around-pattern-commas", "Space around commas in lambda patterns")
+            .inside(NODE_PATTERN).after(T!["{"]).single_space()
+            .inside(NODE_PATTERN).after(T![,]).single_space()
+            .inside(NODE_PATTERN).before(T![,]).no_space_or_newline(),
+    };
+
+    dsl
+        .rule("space-inside-pattern-braces", "Space inside lambda pattern braces")
         .inside(NODE_PATTERN).between(T!["{"], TOKEN_COMMENT).single_space_or_newline()
         .inside(NODE_PATTERN).before(T!["}"]).single_space_or_newline()
         .test("{ }: 92", "{}: 92")
+        .rule("empty-pattern", "No space inside empty lambda patterns")
         .inside(NODE_PATTERN).between(T!["{"], T!["}"]).no_space()
 
-        .test("{ foo,bar }: 92", "{ foo, bar }: 92")
-        .inside(NODE_PATTERN).after(T![,]).single_space()
-        .inside(NODE_PATTERN).before(T![,]).no_space_or_newline()
-
         .test("{ inherit( x )  y  z  ; }", "{ inherit (x) y z; }")
+        .rule("inherit-spacing", "Single spaces between inherited names")
         .inside(NODE_INHERIT).around(NODE_INHERIT_FROM).single_space_or_optional_newline()
         .inside(NODE_INHERIT).around(T![;]).no_space_or_optional_newline()
         .inside(NODE_INHERIT).before(NODE_IDENT).single_space_or_optional_newline()
@@ -99,27 +258,35 @@ pub(crate) fn spacing() -> SpacingDsl {
         .inside(NODE_INHERIT_FROM).after(T!["("]).no_space()
         .inside(NODE_INHERIT_FROM).before(T![")"]).no_space()
 
+        .rule("space-before-with-body", "Space before the body of with")
         .inside(NODE_WITH).before(NODE_LET_IN).single_space_or_optional_newline()
 
         .test("let   foo = bar;in  92", "let foo = bar; in 92")
+        .rule("let-in-spacing", "Space around let and in")
         .inside(NODE_LET_IN).after(T![let]).single_space_or_optional_newline()
         .inside(NODE_LET_IN).around(T![in]).single_space_or_optional_newline()
         .inside(NODE_LET_IN).after(NODE_KEY_VALUE).single_space_or_optional_newline()
-        .inside(NODE_LET_IN).before(NODE_KEY_VALUE).when(let_header_has_newline).newline()
-        .inside(NODE_LET_IN).around(T![in]).when(let_header_has_newline).newline()
+        .rule("multiline-let-in", "Line breaks in multi-line let")
+        .inside(NODE_LET_IN).before(NODE_KEY_VALUE).when(let_header_has_newline()).newline()
+        .inside(NODE_LET_IN).around(T![in]).when(let_header_has_newline()).newline()
 
         .test("{a?3}: a", "{ a ? 3 }: a")
+        .rule("space-around-default", "Space around ? in lambda patterns")
         .inside(NODE_PAT_ENTRY).around(T![?]).single_space()
 
         .test("f  x", "f x")
+        .rule("space-between-args", "Space between function and arguments")
         .inside(NODE_APPLY).between(VALUES, VALUES).single_space_or_optional_newline()
-        .inside(NODE_APPLY).before(VALUES).when(should_be_newline).single_space_or_newline()
+        .rule("multiline-args", "Line breaks between multi-line arguments")
+        .inside(NODE_APPLY).before(VALUES).when(should_be_newline()).single_space_or_newline()
 
         .test("if  cond  then  tru  else  fls", "if cond then tru else fls")
+        .rule("if-then-else-spacing", "Space around if, then and else")
         .inside(NODE_IF_ELSE).after(T![if]).single_space_or_optional_newline()
         .inside(NODE_IF_ELSE).around([T![else],T![then]]).single_space_or_optional_newline()
-        .inside(NODE_IF_ELSE).after(T![then]).when(has_expression_node).single_space_or_newline()
-        .inside(NODE_IF_ELSE).after(T![else]).when(has_expression_node).single_space_or_newline()
+        .rule("multiline-if-then-else", "Line breaks in multi-line if-then-else")
+        .inside(NODE_IF_ELSE).after(T![then]).when(has_expression_node()).single_space_or_newline()
+        .inside(NODE_IF_ELSE).after(T![else]).when(has_expression_node()).single_space_or_newline()
         
         // special-case to force a linebreak before `=` in
         //
@@ -132,623 +299,432 @@ pub(crate) fn spacing() -> SpacingDsl {
         // }
         // ```
         .add_rule(dsl::SpacingRule {
-            name: None,
-            pattern: p(T![=]) & (p(next_sibling_is_multiline_lambda_pattern) | p(next_sibling_is_multiline_letin_pattern)) ,
+            name: RuleName::new("newline-after-eq", "Line break after = before multi-line patterns"),
+            pattern: p(T![=]) & p(multiline_value),
             space: dsl::Space { loc: dsl::SpaceLoc::After, value: dsl::SpaceValue::Newline }
         })
 
         // special-cased rules for leading and trailing whitespace
         .add_rule(dsl::SpacingRule {
-            name: None,
+            name: RuleName::new("no-leading-whitespace", "No whitespace at the start of the file"),
             pattern: NODE_ROOT.into(),
             space: dsl::Space { loc: dsl::SpaceLoc::Before, value: dsl::SpaceValue::None }
         })
 
         .add_rule(dsl::SpacingRule {
-            name: None,
+            name: RuleName::new("final-newline", "Newline at the end of the file"),
             pattern: NODE_ROOT.into(),
             space: dsl::Space { loc: dsl::SpaceLoc::After, value: dsl::SpaceValue::Newline }
         })
 
         ;
 
-    dsl
-}
-
-fn after_literal(element: &SyntaxElement) -> bool {
-    fn is_literal(kind: SyntaxKind) -> bool {
-        kind == NODE_ATTR_SET || kind == NODE_LIST
+    if options.separate_multiline_bindings {
+        dsl
+            .rule("separate-multiline-bindings", "Blank line around multi-line bindings")
+            .inside([NODE_ATTR_SET, NODE_LET_IN])
+            .between(NODE_KEY_VALUE, kind(&[NODE_KEY_VALUE]) & multiline_binding_pair())
+            .blank_line();
     }
 
-    let prev = prev_sibling(element);
-    return if let Some(body) = prev.clone().and_then(With::cast).and_then(|w| w.body()) {
-        is_literal(body.kind())
-    } else {
-        prev.map(|it| is_literal(it.kind())) == Some(true)
-    };
+    dsl
 }
 
-fn has_no_brackets(element: &SyntaxElement) -> bool {
-    let parent = match element.parent() {
-        None => return false,
-        Some(it) => it,
-    };
-    parent.children().all(|it| match it.kind() {
-        NODE_ATTR_SET | NODE_PATTERN | NODE_LIST => false,
-        NODE_IF_ELSE | NODE_BIN_OP | NODE_WITH | NODE_LAMBDA => {
-            before_token_has_newline(&it.into())
+/// Adds spacing rules loaded from a rules file.
+pub(crate) fn custom_spacing(dsl: &mut SpacingDsl, custom_rules: &[CustomRule]) {
+    for rule in custom_rules.iter() {
+        for (before, after) in rule.examples.iter() {
+            dsl.test(before.clone(), after.clone());
         }
-        NODE_APPLY => {
-            if let Some(value) =
-                it.first_child().and_then(rnix::types::Apply::cast).and_then(|e| e.value())
-            {
-                match value.kind() {
-                    NODE_ATTR_SET | NODE_PAREN => return false,
-                    _ => return true && before_token_has_newline(&it.into()),
-                }
-            }
-            return false;
-        }
-        _ => true,
-    })
-}
-
-fn inline_with_attr_set(element: &SyntaxElement) -> bool {
-    fn inline_attr_set(element: &SyntaxElement) -> Option<bool> {
-        let inline_attr = element
-            .parent()?
-            .descendants_with_tokens()
-            .find(|e| e.kind() == NODE_ATTR_SET)
-            .map(|t| before_token_has_newline(&t));
-        inline_attr
+        let builder = dsl.rule(rule.id.clone(), rule.description.clone()).inside(&rule.inside[..]);
+        let builder = match &rule.target {
+            Target::Before(kinds) => builder.before(&kinds[..]),
+            Target::After(kinds) => builder.after(&kinds[..]),
+            Target::Around(kinds) => builder.around(&kinds[..]),
+            Target::Between(left, right) => builder.between(&left[..], &right[..]),
+        };
+        let builder = match &rule.when {
+            Some(cond) => builder.when(cond.clone()),
+            None => builder,
+        };
+        builder.finish(rule.space);
     }
-    inline_attr_set(element) == Some(false)
-        && element.parent().and_then(|e| e.first_child().map(|n| n.kind() == NODE_ATTR_SET))
-            == Some(true)
 }
 
-fn should_be_newline(element: &SyntaxElement) -> bool {
-    let parent = match element.parent() {
-        None => return false,
-        Some(it) => it,
-    };
-
-    let apply = match element.parent() {
-        None => return false,
-        Some(it) => rnix::types::Apply::cast(it),
-    };
-
-    match element.kind() {
-        NODE_APPLY => false,
-        NODE_SELECT | NODE_IDENT => {
-            if is_argument(element) {
-                return has_newline(&parent);
-            }
-            false
-        }
-        NODE_PAREN | NODE_ATTR_SET => match last_argument_in_function(element) {
-            true => {
-                if let Some(fun) = apply.clone().and_then(|e| e.lambda()) {
-                    match fun.kind() {
-                        // node is in multi-argument function
-                        NODE_APPLY => {
-                            if let Some(node) = prev_non_whitespace_sibling(element) {
-                                return node.as_node().map(|t| has_newline(t)).unwrap_or(false);
-                            }
-                            return false;
-                        }
-                        // node is unary function
-                        _ => return false,
-                    }
-                }
-                return false;
-            }
-            false => return has_newline(&parent),
-        },
-        _ => false,
-    }
+/// Checks if either the binding or the preceding one spans several lines.
+fn multiline_binding_pair() -> Cond {
+    multiline() | prev(multiline())
 }
 
-fn is_argument(element: &SyntaxElement) -> bool {
-    match prev_sibling(element) {
-        None => false,
-        Some(it) => it.kind() == NODE_APPLY,
-    }
+/// `;` after a list or an attribute set, possibly in the body of `with`.
+fn after_literal() -> Cond {
+    let literal = || kind(&[NODE_ATTR_SET, NODE_LIST]);
+    prev_node(literal() | kind(&[NODE_WITH]) & last_child(literal()))
 }
 
-fn last_argument_in_function(element: &SyntaxElement) -> bool {
-    let is_last_argument = match element.parent() {
-        None => false,
-        Some(it) => match next_sibling(&it.into()) {
-            None => true,
-            _ => false,
-        },
-    };
-
-    is_last_argument
+/// Parentheses around an expression which doesn't end with brackets of its
+/// own, but starts on a new line.
+fn has_no_brackets() -> Cond {
+    let blocks = [NODE_IF_ELSE, NODE_BIN_OP, NODE_WITH, NODE_LAMBDA];
+    let brackets = [NODE_ATTR_SET, NODE_PATTERN, NODE_LIST];
+    let multi_arg_apply =
+        first_child(kind(&[NODE_APPLY]) & !last_child(kind(&[NODE_ATTR_SET, NODE_PAREN])));
+    parent(all_children(
+        !kind(&[&blocks[..], &brackets[..], &[NODE_APPLY]].concat())
+            | kind(&blocks) & before_token_has_newline()
+            | kind(&[NODE_APPLY]) & multi_arg_apply & before_token_has_newline(),
+    ))
 }
 
-fn has_expression_node(element: &SyntaxElement) -> bool {
-    if let Some(el) = next_non_whitespace_sibling(element) {
-        match el.kind() {
-            NODE_APPLY | NODE_PAREN | NODE_LET_IN => {
-                let node = match el.as_node() {
-                    Some(val) => val,
-                    None => return false,
-                };
-                return has_newline(node);
-            }
-            _ => return false,
-        }
-    }
-    return false;
+/// A list which starts with an attribute set on the same line as `[`.
+fn inline_with_attr_set() -> Cond {
+    parent(first_child(kind(&[NODE_ATTR_SET]) & !before_token_has_newline()))
 }
 
-fn not_inline_if(element: &SyntaxElement) -> bool {
-    fn not_inline_if_then_else(element: &SyntaxElement) -> Option<bool> {
-        let first_el = element
-            .parent()?
-            .descendants_with_tokens()
-            .take_while(|e| e.kind() != T![else])
-            .filter(|element| match element {
-                NodeOrToken::Token(_) => true,
-                _ => false,
-            })
-            .any(|t| t.as_token().map(|e| e.text().contains("\n")).unwrap_or(false));
-
-        Some(first_el)
-    }
+/// An argument of a multi-line function application.
+fn should_be_newline() -> Cond {
+    let followed_by_argument = next_node(any());
+    kind(&[NODE_SELECT, NODE_IDENT]) & prev_node(kind(&[NODE_APPLY])) & parent(multiline())
+        | kind(&[NODE_PAREN, NODE_ATTR_SET])
+            & (parent(followed_by_argument.clone() & multiline())
+                | parent(!followed_by_argument & first_child(kind(&[NODE_APPLY])))
+                    & prev(multiline()))
+}
 
-    not_inline_if_then_else(element) == Some(true)
+/// `then` or `else` followed by a multi-line expression.
+fn has_expression_node() -> Cond {
+    next(kind(&[NODE_APPLY, NODE_PAREN, NODE_LET_IN]) & multiline())
 }
 
-fn before_token_has_newline(element: &SyntaxElement) -> bool {
-    prev_token_sibling(element).map(|e| e.text().contains("\n")).unwrap_or(false)
+/// An `if` whose parent spans several lines before `else`.
+fn not_inline_if() -> Cond {
+    parent(multiline_until(T![else]))
 }
 
-fn next_sibling_is_multiline_lambda_pattern(element: &SyntaxElement) -> bool {
-    fn find(element: &SyntaxElement) -> Option<bool> {
-        let lambda = next_non_whitespace_sibling(element)?.into_node().and_then(Lambda::cast)?;
-        let pattern = lambda.arg().and_then(rnix::types::Pattern::cast)?;
-        Some(has_newline(pattern.node()))
-    }
-    find(element) == Some(true)
+fn next_sibling_is_multiline_lambda_pattern() -> Cond {
+    next(kind(&[NODE_LAMBDA]) & first_child(kind(&[NODE_PATTERN]) & multiline()))
 }
 
-fn next_sibling_is_multiline_letin_pattern(element: &SyntaxElement) -> bool {
-    fn find(element: &SyntaxElement) -> Option<bool> {
-        let letin = next_non_whitespace_sibling(element)?.into_node().and_then(LetIn::cast)?;
-        let header = letin
-            .node()
-            .children_with_tokens()
-            .into_iter()
-            .take_while(|x| match x {
-                NodeOrToken::Node(_) => true,
-                NodeOrToken::Token(token) => token.kind() != TOKEN_IN,
-            })
-            .any(|child| match child {
-                NodeOrToken::Node(node) => has_newline(&node),
-                NodeOrToken::Token(token) => token.text().contains("\n"),
-            });
-        Some(header)
-    }
-    find(element) == Some(true)
+fn next_sibling_is_multiline_letin_pattern() -> Cond {
+    next(kind(&[NODE_LET_IN]) & multiline_until(T![in]))
 }
 
-fn inline_let_in(element: &SyntaxElement) -> bool {
-    element
-        .as_node()
-        .map(|n| {
-            n.children_with_tokens()
-                .take_while(|e| e.kind() != T![in])
-                .any(|t| t.as_token().map(|n| n.text().contains("\n")).unwrap_or(false))
-        })
-        .unwrap_or(false)
+fn inline_let_in() -> Cond {
+    multiline_until(T![in])
 }
 
-fn let_header_has_newline(element: &SyntaxElement) -> bool {
-    let letin = match element {
-        NodeOrToken::Token(token) => Some(token.parent()),
-        NodeOrToken::Node(node) => node.parent(),
-    };
-    // element.as_node().and_then(|x|x.parent());
-    letin
-        .map(|x| {
-            let mut nodes: Vec<SyntaxElement> = x
-                .children_with_tokens()
-                .into_iter()
-                .take_while(|x| match x {
-                    NodeOrToken::Node(_) => true,
-                    NodeOrToken::Token(token) => token.kind() != TOKEN_IN,
-                })
-                .collect();
-            nodes.pop();
-            nodes.into_iter().any(|child| match child {
-                NodeOrToken::Node(node) => has_newline(&node),
-                NodeOrToken::Token(token) => token.text().contains("\n"),
-            })
-        })
-        .unwrap_or(false)
+fn let_header_has_newline() -> Cond {
+    parent(multiline_before(T![in]))
 }
 
 #[rustfmt::skip]
-pub(crate) fn indentation() -> IndentDsl {
+pub(crate) fn indentation(options: &FormatOptions) -> IndentDsl {
     let mut dsl = IndentDsl::default();
+    match options.comma_style {
+        // Arguments are indented relative to the line with `{`, rather than
+        // aligned with it.
+        Some(CommaStyle::Trailing) => dsl.anchor(NODE_PAT_ENTRY),
+        _ => dsl.anchor([NODE_PAT_ENTRY, NODE_PATTERN]),
+    };
     dsl
-        .anchor([NODE_PAT_ENTRY, NODE_PATTERN])
 
 
-        .rule("Indent binops")
-            .inside(p(NODE_BIN_OP) & p(after_concat_is_newline) & p(not_on_top_level))
+        .test(r#"
+            {
+            foo = bar ++
+            [ baz ];
+            }
+        "#, r#"
+            {
+              foo = bar ++
+                [ baz ];
+            }
+        "#)
+        .rule("indent-binops", "Indent binops")
+            .inside(p(NODE_BIN_OP) & p(after_concat_is_newline() & !top_level()))
             .set(Indent)
-            .test(r#"
-                {
-                foo = bar ++
+        .test(r#"
+            {
+            foo = bar ++
+            [ baz ];
+            }
+        "#, r#"
+            {
+              foo = bar ++
                 [ baz ];
-                }
-            "#, r#"
-                {
-                  foo = bar ++
-                    [ baz ];
-                }
-            "#)
-        .rule("Indent binops top level")
-            .inside(p(NODE_BIN_OP) & p(on_top_level))
+            }
+        "#)
+        .rule("indent-top-level-binops", "Indent binops top level")
+            .inside(p(NODE_BIN_OP) & p(top_level()))
             .not_matching(p(T![++]) | p(VALUES))
             .set(Indent)
-            .test(r#"
-                {
-                foo = bar ++
-                [ baz ];
-                }
-            "#, r#"
-                {
-                  foo = bar ++
-                    [ baz ];
-                }
-            "#)
 
-        .rule("Indent list content")
+        .test(r#"
+            [
+            92
+            ]
+        "#, r#"
+            [
+              92
+            ]
+        "#)
+        .rule("indent-list", "Indent list content")
             .inside(NODE_LIST)
             .not_matching([T!["["], T!["]"]])
             .set(Indent)
-            .test(r#"
-                [
-                92
-                ]
-            "#, r#"
-                [
-                  92
-                ]
-            "#)
-        .rule("Indent parenthesized expressions")
+        .test(r#"
+            (
+            92
+            )
+        "#, r#"
+            (
+              92
+            )
+        "#)
+        .rule("indent-parens", "Indent parenthesized expressions")
             .inside(NODE_PAREN)
             .not_matching([T!["("],T![")"]])
             .set(Indent)
-            .test(r#"
-                (
-                92
-                )
-            "#, r#"
-                (
-                  92
-                )
-            "#)
         
-        .rule("Indent attribute set content")
+        .test(r#"
+            {
+            foo = bar;
+            }
+        "#, r#"
+            {
+              foo = bar;
+            }
+        "#)
+        .rule("indent-attr-set", "Indent attribute set content")
             .inside(NODE_ATTR_SET)
             .not_matching([T!["{"], T!["}"]])
             .set(Indent)
-            .test(r#"
-                {
-                foo = bar;
-                }
-            "#, r#"
-                {
-                  foo = bar;
-                }
-            "#)
 
-        .rule("Indent newline let bindings ")
-            .inside(p(NODE_LET_IN) & p(newline_let))
+        .rule("indent-newline-let", "Indent newline let bindings")
+            .inside(p(NODE_LET_IN) & p(newline_let()))
             .not_matching([T![let], T![in]])
             .set(Indent)
 
-        .rule("Indent let bindings after key value")
-            .inside(p(NODE_LET_IN) & p(no_newline_let))
+        .test(r#"
+            (
+              let
+              x = 1;
+              inherit z;
+              in
+              x
+            )
+        "#, r#"
+            (
+              let
+                x = 1;
+                inherit z;
+              in
+              x
+            )
+        "#)
+        .rule("indent-let", "Indent let bindings after key value")
+            .inside(p(NODE_LET_IN) & p(!newline_let()))
             .not_matching(p([T![let], T![in], NODE_WITH, NODE_ASSERT]) | p(VALUES))
             .set(Indent)
-            .test(r#"
-                (
-                  let
-                  x = 1;
-                  inherit z;
-                  in
-                  x
-                )
-            "#, r#"
-                (
-                  let
-                    x = 1;
-                    inherit z;
-                  in
-                  x
-                )
-            "#)
-
-
-        .rule("Indent attribute value")
+
+
+        .test(r#"
+            {
+              foo =
+              92;
+            }
+        "#, r#"
+            {
+              foo =
+                92;
+            }
+        "#)
+        .rule("indent-attr-value", "Indent attribute value")
             .inside(NODE_KEY_VALUE)
             .not_matching(T![;])
             .set(Indent)
-            .test(r#"
-                {
-                  foo =
-                  92;
-                }
-            "#, r#"
-                {
-                  foo =
-                    92;
-                }
-            "#)
 
-        .rule("Indent lambda parameters")
+        .test(r#"
+            {
+            # comment
+            foo ? bar
+            , baz
+            }: foo
+        "#, r#"
+            {
+              # comment
+              foo ? bar
+            , baz
+            }: foo
+        "#)
+        .rule("indent-pattern", "Indent lambda parameters")
             .inside(NODE_PATTERN)
             .not_matching([T!["{"], T!["}"], T![,]])
             .set(Indent)
-            .test(r#"
-                {
-                # comment
-                foo ? bar
-                , baz
-                }: foo
-            "#, r#"
-                {
-                  # comment
-                  foo ? bar
-                , baz
-                }: foo
-            "#)
-
-        .rule("Indent lambda body")
-            .inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_not_newline))
+
+        .rule("indent-lambda-body", "Indent lambda body")
+            .inside(p(NODE_LAMBDA) & p(!top_level() & !pattern_newline()))
             .set(Indent)
-        .rule("Indent newline lambda body")
-            .inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_newline) & p(lambda_inside_node_pattern))
+        .rule("indent-newline-lambda-body", "Indent newline lambda body")
+            .inside(p(NODE_LAMBDA) & p(!top_level() & pattern_newline() & closest(NODE_PATTERN, any())))
             .not_matching(p(TOKEN_COMMENT))
             .set(Indent)
-         .rule("Indent newline lambda body")
-            .inside(p(NODE_LAMBDA) & p(not_on_top_level) & p(pattern_newline) & p(lambda_outside_node_pattern))
+        .test(r#"
+            {}:
+              {
+            foo =
+              # describe bar
+              bar:
+              # describe baz
+              baz:
+              fnbody;
+            }
+        "#, r#"
+            {}:
+            {
+              foo =
+                # describe bar
+                bar:
+                # describe baz
+                baz:
+                fnbody;
+            }
+        "#)
+        // The same rule, outside of lambda patterns, where the values are not
+        // indented either.
+        .rule("indent-newline-lambda-body", "Indent newline lambda body")
+            .inside(p(NODE_LAMBDA) & p(!top_level() & pattern_newline() & !closest(NODE_PATTERN, any())))
             .not_matching(p(TOKEN_COMMENT) | p(VALUES))
             .set(Indent)
-            .test(r#"
-                {}:
-                  {
-                foo =
-                  # describe bar
-                  bar:
-                  # describe baz
-                  baz:
-                  fnbody;
-                }
-            "#, r#"
-                {}:
-                {
-                  foo =
-                    # describe bar
-                    bar:
-                    # describe baz
-                    baz:
-                    fnbody;
-                }
-            "#)
 
-        .rule("Indent top-level apply arg")
-            .inside(p(NODE_APPLY) & p(on_top_level))
+        .test(r#"
+            foo
+            bar baz
+        "#, r#"
+            foo
+              bar
+              baz
+        "#)
+        .rule("indent-top-level-args", "Indent top-level apply arg")
+            .inside(p(NODE_APPLY) & p(top_level()))
             .not_matching([T!["{"], T!["}"], NODE_ATTR_SET])
             .set(Indent)
-            .test(r#"
-                foo
-                bar baz
-            "#, r#"
-                foo
-                  bar
-                  baz
-            "#)
-
-        .rule("Indent apply arg")
-            .inside(p(NODE_APPLY) & p(not_on_top_level) & p(not_inline_apply))
-            .not_matching([T!["{"], T!["}"]])
-            .set(Indent)
-            .test(r#"
-                foo
-                bar baz
-            "#, r#"
-                foo
-                  bar
-                  baz
-            "#)
-
-        .rule("Indent apply arg")
-            .inside(p(NODE_APPLY) & p(not_on_top_level) & p(inline_apply))
+
+        .test(r#"
+            foo
+            bar baz
+        "#, r#"
+            foo
+              bar
+              baz
+        "#)
+        .rule("indent-args", "Indent apply arg")
+            .inside(p(NODE_APPLY) & p(!top_level()))
             .not_matching([T!["{"], T!["}"]])
             .set(Indent)
 
-        .rule("Indent with body in attribute")
+        .test(r#"
+            with foo;
+              {
+              bar = with baz;
+              body;
+              }
+        "#, r#"
+            with foo;
+            {
+              bar = with baz;
+                body;
+            }
+        "#)
+        .rule("indent-with-body", "Indent with body in attribute")
             .inside([NODE_WITH, NODE_ASSERT])
             .when_anchor(NODE_KEY_VALUE)
             .set(Indent)
-            .test(r#"
-                with foo;
-                  {
-                  bar = with baz;
-                  body;
-                  }
-            "#, r#"
-                with foo;
-                {
-                  bar = with baz;
-                    body;
-                }
-            "#)
 
-        .rule("Indent or default")
+        .test(r#"
+            {
+              x = foo or
+              bar;
+            }
+        "#, r#"
+            {
+              x = foo or
+                bar;
+            }
+        "#)
+        .rule("indent-or-default", "Indent or default")
             .inside(NODE_OR_DEFAULT)
             .set(Indent)
-            .test(r#"
-                {
-                  x = foo or
-                  bar;
-                }
-            "#, r#"
-                {
-                  x = foo or
-                    bar;
-                }
-            "#)
 
-        .rule("Indent if-then-else")
-            .inside(p(NODE_IF_ELSE) & p(inline_if_else))
+        .rule("indent-if-then-else", "Indent if-then-else")
+            .inside(p(NODE_IF_ELSE) & p(!multiline_until(T![else])))
             .not_matching(p([T![if], T![then], T![else]]) | p(VALUES))
             .set(Indent)
 
-        .rule("Indent if-then-else")
-            .inside(p(NODE_IF_ELSE) & p(not_inline_if_else))
+        .test(r#"
+            if
+            foo
+            then
+            bar
+            else
+            baz
+        "#, r#"
+            if
+              foo
+            then
+              bar
+            else
+              baz
+        "#)
+        // The same rule, when the condition spans multiple lines.
+        .rule("indent-if-then-else", "Indent if-then-else")
+            .inside(p(NODE_IF_ELSE) & p(multiline_until(T![else])))
             .not_matching([T![if], T![then], T![else], TOKEN_COMMENT])
             .set(Indent)
-            .test(r#"
-                if
-                foo
-                then
-                bar
-                else
-                baz
-            "#, r#"
-                if
-                  foo
-                then
-                  bar
-                else
-                  baz
-            "#)
-
-        .rule("Indent inherit parts")
+
+        .test(r#"
+            {
+              inherit
+              (builtins)
+              # comment
+              toString
+              ;
+            }
+        "#, r#"
+            {
+              inherit
+                (builtins)
+                # comment
+                toString
+                ;
+            }
+        "#)
+        .rule("indent-inherit", "Indent inherit parts")
             .inside(NODE_INHERIT)
             .set(Indent)
-            .test(r#"
-                {
-                  inherit
-                  (builtins)
-                  # comment
-                  toString
-                  ;
-                }
-            "#, r#"
-                {
-                  inherit
-                    (builtins)
-                    # comment
-                    toString
-                    ;
-                }
-            "#)
     ;
 
     dsl
 }
 
-fn inline_apply(element: &SyntaxElement) -> bool {
-    !not_inline_apply(element)
-}
-
-fn not_inline_apply(element: &SyntaxElement) -> bool {
-    fn not_inline_function_apply(element: &SyntaxElement) -> Option<bool> {
-        let first_el = element
-            .ancestors()
-            .filter(|e| e.kind() == NODE_APPLY)
-            .max_by_key(|e| e.text_range().end());
-        first_el.map(|e| before_token_has_newline(&e.into()))
-    }
-    not_inline_function_apply(element) == Some(true)
-}
-
-fn inline_if_else(element: &SyntaxElement) -> bool {
-    !not_inline_if_else(element)
-}
-
-fn not_inline_if_else(element: &SyntaxElement) -> bool {
-    fn not_inline_if_then_else(element: &SyntaxElement) -> Option<bool> {
-        let first_el = element
-            .as_node()?
-            .descendants_with_tokens()
-            .take_while(|e| e.kind() != T![else])
-            .filter(|element| match element {
-                NodeOrToken::Token(_) => true,
-                _ => false,
-            })
-            .any(|t| t.as_token().map(|e| e.text().contains("\n")).unwrap_or(false));
-
-        Some(first_el)
-    }
-
-    not_inline_if_then_else(element) == Some(true)
-}
-
-fn pattern_newline(element: &SyntaxElement) -> bool {
-    fn lambda_has_newline(element: &SyntaxElement) -> Option<bool> {
-        let first_el = element
-            .ancestors()
-            .find(|e| e.kind() == NODE_KEY_VALUE)?
-            .children()
-            .find(|e| e.kind() == NODE_LAMBDA)?;
-
-        Some(
-            first_el.first_child().map(|e| has_newline(&e)).unwrap_or(false)
-                || before_token_has_newline(&first_el.into()),
-        )
-    }
-
-    lambda_has_newline(element) == Some(true) || before_token_has_newline(element)
-}
-
-fn pattern_not_newline(element: &SyntaxElement) -> bool {
-    !pattern_newline(element)
+/// The arguments of a lambda start on a new line, or the lambda which is the
+/// value of the enclosing binding has a multi-line argument or starts on a new
+/// line.
+fn pattern_newline() -> Cond {
+    let lambda_has_newline = first_child(multiline()) | before_token_has_newline();
+    before_token_has_newline() | closest(NODE_KEY_VALUE, child_is(NODE_LAMBDA, lambda_has_newline))
 }
 
-fn lambda_inside_node_pattern(element: &SyntaxElement) -> bool {
-    element.ancestors().any(|e| e.kind() == NODE_PATTERN)
-}
-
-fn lambda_outside_node_pattern(element: &SyntaxElement) -> bool {
-    !lambda_inside_node_pattern(element)
-}
-
-fn after_concat_is_newline(element: &SyntaxElement) -> bool {
-    fn node_newline(element: &SyntaxElement) -> Option<bool> {
-        let first_el = element.as_node()?.descendants().filter(|e| e.kind() != NODE_BIN_OP).nth(0);
-        first_el.map(|e| has_newline(&e))
-    }
-    fn prev_newline(element: &SyntaxElement) -> Option<bool> {
-        let first_el = element
-            .ancestors()
-            .filter(|e| e.kind() == NODE_BIN_OP)
-            .max_by_key(|e| e.text_range().end());
-        first_el.map(|e| before_token_has_newline(&e.into()))
-    }
-
-    node_newline(element) == Some(false) && prev_newline(element) == Some(false)
-}
-
-fn newline_let(element: &SyntaxElement) -> bool {
-    let inline_let_in = inline_let_in(element);
-    let inside_key_value = element.parent().map(|e| e.kind() == NODE_KEY_VALUE).unwrap_or(false);
-
-    !before_token_has_newline(element) && inside_key_value && !inline_let_in
+/// Neither the first operand of a chain of binops, nor the chain itself, starts
+/// a line.
+fn after_concat_is_newline() -> Cond {
+    leftmost(NODE_BIN_OP, !multiline()) & outermost(NODE_BIN_OP, !before_token_has_newline())
 }
 
-fn no_newline_let(element: &SyntaxElement) -> bool {
-    !newline_let(element)
+/// A multi-line `let` which is the value of a binding and starts on the same
+/// line as the key.
+fn newline_let() -> Cond {
+    !before_token_has_newline() & parent(kind(&[NODE_KEY_VALUE])) & !inline_let_in()
 }
 
 static VALUES: &[SyntaxKind] = &[
@@ -793,6 +769,7 @@ mod tests {
     use crate::{
         reformat_string,
         rules::{indentation, spacing},
+        FormatOptions,
     };
 
     #[test]
@@ -821,11 +798,12 @@ foo = x:
     /// This test extracts such test cases and checks them.
     #[test]
     fn test_inline_spacing_tests() {
-        let rules = spacing();
+        let rules = spacing(&FormatOptions::default());
+        assert_eq!(rules.check(), Ok(()));
         let tests: Vec<TestCase> = rules
             .tests
             .iter()
-            .map(|&(before, after)| {
+            .map(|(_rule, (before, after))| {
                 let before = before.to_string();
                 let after = format!("{}\n", after);
                 TestCase::from_before_after(before, after)
@@ -836,11 +814,12 @@ foo = x:
 
     #[test]
     fn test_inline_indentation_tests() {
-        let rules = indentation();
+        let rules = indentation(&FormatOptions::default());
+        assert_eq!(rules.check(), Ok(()));
         let tests: Vec<TestCase> = rules
             .tests
             .iter()
-            .map(|&(before, after)| {
+            .map(|(_rule, (before, after))| {
                 let before = unindent::unindent(before);
                 let after = unindent::unindent(after);
                 TestCase::from_before_after(before, after)
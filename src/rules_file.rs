@@ -0,0 +1,358 @@
+//! This module loads user-defined spacing rules from a declarative file.
+//!
+//! The file is written in a subset of Nix: a list of attribute sets, each of
+//! which describes a single rule in the same terms as `SpacingDsl`.
+//!
+//! ```nix
+//! [
+//!   {
+//!     id = "no-space-before-at";
+//!     description = "No space before @ in lambda patterns";
+//!     inside = "NODE_PAT_BIND";
+//!     before = "TOKEN_AT";
+//!     space = "no_space";
+//!     tests = [
+//!       { before = "args @{ a }: a"; after = "args@{ a }: a"; }
+//!     ];
+//!   }
+//! ]
+//! ```
+//!
+//! Exactly one of `before`, `after`, `around` or `between` (a list of two
+//! elements) must be set. Syntax kinds can be given either as a single string
+//! or as a list of strings. `space` is one of the `SpacingRuleBuilder`
+//! methods, like `single_space` or `no_space_or_newline`. An optional `when`
+//! restricts `before`, `after` and `around` rules with a `Cond`, like
+//! `when = "parent(multiline)";`.
+use std::{error, fmt};
+
+use rnix::{
+    types::{AttrSet, EntryHolder, Ident, List, Str, TokenWrapper, TypedNode, Wrapper},
+    StrPart, SyntaxKind, SyntaxNode,
+};
+
+use crate::{
+    dsl::SpaceValue,
+    pattern::{kind_by_name, Cond},
+};
+
+/// A spacing rule loaded with `parse_rules_file`.
+///
+/// Pass it to the formatter via `FormatOptions::custom_rules`. Custom rules
+/// are applied after the built-in ones, so they can override them.
+#[derive(Debug, Clone)]
+pub struct CustomRule {
+    /// Stable identifier of the rule, which can be used with
+    /// `FormatOptions::disabled_rules`.
+    pub id: String,
+    pub description: String,
+    /// Examples from the `tests` attribute, as `(before, after)` pairs.
+    pub examples: Vec<(String, String)>,
+    pub(crate) inside: Vec<SyntaxKind>,
+    pub(crate) target: Target,
+    pub(crate) when: Option<Cond>,
+    pub(crate) space: SpaceValue,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Target {
+    Before(Vec<SyntaxKind>),
+    After(Vec<SyntaxKind>),
+    Around(Vec<SyntaxKind>),
+    Between(Vec<SyntaxKind>, Vec<SyntaxKind>),
+}
+
+/// An error in a rules file, with the line it occurred on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RulesFileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RulesFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for RulesFileError {}
+
+type Result<T> = std::result::Result<T, RulesFileError>;
+
+/// Parses the rules from the text of a rules file.
+pub fn parse_rules_file(text: &str) -> Result<Vec<CustomRule>> {
+    let ast = rnix::parse(text);
+    let parser = Parser { text };
+    if let Some(error) = ast.errors().first() {
+        return Err(RulesFileError { line: 1, message: format!("syntax error: {}", error) });
+    }
+    let root = ast.root().inner().ok_or_else(|| parser.error(&ast.node(), "empty file"))?;
+    let list =
+        List::cast(root.clone()).ok_or_else(|| parser.error(&root, "expected a list of rules"))?;
+
+    let builtin_ids: Vec<String> =
+        crate::rule_registry().into_iter().map(|it| it.id.into_owned()).collect();
+    let mut res: Vec<CustomRule> = Vec::new();
+    for item in list.items() {
+        let rule = parser.rule(&item)?;
+        if builtin_ids.contains(&rule.id) || res.iter().any(|it| it.id == rule.id) {
+            return Err(parser.error(&item, &format!("duplicate rule id: {}", rule.id)));
+        }
+        res.push(rule)
+    }
+    Ok(res)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+}
+
+impl Parser<'_> {
+    fn rule(&self, node: &SyntaxNode) -> Result<CustomRule> {
+        let attr_set =
+            AttrSet::cast(node.clone()).ok_or_else(|| self.error(node, "expected a rule"))?;
+        let mut id = None;
+        let mut description = None;
+        let mut inside = None;
+        let mut target = None;
+        let mut space = None;
+        let mut when = None;
+        let mut examples = Vec::new();
+        for entry in attr_set.entries() {
+            let key = entry
+                .key()
+                .and_then(|key| key.path().next())
+                .and_then(Ident::cast)
+                .ok_or_else(|| self.error(entry.node(), "expected a plain attribute name"))?;
+            let value = entry.value().ok_or_else(|| self.error(entry.node(), "missing value"))?;
+            match key.as_str() {
+                "id" => id = Some(self.id(&value)?),
+                "description" => description = Some(self.description(&value)?),
+                "inside" => inside = Some(self.kinds(&value)?),
+                "before" | "after" | "around" | "between" if target.is_some() => {
+                    return Err(self.error(entry.node(), "the element is already specified"))
+                }
+                "before" => target = Some(Target::Before(self.kinds(&value)?)),
+                "after" => target = Some(Target::After(self.kinds(&value)?)),
+                "around" => target = Some(Target::Around(self.kinds(&value)?)),
+                "between" => {
+                    let pair = List::cast(value.clone())
+                        .map(|it| it.items().collect::<Vec<_>>())
+                        .filter(|it| it.len() == 2)
+                        .ok_or_else(|| self.error(&value, "expected a list of two elements"))?;
+                    target = Some(Target::Between(self.kinds(&pair[0])?, self.kinds(&pair[1])?))
+                }
+                "space" => space = Some(self.space(&value)?),
+                "when" => {
+                    let cond = self.string(&value)?.parse().map_err(|err: String| {
+                        self.error(&value, &format!("invalid condition: {}", err))
+                    })?;
+                    when = Some(cond)
+                }
+                "tests" => examples = self.tests(&value)?,
+                other => {
+                    return Err(self.error(entry.node(), &format!("unknown attribute: {}", other)))
+                }
+            }
+        }
+        let missing = |name: &str| self.error(node, &format!("missing attribute: {}", name));
+        let target = target.ok_or_else(|| missing("before, after, around or between"))?;
+        if let (Target::Between(..), Some(_)) = (&target, &when) {
+            return Err(self.error(node, "when is not supported with between"));
+        }
+        Ok(CustomRule {
+            id: id.ok_or_else(|| missing("id"))?,
+            description: description.ok_or_else(|| missing("description"))?,
+            examples,
+            inside: inside.ok_or_else(|| missing("inside"))?,
+            target,
+            when,
+            space: space.ok_or_else(|| missing("space"))?,
+        })
+    }
+
+    fn id(&self, node: &SyntaxNode) -> Result<String> {
+        let id = self.string(node)?;
+        let is_kebab_case = !id.is_empty()
+            && id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !is_kebab_case {
+            return Err(self.error(node, "rule ids should be kebab-cased"));
+        }
+        Ok(id)
+    }
+
+    fn description(&self, node: &SyntaxNode) -> Result<String> {
+        let description = self.string(node)?;
+        if !description.chars().next().map(char::is_uppercase).unwrap_or(false) {
+            return Err(self.error(node, "rule descriptions should be capitalized"));
+        }
+        if description.ends_with('.') {
+            return Err(self.error(node, "rule descriptions should not end in `.`"));
+        }
+        Ok(description)
+    }
+
+    fn kinds(&self, node: &SyntaxNode) -> Result<Vec<SyntaxKind>> {
+        let names = match List::cast(node.clone()) {
+            Some(list) => list.items().collect(),
+            None => vec![node.clone()],
+        };
+        names
+            .iter()
+            .map(|name| {
+                let kind_name = self.string(name)?;
+                kind_by_name(&kind_name)
+                    .ok_or_else(|| self.error(name, &format!("unknown syntax kind: {}", kind_name)))
+            })
+            .collect()
+    }
+
+    fn space(&self, node: &SyntaxNode) -> Result<SpaceValue> {
+        let res = match self.string(node)?.as_str() {
+            "single_space" => SpaceValue::Single,
+            "single_space_or_optional_newline" => SpaceValue::SingleOptionalNewline,
+            "single_space_or_newline" => SpaceValue::SingleOrNewline,
+            "no_space" => SpaceValue::None,
+            "no_space_or_optional_newline" => SpaceValue::NoneOptionalNewline,
+            "no_space_or_newline" => SpaceValue::NoneOrNewline,
+            "newline" => SpaceValue::Newline,
+            "blank_line" => SpaceValue::BlankLine,
+            other => return Err(self.error(node, &format!("unknown space: {}", other))),
+        };
+        Ok(res)
+    }
+
+    fn tests(&self, node: &SyntaxNode) -> Result<Vec<(String, String)>> {
+        let list =
+            List::cast(node.clone()).ok_or_else(|| self.error(node, "expected a list of tests"))?;
+        let mut res = Vec::new();
+        for item in list.items() {
+            let attr_set = AttrSet::cast(item.clone())
+                .ok_or_else(|| self.error(&item, "expected { before = ...; after = ...; }"))?;
+            let mut before = None;
+            let mut after = None;
+            for entry in attr_set.entries() {
+                let key = entry.key().and_then(|key| key.path().next()).and_then(Ident::cast);
+                let value =
+                    entry.value().ok_or_else(|| self.error(entry.node(), "missing value"))?;
+                match key.as_ref().map(|it| it.as_str()) {
+                    Some("before") => before = Some(self.string(&value)?),
+                    Some("after") => after = Some(self.string(&value)?),
+                    _ => return Err(self.error(entry.node(), "expected before or after")),
+                }
+            }
+            match (before, after) {
+                (Some(before), Some(after)) => res.push((before, after)),
+                _ => return Err(self.error(&item, "expected { before = ...; after = ...; }")),
+            }
+        }
+        Ok(res)
+    }
+
+    fn string(&self, node: &SyntaxNode) -> Result<String> {
+        let parts =
+            Str::cast(node.clone()).ok_or_else(|| self.error(node, "expected a string"))?.parts();
+        let mut res = String::new();
+        for part in parts {
+            match part {
+                StrPart::Literal(literal) => res.push_str(&literal),
+                StrPart::Ast(_) => return Err(self.error(node, "interpolation is not supported")),
+            }
+        }
+        Ok(res)
+    }
+
+    fn error(&self, node: &SyntaxNode, message: &str) -> RulesFileError {
+        let offset: usize = node.text_range().start().into();
+        let line = self.text[..offset].matches('\n').count() + 1;
+        RulesFileError { line, message: message.to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_rules() {
+        let text = r#"[
+  {
+    id = "no-space-around-at";
+    description = "No space around @ in lambda patterns";
+    inside = [ "NODE_PATTERN" "NODE_PAT_BIND" ];
+    between = [ [ "NODE_IDENT" "NODE_PAT_BIND" ] [ "TOKEN_AT" "TOKEN_CURLY_B_OPEN" ] ];
+    space = "no_space";
+    tests = [ { before = "args @ { a }: a"; after = "args@{ a }: a"; } ];
+  }
+]"#;
+        let rules = parse_rules_file(text).unwrap();
+        assert_eq!(rules[0].id, "no-space-around-at");
+        assert_eq!(
+            rules[0].examples,
+            vec![("args @ { a }: a".to_string(), "args@{ a }: a".to_string())]
+        );
+        let options = crate::FormatOptions { custom_rules: rules, ..Default::default() };
+        assert_eq!(
+            crate::reformat_string_with_options("args @ { a }: a", &options),
+            "args@{ a }: a\n"
+        );
+        assert_eq!(
+            crate::explain_with_options("args@ { a }: a\n", &options),
+            "args@ { a }: a  # [5; 6): No space around @ in lambda patterns\n"
+        );
+    }
+
+    #[test]
+    fn applies_conditions() {
+        let text = r#"[
+  {
+    id = "no-space-before-inline-arg";
+    description = "No space before single-line attribute set arguments";
+    inside = "NODE_APPLY";
+    before = "NODE_ATTR_SET";
+    when = "!parent(multiline)";
+    space = "no_space";
+  }
+]"#;
+        let rules = parse_rules_file(text).unwrap();
+        assert_eq!(rules[0].when.as_ref().unwrap().to_string(), "!parent(multiline)");
+        let options = crate::FormatOptions { custom_rules: rules, ..Default::default() };
+        assert_eq!(crate::reformat_string_with_options("f { a = 1; }", &options), "f{ a = 1; }\n");
+        assert_eq!(
+            crate::reformat_string_with_options("f {\n  a = 1;\n}\n", &options),
+            "f {\n  a = 1;\n}\n"
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        let check = |text: &str, expected: &str| {
+            let err = parse_rules_file(text).unwrap_err();
+            assert_eq!(err.to_string(), expected);
+        };
+        check("{ }", "line 1: expected a list of rules");
+        check(
+            "[\n  { id = \"foo\"; description = \"Foo\"; inside = \"NODE_FOO\"; }\n]",
+            "line 2: unknown syntax kind: NODE_FOO",
+        );
+        check(
+            "[\n  { id = \"foo\"; description = \"Foo\"; inside = \"NODE_LIST\"; }\n]",
+            "line 2: missing attribute: before, after, around or between",
+        );
+        check(
+            "[ { id = \"space-before-eq\"; description = \"Foo\"; inside = \"NODE_LIST\"; \
+             before = \"TOKEN_SQUARE_B_CLOSE\"; space = \"no_space\"; } ]",
+            "line 1: duplicate rule id: space-before-eq",
+        );
+        check(
+            "[\n  { id = \"foo\"; description = \"Foo\"; inside = \"NODE_LIST\";\n    \
+             when = \"parent(multiline\"; }\n]",
+            "line 3: invalid condition: expected `)`",
+        );
+        check(
+            "[ { id = \"foo\"; description = \"Foo\"; inside = \"NODE_LIST\"; when = \"any\"; \
+             between = [ \"NODE_IDENT\" \"NODE_IDENT\" ]; space = \"no_space\"; } ]",
+            "line 1: when is not supported with between",
+        );
+    }
+}
@@ -68,10 +68,6 @@ pub(crate) fn next_sibling(element: &SyntaxElement) -> Option<SyntaxNode> {
     )
 }
 
-pub(crate) fn not_on_top_level(element: &SyntaxElement) -> bool {
-    !on_top_level(element)
-}
-
 pub(crate) fn on_top_level(element: &SyntaxElement) -> bool {
     let parent = match element.parent() {
         None => return true,
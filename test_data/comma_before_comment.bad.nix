@@ -0,0 +1,4 @@
+{ cc
+, # comment
+  bintools ? null
+}: 92
@@ -0,0 +1,6 @@
+{
+/* foo
+  	
+*/
+a = 1;
+}
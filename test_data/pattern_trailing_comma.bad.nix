@@ -0,0 +1,6 @@
+{
+  stdenv,
+  fetchurl,
+}:
+
+92
@@ -1,5 +1,5 @@
 {
-  /* 
+  /*
     decorated
     block
     comment
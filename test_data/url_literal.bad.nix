@@ -0,0 +1,7 @@
+{
+  src = fetchurl {
+    url = http://example.com/foo-1.0.tar.gz;
+    sha256 = "0000000000000000000000000000000000000000000000000000";
+  };
+  meta.homepage = https://example.com/;
+}
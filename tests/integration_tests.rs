@@ -57,3 +57,203 @@ fn stdout_closed_parse() {
 fn stdout_closed_explain() {
     test_stdout_closed(&["--explain"]);
 }
+
+#[test]
+fn parse_errors_have_positions() {
+    use std::io::Write;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nixpkgs-fmt"))
+        .arg("--parse")
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"{\n  a = ;\n}\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error: unexpected TOKEN_SEMICOLON at 8..9"), "{}", stderr);
+    assert!(stderr.contains(" --> <stdin>:2:7\n  |\n2 |   a = ;\n  |       ^\n"), "{}", stderr);
+}
+
+#[test]
+fn query_prints_matches() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data/issue-126.good.nix");
+    let output = Command::new(env!("CARGO_BIN_EXE_nixpkgs-fmt"))
+        .args(["query", "NODE_KEY_VALUE[key=version] > NODE_STRING"])
+        .arg(&path)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let expected: String = [47, 56]
+        .iter()
+        .map(|line| format!("{}:{}:17: \"${{foo}}-mptcp_v1.0.0\"\n", path.display(), line))
+        .collect();
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn rewrite_formats_only_the_rewritten_code() {
+    use std::io::Write;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nixpkgs-fmt"))
+        .args(["rewrite", "-e", "NODE_SELECT[text=stdenv.lib] => lib"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"{\n  a =  1;\n  b = stdenv.lib.id;\n}\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "{\n  a =  1;\n  b = lib.id;\n}\n");
+}
+
+#[test]
+fn lint_reports_warnings() {
+    use std::io::Write;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nixpkgs-fmt"))
+        .arg("--lint")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"{ a = stdenv.lib.id; }\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "<stdin>:1:7-1:16: warning[stdenv-lib]: Use lib instead of stdenv.lib\n"
+    );
+}
+
+#[test]
+fn rule_conflicts_are_reported() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("rule_conflicts_are_reported");
+    std::fs::create_dir_all(&dir).unwrap();
+    let rules = dir.join("rules.nix");
+    std::fs::write(
+        &rules,
+        r#"[ { id = "tight-in"; description = "One space around in"; inside = "NODE_LET_IN"; around = "TOKEN_IN"; space = "single_space"; } ]"#,
+    )
+    .unwrap();
+    let file = dir.join("default.nix");
+    std::fs::write(&file, "let\n  a = 1;\nin\na\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nixpkgs-fmt"))
+        .args(["--check", "--rules"])
+        .arg(&rules)
+        .arg(&file)
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let warning = format!(
+        "warning: {}: spacing rules `multiline-let-in` and `tight-in` both match [13; 15)\n",
+        file.display()
+    );
+    assert!(stderr.contains(&warning), "{}", stderr);
+}
+
+fn parse_with_format(output_format: &str, input: &str) -> String {
+    use std::io::Write;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nixpkgs-fmt"))
+        .args(["--parse", "--output-format", output_format])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn parse_prints_sexpr() {
+    assert_eq!(
+        parse_with_format("sexpr", "[ \"a\\\"\nb\" ]"),
+        r#"(NODE_ROOT 0..11
+  (NODE_LIST 0..11
+    (TOKEN_SQUARE_B_OPEN 0..1 "[")
+    (TOKEN_WHITESPACE 1..2 " ")
+    (NODE_STRING 2..9 (TOKEN_STRING_START 2..3 "\"") (TOKEN_STRING_CONTENT 3..8 "a\\\"\nb") (TOKEN_STRING_END 8..9 "\""))
+    (TOKEN_WHITESPACE 9..10 " ")
+    (TOKEN_SQUARE_B_CLOSE 10..11 "]")))
+"#
+    );
+}
+
+#[test]
+fn parse_prints_dot() {
+    assert_eq!(
+        parse_with_format("dot", "\"a\\\"\nb\""),
+        r#"digraph syntax {
+  node [shape=box, fontname=monospace];
+  n0 [label="NODE_ROOT 0..7"];
+  n1 [label="NODE_STRING 0..7"];
+  n0 -> n1;
+  n2 [label="TOKEN_STRING_START 0..1\n\"\\\"\"", shape=plaintext];
+  n1 -> n2;
+  n3 [label="TOKEN_STRING_CONTENT 1..6\n\"a\\\\\\\"\\nb\"", shape=plaintext];
+  n1 -> n3;
+  n4 [label="TOKEN_STRING_END 6..7\n\"\\\"\"", shape=plaintext];
+  n1 -> n4;
+}
+"#
+    );
+}
+
+#[test]
+fn parse_prints_typed() {
+    assert_eq!(
+        parse_with_format("typed", "{ a = x: -x + 1; b = rec { }; }"),
+        r#"Root 0..31
+  AttrSet 0..31
+    KeyValue 2..16
+      key: Key 2..3
+        Ident 2..3 "a"
+      value: Lambda 6..15
+        arg: Ident 6..7 "x"
+        body: BinOp 9..15 Add
+          lhs: UnaryOp 9..11 Negate
+            value: Ident 10..11 "x"
+          rhs: Value 14..15 1
+    KeyValue 17..29
+      key: Key 17..18
+        Ident 17..18 "b"
+      value: AttrSet 21..28 rec
+"#
+    );
+}
+
+#[test]
+fn rewrite_errors_fail_in_directories_too() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("rewrite_errors_fail_in_directories_too");
+    std::fs::create_dir_all(&dir).unwrap();
+    let bad = dir.join("a.nix");
+    std::fs::write(&bad, "{ b = a; }\n").unwrap();
+    std::fs::write(dir.join("b.nix"), "{ b = c; }\n").unwrap();
+
+    for target in [&dir, &bad] {
+        let output = Command::new(env!("CARGO_BIN_EXE_nixpkgs-fmt"))
+            .args(["rewrite", "-e", "NODE_IDENT[text=a] => ("])
+            .arg(target)
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(1), "{}", target.display());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let error = format!("error: {}: the rewritten code does not parse", bad.display());
+        assert!(stderr.contains(&error), "{}", stderr);
+        assert!(stderr.ends_with("error: 1 file(s) could not be rewritten\n"), "{}", stderr);
+    }
+}
@@ -2,4 +2,8 @@
 
 cd "$(dirname "$0")"
 
+# The module for the demo page
 wasm-pack build --target web
+
+# The module for the npm package
+wasm-pack build --target nodejs --out-dir npm/pkg
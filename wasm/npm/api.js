@@ -0,0 +1,76 @@
+"use strict";
+
+// The JS API on top of the WASM bindings. The bindings are loaded by
+// `loadBindings`, so that the parts which don't need the formatter can be
+// tested without building it.
+//
+// The module can't be used again after the formatter panics, so the bindings
+// are loaded again then, and the call throws a `nixpkgs-fmt panicked:` error.
+function createApi(loadBindings) {
+  let bindings = loadBindings();
+  const call = (f) => {
+    try {
+      return f(bindings);
+    } catch (err) {
+      const message = panicMessage(bindings, err);
+      if (message === null) {
+        throw err;
+      }
+      bindings = loadBindings();
+      throw new Error(`nixpkgs-fmt panicked: ${message}`);
+    }
+  };
+  return {
+    // Returns the formatted text.
+    format: (text, options) => call((it) => it.format(text, options)),
+    // Returns true if the text is formatted already, like `--check`.
+    check: (text, options) => !call((it) => it.reformat(text, options)).changed,
+    // Annotates each line with the rules which would change it, like
+    // `--explain`.
+    explain: (text, options) => call((it) => it.explain(text, options)),
+    reformat: (text, options) => call((it) => it.reformat(text, options)),
+    edits: (text, options) => call((it) => it.edits(text, options)),
+    parse: (text) => call((it) => it.parse(text)),
+    parseErrors: (text) => call((it) => it.parse_errors(text)),
+    rules: () => call((it) => it.rules()),
+  };
+}
+
+// Returns the message of the panic which made `err` be thrown, or `null` if
+// it is an ordinary error, like invalid options. The call which panics fails
+// with a `RuntimeError`, and the later ones with `nixpkgs-fmt panicked:`.
+function panicMessage(bindings, err) {
+  const prefix = "nixpkgs-fmt panicked: ";
+  if (typeof err === "string" && err.startsWith(prefix)) {
+    return err.slice(prefix.length);
+  }
+  try {
+    return bindings.panic_message();
+  } catch (_) {
+    return String(err);
+  }
+}
+
+// Builds a Prettier document which prints `text` with `edits` applied. The
+// unchanged parts and the inserted parts alternate, and line breaks become
+// `literalline`s, which, unlike `hardline`s, keep trailing whitespace as is,
+// as it is significant in `''` strings.
+function editsToDoc(text, edits, builders) {
+  const parts = [];
+  const push = (segment) => {
+    if (segment !== "") {
+      parts.push(builders.join(builders.literalline, segment.split("\n")));
+    }
+  };
+  let prev = 0;
+  const sorted = [...edits].sort((a, b) => a.range.start - b.range.start);
+  for (const edit of sorted) {
+    push(text.slice(prev, edit.range.start));
+    push(edit.newText);
+    prev = edit.range.end;
+  }
+  push(text.slice(prev));
+  return parts;
+}
+
+module.exports = { createApi, editsToDoc };
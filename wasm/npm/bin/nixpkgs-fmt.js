@@ -0,0 +1,52 @@
+#!/usr/bin/env node
+"use strict";
+
+// A subset of the nixpkgs-fmt command line: formats the given files in place,
+// or stdin to stdout, with `--check` and `--explain` like the native binary.
+const fs = require("fs");
+const nixpkgsFmt = require("../index.js");
+
+function main(args) {
+  const check = args.includes("--check");
+  const explain = args.includes("--explain");
+  const files = args.filter((arg) => !arg.startsWith("--"));
+
+  if (files.length === 0) {
+    const input = fs.readFileSync(0, "utf8");
+    if (check) {
+      return nixpkgsFmt.check(input) ? 0 : 1;
+    }
+    process.stdout.write(explain ? nixpkgsFmt.explain(input) : nixpkgsFmt.format(input));
+    return 0;
+  }
+
+  let unformatted = 0;
+  for (const file of files) {
+    const input = fs.readFileSync(file, "utf8");
+    if (explain) {
+      process.stdout.write(nixpkgsFmt.explain(input));
+      continue;
+    }
+    const result = nixpkgsFmt.reformat(input);
+    for (const error of result.errors) {
+      console.error(`${file}: warning: ${error.message}`);
+    }
+    if (!result.changed) {
+      continue;
+    }
+    unformatted += 1;
+    if (check) {
+      console.error(`${file}: not formatted`);
+    } else {
+      fs.writeFileSync(file, result.output);
+    }
+  }
+  return check && unformatted > 0 ? 1 : 0;
+}
+
+try {
+  process.exitCode = main(process.argv.slice(2));
+} catch (err) {
+  console.error(`error: ${err}`);
+  process.exitCode = 1;
+}
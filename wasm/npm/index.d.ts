@@ -0,0 +1,23 @@
+import type {
+  FormatOptions,
+  FormatResult,
+  ParseError,
+  Rule,
+  SyntaxNode,
+  TextEdit,
+} from "./pkg/nixpkgs_fmt_wasm";
+
+export type { FormatOptions, FormatResult, ParseError, Rule, SyntaxNode, TextEdit };
+
+/** Returns the formatted text. */
+export function format(text: string, options?: FormatOptions): string;
+/** Returns true if the text is formatted already, like `nixpkgs-fmt --check`. */
+export function check(text: string, options?: FormatOptions): boolean;
+/** Annotates each line with the rules which would change it, like `nixpkgs-fmt --explain`. */
+export function explain(text: string, options?: FormatOptions): string;
+export function reformat(text: string, options?: FormatOptions): FormatResult;
+/** The edits which format the text, as a single transaction. */
+export function edits(text: string, options?: FormatOptions): TextEdit[];
+export function parse(text: string): SyntaxNode;
+export function parseErrors(text: string): ParseError[];
+export function rules(): Rule[];
@@ -0,0 +1,11 @@
+"use strict";
+
+const { createApi } = require("./api.js");
+
+const bindingsPath = require.resolve("./pkg/nixpkgs_fmt_wasm.js");
+
+// Requiring the bindings again instantiates a new module.
+module.exports = createApi(() => {
+  delete require.cache[bindingsPath];
+  return require(bindingsPath);
+});
@@ -0,0 +1,34 @@
+"use strict";
+
+// A Prettier plugin which formats Nix files with nixpkgs-fmt. The parser
+// computes the edits, and the printer turns them into a Prettier document.
+const { builders } = require("prettier/doc");
+const { editsToDoc } = require("./api.js");
+const nixpkgsFmt = require("./index.js");
+
+module.exports = {
+  languages: [
+    {
+      name: "Nix",
+      parsers: ["nixpkgs-fmt"],
+      extensions: [".nix"],
+      vscodeLanguageIds: ["nix"],
+    },
+  ],
+  parsers: {
+    "nixpkgs-fmt": {
+      parse: (text) => ({ type: "root", text, edits: nixpkgsFmt.edits(text) }),
+      astFormat: "nixpkgs-fmt",
+      locStart: () => 0,
+      locEnd: (node) => node.text.length,
+    },
+  },
+  printers: {
+    "nixpkgs-fmt": {
+      print: (path) => {
+        const node = path.getValue();
+        return editsToDoc(node.text, node.edits, builders);
+      },
+    },
+  },
+};
@@ -0,0 +1,89 @@
+"use strict";
+
+const assert = require("assert");
+const test = require("node:test");
+const { createApi, editsToDoc } = require("../api.js");
+
+// Stands in for the WASM bindings, which only reformat `{a=1;}`.
+const fakeBindings = {
+  reformat: (text) => {
+    const output = text === "{a=1;}\n" ? "{ a = 1; }\n" : text;
+    return { output, changed: output !== text, diff: null, errors: [] };
+  },
+  panic_message: () => null,
+};
+
+// Stands in for bindings which panic when formatting `boom` and fail every
+// call afterwards, like the WASM module does.
+function panickingBindings() {
+  let panicked = null;
+  return {
+    format: (text) => {
+      if (panicked !== null) {
+        throw `nixpkgs-fmt panicked: ${panicked}`;
+      }
+      if (text === "boom") {
+        panicked = "boom";
+        throw new Error("unreachable");
+      }
+      if (text === "bad options") {
+        throw "unknown rule: bad";
+      }
+      return text;
+    },
+    panic_message: () => panicked,
+  };
+}
+
+// Renders documents built from strings, `join` and `literalline` only.
+const builders = {
+  literalline: { type: "literalline" },
+  join: (separator, parts) => ({ type: "join", separator, parts }),
+};
+const render = (doc) => {
+  if (typeof doc === "string") {
+    return doc;
+  } else if (Array.isArray(doc)) {
+    return doc.map(render).join("");
+  } else if (doc.type === "literalline") {
+    return "\n";
+  }
+  return doc.parts.map(render).join(render(doc.separator));
+};
+
+test("check tells if the text is formatted", () => {
+  const api = createApi(() => fakeBindings);
+  assert.strictEqual(api.check("{ a = 1; }\n"), true);
+  assert.strictEqual(api.check("{a=1;}\n"), false);
+});
+
+test("bindings are loaded again after a panic", () => {
+  let loads = 0;
+  const api = createApi(() => {
+    loads += 1;
+    return panickingBindings();
+  });
+  assert.strictEqual(api.format("a"), "a");
+  assert.throws(() => api.format("bad options"), /unknown rule: bad/);
+  assert.strictEqual(loads, 1);
+  assert.throws(() => api.format("boom"), {
+    message: "nixpkgs-fmt panicked: boom",
+  });
+  assert.strictEqual(loads, 2);
+  assert.strictEqual(api.format("a"), "a");
+});
+
+test("edits are printed as a Prettier document", () => {
+  const text = "{a=1;\n  b = ''\n    x  \n  '';}\n";
+  const range = (start, end) => ({ start, end });
+  const edits = [
+    { range: range(28, 28), newText: "\n" },
+    { range: range(1, 1), newText: "\n  " },
+    { range: range(2, 3), newText: " = " },
+  ];
+  assert.strictEqual(
+    render(editsToDoc(text, edits, builders)),
+    "{\n  a = 1;\n  b = ''\n    x  \n  '';\n}\n",
+  );
+  assert.strictEqual(render(editsToDoc(text, [], builders)), text);
+});
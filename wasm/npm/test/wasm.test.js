@@ -0,0 +1,56 @@
+"use strict";
+
+// These tests need the module built by `../../build.sh`.
+const assert = require("assert");
+const fs = require("fs");
+const path = require("path");
+const test = require("node:test");
+
+const built = fs.existsSync(path.join(__dirname, "../pkg/nixpkgs_fmt_wasm.js"));
+const skip = built ? false : "the WASM module is not built";
+
+test("formats, checks and explains", { skip }, () => {
+  const nixpkgsFmt = require("../index.js");
+  assert.strictEqual(nixpkgsFmt.format("{a=1;}"), "{ a = 1; }\n");
+  assert.strictEqual(nixpkgsFmt.check("{a=1;}"), false);
+  assert.strictEqual(nixpkgsFmt.check("{ a = 1; }\n"), true);
+  assert.match(nixpkgsFmt.explain("{a=1;}\n"), /Space before =/);
+});
+
+test("returns result objects", { skip }, () => {
+  const nixpkgsFmt = require("../index.js");
+  const result = nixpkgsFmt.reformat("{a=1;}\n");
+  assert.strictEqual(result.changed, true);
+  assert.match(result.diff, /^--- Input/);
+  assert.deepStrictEqual(result.errors, []);
+
+  const invalid = nixpkgsFmt.reformat("{ a = 1;\n");
+  assert.notDeepStrictEqual(invalid.errors, []);
+
+  const unchanged = nixpkgsFmt.reformat("{ a = 1; }\n");
+  assert.deepStrictEqual(unchanged, {
+    output: "{ a = 1; }\n",
+    changed: false,
+    diff: null,
+    errors: [],
+  });
+});
+
+test("returns edits with positions", { skip }, () => {
+  const nixpkgsFmt = require("../index.js");
+  const edits = nixpkgsFmt.edits("# ö\n{a = 1;}\n");
+  assert.deepStrictEqual(edits[0], {
+    range: {
+      start: 5,
+      end: 5,
+      startPosition: { line: 1, character: 1 },
+      endPosition: { line: 1, character: 1 },
+    },
+    newText: " ",
+  });
+});
+
+test("throws on invalid options", { skip }, () => {
+  const nixpkgsFmt = require("../index.js");
+  assert.throws(() => nixpkgsFmt.format("{}", { disabledRules: ["no-such-rule"] }));
+});
@@ -1,31 +1,430 @@
-use std::panic;
+use std::{cell::RefCell, panic};
+
+use nixpkgs_fmt::{AtomEdit, CommaStyle, CustomRule, FormatOptions, Formatter, RulesFileError};
+use rnix::{parser::ParseError, TextRange, TextSize};
+use serde_json::{json, Value};
 use wasm_bindgen::prelude::*;
 
 // Use the smaller `wee_alloc` as the global allocator.
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+#[wasm_bindgen(typescript_custom_section)]
+const TYPES: &'static str = r#"
+/** The options of the formatter, see `nixpkgs-fmt --help`. */
+export interface FormatOptions {
+  quoteUrls?: boolean;
+  commaStyle?: "leading" | "trailing";
+  maxBlankLines?: number;
+  trimBlankLines?: boolean;
+  separateMultilineBindings?: boolean;
+  /** IDs or kinds of the rules to skip, see `rules()`. */
+  disabledRules?: string[];
+  /** IDs or kinds of the only rules to apply, see `rules()`. */
+  onlyRules?: string[];
+  /** The contents of a rules file, see `--rules`. */
+  rules?: string;
+}
+
+/** A zero-based position, with the character counted in UTF-16 code units. */
+export interface Position {
+  line: number;
+  character: number;
+}
+
+/** A range of the input text, `start` and `end` are UTF-16 offsets. */
+export interface Range {
+  start: number;
+  end: number;
+  startPosition: Position;
+  endPosition: Position;
+}
+
+/** A replacement of `range` of the input with `newText`. */
+export interface TextEdit {
+  range: Range;
+  newText: string;
+}
+
+export interface ParseError {
+  message: string;
+  /** The range of the error, if it isn't at the end of the input. */
+  range: Range | null;
+}
+
+export interface FormatResult {
+  output: string;
+  changed: boolean;
+  /** The unified diff from the input to the output, if it changed. */
+  diff: string | null;
+  errors: ParseError[];
+}
+
+export interface Rule {
+  id: string;
+  description: string;
+  kind: string;
+}
+
+/**
+ * A node of the syntax tree, as printed by `nixpkgs-fmt --parse --output-format json`.
+ * Unlike elsewhere, `text_range` holds UTF-8 byte offsets.
+ */
+export interface SyntaxNode {
+  kind: string;
+  text_range: [number, number];
+  children: (SyntaxNode | SyntaxToken)[];
+}
+
+export interface SyntaxToken {
+  kind: string;
+  text_range: [number, number];
+  text: string;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "FormatOptions")]
+    pub type JsFormatOptions;
+    #[wasm_bindgen(typescript_type = "FormatResult")]
+    pub type FormatResult;
+    #[wasm_bindgen(typescript_type = "TextEdit[]")]
+    pub type TextEdits;
+    #[wasm_bindgen(typescript_type = "ParseError[]")]
+    pub type ParseErrors;
+    #[wasm_bindgen(typescript_type = "Rule[]")]
+    pub type Rules;
+    #[wasm_bindgen(typescript_type = "SyntaxNode")]
+    pub type JsSyntaxNode;
+}
+
+thread_local! {
+    /// The message of the panic which poisoned the module, if any.
+    static PANIC_MESSAGE: RefCell<Option<String>> = RefCell::new(None);
+}
+
 // Called when the wasm module is instantiated
 #[wasm_bindgen(start)]
 pub fn main() -> Result<(), JsValue> {
-    panic::set_hook(Box::new(console_error_panic_hook::hook));
+    panic::set_hook(Box::new(|info| {
+        console_error_panic_hook::hook(info);
+        // There is no unwinding on wasm32-unknown-unknown, so the call aborts
+        // with a `RuntimeError` once the hook returns. The state of the module
+        // can't be trusted afterwards, so all the later calls fail.
+        PANIC_MESSAGE.with(|it| *it.borrow_mut() = Some(info.to_string()));
+    }));
     Ok(())
 }
 
+/// Returns the message of the panic after which the module has to be loaded
+/// again, or `null` if it hasn't panicked.
 #[wasm_bindgen]
-pub fn reformat_string(text: &str, format: &str) -> String {
-    let out = nixpkgs_fmt::reformat_string(text);
+pub fn panic_message() -> Option<String> {
+    PANIC_MESSAGE.with(|it| it.borrow().clone())
+}
 
-    if format == "diff" {
-        let first_text = text.lines().collect::<Vec<&str>>();
-        let second_text = out.lines().collect::<Vec<&str>>();
-        let diff = difflib::unified_diff(&first_text, &second_text, "Input", "Output", "", "", 3);
-        if diff.len() == 0 {
-            return String::from("No changes found");
-        } else {
-            return [diff[0..3].join(""), diff[3..].join("\n")].join("");
+/// Fails if the module has panicked before.
+fn check_poisoned() -> Result<(), JsValue> {
+    match panic_message() {
+        Some(message) => Err(JsValue::from_str(&format!("nixpkgs-fmt panicked: {}", message))),
+        None => Ok(()),
+    }
+}
+
+/// Formats `text`, also returning whether it changed, the unified diff of the
+/// changes and the syntax errors, which the formatter skips over.
+#[wasm_bindgen]
+pub fn reformat(text: &str, options: Option<JsFormatOptions>) -> Result<FormatResult, JsValue> {
+    check_poisoned()?;
+    let output = formatter(options)?.reformat_string(text);
+    let changed = output != text;
+    let diff = if changed { unified_diff(text, &output) } else { None };
+    let errors = errors(text, &LineIndex::new(text));
+    to_js(&json!({ "output": output, "changed": changed, "diff": diff, "errors": errors }))
+}
+
+/// Returns the unified diff between `text` and `output`, or `None` if they
+/// are the same. A missing final newline counts as a change, like in `diff`.
+fn unified_diff(text: &str, output: &str) -> Option<String> {
+    let lines = |text: &str| -> Vec<String> {
+        text.split_inclusive('\n')
+            .map(|line| match line.strip_suffix('\n') {
+                Some(line) => line.to_string(),
+                None => format!("{}\n\\ No newline at end of file", line),
+            })
+            .collect()
+    };
+    let diff = difflib::unified_diff(&lines(text), &lines(output), "Input", "Output", "", "", 3);
+    if diff.is_empty() {
+        return None;
+    }
+    Some([diff[0..3].join(""), diff[3..].join("\n")].join(""))
+}
+
+/// Formats `text`.
+#[wasm_bindgen]
+pub fn format(text: &str, options: Option<JsFormatOptions>) -> Result<String, JsValue> {
+    check_poisoned()?;
+    Ok(formatter(options)?.reformat_string(text))
+}
+
+/// Returns the edits which format `text`, as a single transaction, so that
+/// editors can apply them without replacing the whole buffer.
+#[wasm_bindgen]
+pub fn edits(text: &str, options: Option<JsFormatOptions>) -> Result<TextEdits, JsValue> {
+    check_poisoned()?;
+    let edits = formatter(options)?.text_edits(text);
+    let ranges = LineIndex::new(text).ranges(edits.iter().map(|edit| edit.delete));
+    let edits: Vec<Value> = edits
+        .iter()
+        .zip(ranges)
+        .map(|(AtomEdit { insert, .. }, range)| json!({ "range": range, "newText": insert.as_str() }))
+        .collect();
+    to_js(&Value::from(edits))
+}
+
+/// Annotates each line of `text` with the rules which would change it, like
+/// `nixpkgs-fmt --explain`.
+#[wasm_bindgen]
+pub fn explain(text: &str, options: Option<JsFormatOptions>) -> Result<String, JsValue> {
+    check_poisoned()?;
+    Ok(formatter(options)?.explain(text))
+}
+
+/// Returns the syntax tree of `text`, like `nixpkgs-fmt --parse --output-format json`.
+#[wasm_bindgen]
+pub fn parse(text: &str) -> Result<JsSyntaxNode, JsValue> {
+    check_poisoned()?;
+    let node = serde_json::to_value(&rnix::parse(text).node())
+        .map_err(|err| JsValue::from_str(&err.to_string()))?;
+    to_js(&node)
+}
+
+/// Returns the syntax errors of `text`. Note that the formatter still formats
+/// the text around the errors.
+#[wasm_bindgen]
+pub fn parse_errors(text: &str) -> Result<ParseErrors, JsValue> {
+    check_poisoned()?;
+    to_js(&Value::from(errors(text, &LineIndex::new(text))))
+}
+
+/// Lists the rules which can be selected with `disabledRules` and
+/// `onlyRules`.
+#[wasm_bindgen]
+pub fn rules() -> Result<Rules, JsValue> {
+    check_poisoned()?;
+    let rules: Vec<Value> = nixpkgs_fmt::rule_registry()
+        .iter()
+        .map(|rule| {
+            json!({ "id": rule.id, "description": rule.description, "kind": rule.kind.to_string() })
+        })
+        .collect();
+    to_js(&Value::from(rules))
+}
+
+fn to_js<T: JsCast>(value: &Value) -> Result<T, JsValue> {
+    let value = JsValue::from_serde(value).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    Ok(value.unchecked_into())
+}
+
+fn formatter(options: Option<JsFormatOptions>) -> Result<Formatter, JsValue> {
+    let options = match options {
+        Some(options) => format_options(options).map_err(|err| JsValue::from_str(&err))?,
+        None => FormatOptions::default(),
+    };
+    Ok(Formatter::builder().options(options).build())
+}
+
+fn format_options(options: JsFormatOptions) -> Result<FormatOptions, String> {
+    let options: Value = JsValue::from(options).into_serde().map_err(|err| err.to_string())?;
+    let flag = |name: &str, default: bool| -> Result<bool, String> {
+        match &options[name] {
+            Value::Null => Ok(default),
+            value => value.as_bool().ok_or_else(|| format!("invalid {}: {}", name, value)),
+        }
+    };
+    let rule_ids = |name: &str| -> Result<Option<Vec<String>>, String> {
+        let selectors = match &options[name] {
+            Value::Null => return Ok(None),
+            Value::Array(selectors) => selectors,
+            value => return Err(format!("invalid {}: {}", name, value)),
+        };
+        let selectors = selectors
+            .iter()
+            .map(|selector| {
+                selector.as_str().ok_or_else(|| format!("invalid {}: {}", name, selector))
+            })
+            .collect::<Result<Vec<&str>, String>>()?;
+        Ok(Some(nixpkgs_fmt::resolve_rules(selectors)?))
+    };
+
+    let mut res = FormatOptions::default();
+    res.quote_urls = flag("quoteUrls", res.quote_urls)?;
+    res.comma_style = match &options["commaStyle"] {
+        Value::Null => res.comma_style,
+        Value::String(style) if style == "leading" => Some(CommaStyle::Leading),
+        Value::String(style) if style == "trailing" => Some(CommaStyle::Trailing),
+        value => return Err(format!("invalid commaStyle: {}", value)),
+    };
+    res.max_blank_lines = match &options["maxBlankLines"] {
+        Value::Null => res.max_blank_lines,
+        value => {
+            Some(value.as_u64().ok_or_else(|| format!("invalid maxBlankLines: {}", value))? as usize)
+        }
+    };
+    res.trim_blank_lines = flag("trimBlankLines", res.trim_blank_lines)?;
+    res.separate_multiline_bindings =
+        flag("separateMultilineBindings", res.separate_multiline_bindings)?;
+    res.disabled_rules = rule_ids("disabledRules")?.unwrap_or_default();
+    res.only_rules = rule_ids("onlyRules")?;
+    res.custom_rules = match &options["rules"] {
+        Value::Null => Vec::new(),
+        Value::String(rules) => custom_rules(rules).map_err(|err| format!("rules: {}", err))?,
+        value => return Err(format!("invalid rules: {}", value)),
+    };
+    Ok(res)
+}
+
+thread_local! {
+    /// The rules file which was parsed last, together with its rules. Editors
+    /// and the Prettier plugin pass the same file with every call.
+    static RULES_CACHE: RefCell<Option<(String, Vec<CustomRule>)>> = RefCell::new(None);
+}
+
+fn custom_rules(text: &str) -> Result<Vec<CustomRule>, RulesFileError> {
+    RULES_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if let Some((cached_text, rules)) = &*cache {
+            if cached_text == text {
+                return Ok(rules.clone());
+            }
         }
-    } else {
-        return out;
+        let rules = nixpkgs_fmt::parse_rules_file(text)?;
+        *cache = Some((text.to_string(), rules.clone()));
+        Ok(rules)
+    })
+}
+
+fn errors(text: &str, index: &LineIndex) -> Vec<Value> {
+    let errors = rnix::parse(text).errors();
+    let mut ranges = index.ranges(errors.iter().filter_map(error_range)).into_iter();
+    errors
+        .iter()
+        .map(|error| {
+            json!({
+                "message": error.to_string(),
+                "range": error_range(error).map(|_| ranges.next().unwrap()),
+            })
+        })
+        .collect()
+}
+
+fn error_range(error: &ParseError) -> Option<TextRange> {
+    match error {
+        ParseError::Unexpected(range)
+        | ParseError::UnexpectedExtra(range)
+        | ParseError::UnexpectedWanted(_, range, _)
+        | ParseError::UnexpectedDoubleBind(range)
+        | ParseError::DuplicatedArgs(range, _) => Some(*range),
+        // `UnexpectedEOF` and friends
+        _ => None,
+    }
+}
+
+/// Converts the byte offsets of the formatter into the UTF-16 offsets and
+/// positions of JavaScript strings.
+struct LineIndex<'a> {
+    text: &'a str,
+    /// The byte offsets of the starts of the lines.
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(text: &'a str) -> LineIndex<'a> {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(idx, _)| idx + 1));
+        LineIndex { text, line_starts }
+    }
+
+    /// Converts `ranges` in one pass over the text, whatever their order.
+    fn ranges(&self, ranges: impl Iterator<Item = TextRange>) -> Vec<Value> {
+        let mut offsets: Vec<(TextSize, usize)> = ranges
+            .enumerate()
+            .flat_map(|(idx, range)| vec![(range.start(), 2 * idx), (range.end(), 2 * idx + 1)])
+            .collect();
+        offsets.sort();
+
+        let utf16_len = |text: &str| text.chars().map(char::len_utf16).sum::<usize>();
+        let mut positions = vec![(0, Value::Null); offsets.len()];
+        let (mut line, mut line_start_utf16) = (0, 0);
+        let (mut prev_offset, mut utf16_offset) = (0, 0);
+        for (offset, idx) in offsets {
+            let offset = usize::from(offset);
+            while self.line_starts.get(line + 1).map_or(false, |&start| start <= offset) {
+                line += 1;
+                let line_start = self.line_starts[line];
+                utf16_offset += utf16_len(&self.text[prev_offset..line_start]);
+                prev_offset = line_start;
+                line_start_utf16 = utf16_offset;
+            }
+            utf16_offset += utf16_len(&self.text[prev_offset..offset]);
+            prev_offset = offset;
+            let character = utf16_offset - line_start_utf16;
+            positions[idx] = (utf16_offset, json!({ "line": line, "character": character }));
+        }
+
+        positions
+            .chunks(2)
+            .map(|pair| {
+                json!({
+                    "start": pair[0].0,
+                    "end": pair[1].0,
+                    "startPosition": pair[0].1,
+                    "endPosition": pair[1].1,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_a_missing_final_newline() {
+        let output = nixpkgs_fmt::reformat_string("{ }");
+        assert_eq!(output, "{ }\n");
+        assert_eq!(
+            unified_diff("{ }", &output).unwrap(),
+            "--- Input\t\n+++ Output\t\n@@ -1 +1 @@\n-{ }\n\\ No newline at end of file\n+{ }"
+        );
+        assert_eq!(unified_diff("{ }\n", "{ }\n"), None);
+    }
+
+    #[test]
+    fn converts_ranges_to_utf16() {
+        let text = "a = \"ö€😀\";\nb = 1;\n";
+        let range = |start: u32, end: u32| TextRange::new(start.into(), end.into());
+        let position =
+            |line: usize, character: usize| json!({ "line": line, "character": character });
+        assert_eq!(
+            LineIndex::new(text).ranges(vec![range(18, 19), range(4, 14)].into_iter()),
+            vec![
+                json!({
+                    "start": 13,
+                    "end": 14,
+                    "startPosition": position(1, 1),
+                    "endPosition": position(1, 2),
+                }),
+                json!({
+                    "start": 4,
+                    "end": 9,
+                    "startPosition": position(0, 4),
+                    "endPosition": position(0, 9),
+                }),
+            ]
+        );
     }
 }